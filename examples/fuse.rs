@@ -3,8 +3,9 @@ extern crate fuse;
 extern crate libc;
 extern crate time;
 
-use std::{error, fs, iter, path};
+use std::{error, fs, iter, path, thread};
 use std::collections::{HashMap};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
 use std::os::unix::ffi::{OsStrExt};
 use std::ffi::{OsStr, OsString};
 
@@ -16,34 +17,89 @@ fn main() {
   let argv: Vec<_> = env::args_os().collect();
   let ext2_file = argv.get(1).unwrap_or(&default_ext2_file);
   let mount_point = argv.get(2).unwrap_or(&default_mount_point);
+
+  let mut options = ext2::MountOptions::default();
+  let mut direct = false;
+  for arg in argv.iter().skip(3) {
+    match arg.to_str() {
+      Some("strictatime") => options.atime_mode = ext2::AtimeMode::Strict,
+      Some("relatime") => options.atime_mode = ext2::AtimeMode::Relative,
+      Some("noatime") => options.atime_mode = ext2::AtimeMode::Never,
+      Some("quota") => options.quota = true,
+      Some("direct") => direct = true,
+      Some("reproducible") => options.reproducible =
+        ext2::Reproducible::from_env().or_else(|| Some(
+          ext2::Reproducible::from_epoch(ext2::Timestamp::default()))),
+      _ => println!("Unknown option {:?}", arg),
+    }
+  }
   
-  match fuse_main(ext2_file, mount_point) {
+  match fuse_main(ext2_file, mount_point, direct, options) {
     Ok(()) => {},
     Err(err) => print_error(&err),
   }
 }
 
-fn fuse_main(ext2_file: &OsString, mount_point: &OsString) -> Result<(), ext2::Error> {
-  let file = try!(fs::OpenOptions::new()
-      .read(true).write(true).open(ext2_file));
-  let volume = ext2::FileVolume(file);
-  let fs = try!(ext2::mount_fs(Box::new(volume)));
+fn fuse_main(ext2_file: &OsString, mount_point: &OsString, direct: bool,
+  options: ext2::MountOptions) -> Result<(), ext2::Error>
+{
+  let volume = if direct {
+    try!(ext2::FileVolume::open_direct(ext2_file, 4096))
+  } else {
+    ext2::FileVolume::new(try!(fs::OpenOptions::new()
+      .read(true).write(true).open(ext2_file)))
+  };
+  let fs = try!(ext2::mount_fs_with_options(Box::new(volume), options));
   let fuse = Fuse::new(fs);
   fuse::mount(fuse, mount_point, &[]);
   Ok(())
 }
 
+const WORKERS: usize = 4;
+
+type Job = Box<FnOnce() + Send>;
+
+struct Pool {
+  sender: mpsc::Sender<Job>,
+}
+
+impl Pool {
+  fn new(workers: usize) -> Pool {
+    let (sender, receiver) = mpsc::channel::<Job>();
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..workers {
+      let receiver = receiver.clone();
+      thread::spawn(move || loop {
+        let job = match receiver.lock().unwrap().recv() {
+          Ok(job) => job,
+          Err(_) => break,
+        };
+        job();
+      });
+    }
+    Pool { sender: sender }
+  }
+
+  fn run<F: FnOnce() + Send + 'static>(&self, job: F) {
+    let _ = self.sender.send(Box::new(job));
+  }
+}
+
+// requests that only read run on the pool against the shared filesystem,
+// everything that changes it takes the exclusive lock on the session thread
 struct Fuse {
-  fs: ext2::Filesystem,
-  dir_handles: HashMap<u64, ext2::DirHandle>,
-  file_handles: HashMap<u64, ext2::FileHandle>,
+  shared: Arc<ext2::SharedFilesystem>,
+  pool: Pool,
+  dir_handles: HashMap<u64, Arc<Mutex<ext2::DirHandle>>>,
+  file_handles: HashMap<u64, Arc<Mutex<Option<ext2::FileHandle>>>>,
   next_fh: u64,
 }
 
 impl Fuse {
   fn new(fs: ext2::Filesystem) -> Fuse {
     Fuse {
-      fs: fs,
+      shared: Arc::new(ext2::SharedFilesystem::new(fs)),
+      pool: Pool::new(WORKERS),
       dir_handles: HashMap::new(),
       file_handles: HashMap::new(),
       next_fh: 0,
@@ -55,112 +111,142 @@ const TTL: time::Timespec = time::Timespec { sec: 1, nsec: 0 };
 
 impl Drop for Fuse {
   fn drop(&mut self) {
-    let _ = ext2::flush_fs(&mut self.fs);
+    let _ = self.shared.flush();
   }
 }
 
 impl fuse::Filesystem for Fuse {
   fn destroy(&mut self, _req: &fuse::Request) {
     println!("destroy");
-    let _ = ext2::flush_fs(&mut self.fs);
+    let _ = self.shared.flush();
   }
 
-  fn lookup(&mut self, _req: &fuse::Request,
+  fn lookup(&mut self, req: &fuse::Request,
     parent_ino: u64, name: &path::Path, reply: fuse::ReplyEntry)
   {
     println!("lookup (ino {}, name {:?})", parent_ino, 
              &name.to_string_lossy());
-    let res: Result<_, ext2::Error> = (|| {
-      let entry = try!(ext2::lookup_in_dir(&mut self.fs,
-          ext2_ino(parent_ino), name.as_os_str().as_bytes()));
-      Ok(match entry {
-        Some(entry_ino) => {
-          let entry_inode = try!(ext2::get_inode(&mut self.fs, entry_ino));
-          Some(inode_to_file_attr(&entry_inode))
-        },
-        None => None,
-      })
-    })();
-
-    match res {
-      Err(_err) => reply.error(65),
-      Ok(None) => reply.error(libc::ENOENT),
-      Ok(Some(file_attr)) => reply.entry(&TTL, &file_attr, 0),
-    }
+    let shared = self.shared.clone();
+    let creds = credentials(req);
+    let name = name.as_os_str().as_bytes().to_vec();
+    self.pool.run(move || {
+      let res: Result<_, ext2::Error> = (|| {
+        let entry = try!(shared.lookup_in_dir(&creds, ext2_ino(parent_ino), &name[..]));
+        Ok(match entry {
+          Some(entry_ino) => {
+            let entry_inode = try!(shared.get_inode(entry_ino));
+            Some(inode_to_file_attr(&entry_inode))
+          },
+          None => None,
+        })
+      })();
+
+      match res {
+        Err(err) => reply.error(fuse_errno(&err)),
+        Ok(None) => reply.error(libc::ENOENT),
+        Ok(Some(file_attr)) => reply.entry(&TTL, &file_attr, 0),
+      }
+    });
   }
 
   fn getattr(&mut self, _req: &fuse::Request, ino: u64, reply: fuse::ReplyAttr) {
     println!("getattr (ino {})", ino);
-    match ext2::get_inode(&mut self.fs, ext2_ino(ino)) {
-      Err(_err) => reply.error(65),
-      Ok(inode) => reply.attr(&TTL, &inode_to_file_attr(&inode)),
-    }
+    let shared = self.shared.clone();
+    self.pool.run(move || {
+      match shared.get_inode(ext2_ino(ino)) {
+        Err(err) => reply.error(fuse_errno(&err)),
+        Ok(inode) => reply.attr(&TTL, &inode_to_file_attr(&inode)),
+      }
+    });
   }
 
-  fn setattr(&mut self, _req: &fuse::Request, ino: u64, mode: Option<u32>,
+  fn setattr(&mut self, req: &fuse::Request, ino: u64, mode: Option<u32>,
     uid: Option<u32>, gid: Option<u32>,
     size: Option<u64>,
     atime: Option<time::Timespec>, mtime: Option<time::Timespec>, 
     _fh: Option<u64>,
-    _crtime: Option<time::Timespec>, chgtime: Option<time::Timespec>,
+    crtime: Option<time::Timespec>, _chgtime: Option<time::Timespec>,
     _bkuptime: Option<time::Timespec>, _flags: Option<u32>,
     reply: fuse::ReplyAttr)
   {
     println!("setattr (ino {}, uid {:?}, gid {:?}, size {:?}, ...)",
       ino, uid, gid, size);
 
-    fn to_seconds(time: time::Timespec) -> u32 { time.sec as u32 }
-
+    let creds = credentials(req);
     let res: Result<_, ext2::Error> = (|| {
+      let mut fs = try!(self.shared.write());
       if let Some(new_size) = size {
-        try!(ext2::truncate_inode_size(&mut self.fs, ext2_ino(ino), new_size))
+        try!(ext2::truncate_inode_size(&mut fs, &creds, ext2_ino(ino), new_size))
       }
 
-      let inode = try!(ext2::get_inode(&mut self.fs, ext2_ino(ino)));
+      let inode = try!(ext2::get_inode(&mut fs, ext2_ino(ino)));
 
       let new_mode = match mode {
         Some(new_mode) => try!(ext2::inode_mode_from_linux_mode(new_mode as u16)),
         None => inode.mode,
       };
 
+      // this protocol version does not flag utime(NULL), the kernel just
+      // sends its current time for both fields
+      let set_time = |timespec: time::Timespec| {
+        let now = time::get_time();
+        if atime == mtime && (now.sec - timespec.sec).abs() <= 1 {
+          ext2::Timestamp { sec: 0, nsec: ext2::UTIME_NOW }
+        } else {
+          ext2_timestamp(timespec)
+        }
+      };
+
       let new_attr = ext2::FileAttr {
-        uid: uid.unwrap_or(0),
-        gid: gid.unwrap_or(0),
-        atime: atime.map(to_seconds).unwrap_or(inode.attr.atime),
-        ctime: chgtime.map(to_seconds).unwrap_or(inode.attr.ctime),
-        mtime: mtime.map(to_seconds).unwrap_or(inode.attr.mtime),
+        uid: uid.unwrap_or(inode.attr.uid),
+        gid: gid.unwrap_or(inode.attr.gid),
+        atime: atime.map(&set_time).unwrap_or(inode.attr.atime),
+        mtime: mtime.map(&set_time).unwrap_or(inode.attr.mtime),
+        crtime: crtime.map(ext2_timestamp).unwrap_or(inode.attr.crtime),
         .. inode.attr
       };
 
-      try!(ext2::set_inode_mode_attr(&mut self.fs, ext2_ino(ino), new_mode, new_attr));
-      ext2::get_inode(&mut self.fs, ext2_ino(ino))
+      try!(ext2::set_inode_mode_attr(&mut fs, &creds,
+        ext2_ino(ino), new_mode, new_attr));
+      ext2::get_inode(&mut fs, ext2_ino(ino))
     })();
 
     match res {
-      Err(_err) => reply.error(65),
+      Err(err) => reply.error(fuse_errno(&err)),
       Ok(inode) => reply.attr(&TTL, &inode_to_file_attr(&inode)),
     }
   }
 
   fn readlink(&mut self, _req: &fuse::Request, ino: u64, reply: fuse::ReplyData) {
     println!("readlink (ino {})", ino);
-    match ext2::read_link(&mut self.fs, ext2_ino(ino)) {
-      Err(_err) => reply.error(65),
-      Ok(path) => reply.data(&path[..]),
-    }
+    let shared = self.shared.clone();
+    self.pool.run(move || {
+      match shared.read_link(ext2_ino(ino)) {
+        Err(err) => reply.error(fuse_errno(&err)),
+        Ok(path) => reply.data(&path[..]),
+      }
+    });
   }
 
-  fn mknod(&mut self, _req: &fuse::Request, parent: u64, name: &path::Path,
-    mode: u32, _rdev: u32, reply: fuse::ReplyEntry)
+  fn mknod(&mut self, req: &fuse::Request, parent: u64, name: &path::Path,
+    mode: u32, rdev: u32, reply: fuse::ReplyEntry)
   {
-    println!("mknod (ino {}, name {:?}, mode {:x})", parent, name, mode);
+    println!("mknod (ino {}, name {:?}, mode {:x}, rdev {:x})", parent, name, mode, rdev);
+    let creds = credentials(req);
     let res: Result<_, ext2::Error> = (|| {
-      ext2::make_inode_in_dir(&mut self.fs, ext2_ino(parent),
-        name.as_os_str().as_bytes(), try!(ext2_mode(mode as u16)),
-        ext2::FileAttr { uid: 0, gid: 0, atime: 0, ctime: 0, mtime: 0, dtime: 0 })
+      let mode = try!(ext2_mode(mode as u16));
+      let mut fs = try!(self.shared.write());
+      match mode.file_type {
+        ext2::FileType::CharDev | ext2::FileType::BlockDev =>
+          ext2::make_device_in_dir(&mut fs, &creds, ext2_ino(parent),
+            name.as_os_str().as_bytes(), mode, rdev, owner_attr(&creds)),
+        _ =>
+          ext2::make_inode_in_dir(&mut fs, &creds, ext2_ino(parent),
+            name.as_os_str().as_bytes(), mode, owner_attr(&creds)),
+      }
     })();
     match res {
-      Err(_err) => reply.error(65),
+      Err(err) => reply.error(fuse_errno(&err)),
       Ok(inode) => reply.entry(&TTL, &inode_to_file_attr(&inode), 0),
     }
   }
@@ -171,14 +257,15 @@ impl fuse::Filesystem for Fuse {
     self.mknod(req, parent, name, 0x4000 + (mode & 0xfff), 0, reply)
   }
 
-  fn unlink(&mut self, _req: &fuse::Request, parent: u64,
+  fn unlink(&mut self, req: &fuse::Request, parent: u64,
     name: &path::Path, reply: fuse::ReplyEmpty)
   {
     println!("unlink (ino {}, name {:?})", parent, name);
-    match ext2::remove_from_dir(&mut self.fs,
-      ext2_ino(parent), name.as_os_str().as_bytes()) 
-    {
-      Err(_err) => { print_error(&_err); reply.error(65) },
+    let res = self.shared.write().and_then(|mut fs|
+      ext2::unlink_in_dir(&mut fs, &credentials(req),
+        ext2_ino(parent), name.as_os_str().as_bytes()));
+    match res {
+      Err(err) => { print_error(&err); reply.error(fuse_errno(&err)) },
       Ok(true) => reply.ok(),
       Ok(false) => reply.error(libc::ENOENT),
     }
@@ -187,57 +274,71 @@ impl fuse::Filesystem for Fuse {
   fn rmdir(&mut self, req: &fuse::Request, parent: u64,
     name: &path::Path, reply: fuse::ReplyEmpty)
   {
-    self.unlink(req, parent, name, reply)
+    println!("rmdir (ino {}, name {:?})", parent, name);
+    let res = self.shared.write().and_then(|mut fs|
+      ext2::rmdir_in_dir(&mut fs, &credentials(req),
+        ext2_ino(parent), name.as_os_str().as_bytes()));
+    match res {
+      Err(err) => { print_error(&err); reply.error(fuse_errno(&err)) },
+      Ok(true) => reply.ok(),
+      Ok(false) => reply.error(libc::ENOENT),
+    }
   }
 
-  fn symlink(&mut self, _req: &fuse::Request, parent: u64,
+  fn symlink(&mut self, req: &fuse::Request, parent: u64,
     name: &path::Path, link: &path::Path, reply: fuse::ReplyEntry)
   {
     println!("symlink (ino {}, name {:?}, link {:?})", parent, name, link);
-    match ext2::make_symlink_in_dir(&mut self.fs, ext2_ino(parent),
-      name.as_os_str().as_bytes(), link.as_os_str().as_bytes(),
-      ext2::FileAttr { uid: 0, gid: 0, atime: 0, ctime: 0, mtime: 0, dtime: 0 })
-    {
-      Err(_err) => reply.error(65),
+    let creds = credentials(req);
+    let res = self.shared.write().and_then(|mut fs|
+      ext2::make_symlink_in_dir(&mut fs, &creds, ext2_ino(parent),
+        name.as_os_str().as_bytes(), link.as_os_str().as_bytes(),
+        owner_attr(&creds)));
+    match res {
+      Err(err) => reply.error(fuse_errno(&err)),
       Ok(inode) => reply.entry(&TTL, &inode_to_file_attr(&inode), 0),
     }
   }
 
-  fn rename(&mut self, _req: &fuse::Request, parent: u64, name: &path::Path,
+  fn rename(&mut self, req: &fuse::Request, parent: u64, name: &path::Path,
     newparent: u64, newname: &path::Path, reply: fuse::ReplyEmpty)
   {
     println!("rename (old ino {}, old name {:?}, new ino {}, new name {:?})",
       parent, name, newparent, newname);
-    match ext2::move_between_dirs(&mut self.fs,
-      ext2_ino(parent), name.as_os_str().as_bytes(),
-      ext2_ino(newparent), newname.as_os_str().as_bytes())
-    {
-      Err(_err) => reply.error(65),
+    let res = self.shared.write().and_then(|mut fs|
+      ext2::move_between_dirs(&mut fs, &credentials(req),
+        ext2_ino(parent), name.as_os_str().as_bytes(),
+        ext2_ino(newparent), newname.as_os_str().as_bytes()));
+    match res {
+      Err(err) => reply.error(fuse_errno(&err)),
       Ok(false) => reply.error(libc::ENOENT),
       Ok(true) => reply.ok(),
     }
   }
 
-  fn link(&mut self, _req: &fuse::Request, ino: u64,
+  fn link(&mut self, req: &fuse::Request, ino: u64,
     newparent: u64, newname: &path::Path, reply: fuse::ReplyEntry)
   {
     println!("link (link {}, new ino {}, new name {:?})", ino, newparent, newname);
-    match ext2::make_hardlink_in_dir(&mut self.fs,
-      ext2_ino(newparent), newname.as_os_str().as_bytes(), ext2_ino(ino))
-    {
-      Err(_err) => reply.error(65),
+    let res = self.shared.write().and_then(|mut fs|
+      ext2::make_hardlink_in_dir(&mut fs, &credentials(req),
+        ext2_ino(newparent), newname.as_os_str().as_bytes(), ext2_ino(ino)));
+    match res {
+      Err(err) => reply.error(fuse_errno(&err)),
       Ok(inode) => reply.entry(&TTL, &inode_to_file_attr(&inode), 0),
     }
   }
 
-  fn open(&mut self, _req: &fuse::Request, ino: u64,
-    _flags: u32, reply: fuse::ReplyOpen) 
+  fn open(&mut self, req: &fuse::Request, ino: u64,
+    flags: u32, reply: fuse::ReplyOpen) 
   {
-    println!("open (ino {})", ino);
-    match ext2::open_file(&mut self.fs, ext2_ino(ino)) {
-      Err(_err) => reply.error(65),
+    println!("open (ino {}, flags {:x})", ino, flags);
+    let res = self.shared.write().and_then(|mut fs|
+      ext2::open_file(&mut fs, &credentials(req), ext2_ino(ino), open_access(flags)));
+    match res {
+      Err(err) => reply.error(fuse_errno(&err)),
       Ok(handle) => {
-        self.file_handles.insert(self.next_fh, handle);
+        self.file_handles.insert(self.next_fh, Arc::new(Mutex::new(Some(handle))));
         self.next_fh += 1;
         reply.opened(self.next_fh - 1, 0);
       }
@@ -248,35 +349,39 @@ impl fuse::Filesystem for Fuse {
     offset: u64, size: u32, reply: fuse::ReplyData)
   {
     println!("read (ino {}, fh {}, offset {}, size {})", _ino, fh, offset, size);
-    let res: Result<_, ext2::Error> = (|| {
-      let handle = try!(self.file_handles.get_mut(&fh)
-          .ok_or_else(|| ext2::Error::new(format!("Bad file handle"))));
-      let mut buffer: Vec<u8> = iter::repeat(0).take(size as usize).collect();
-      let length = try!(ext2::read_file(&mut self.fs, handle,
-            offset, &mut buffer[..]));
-      buffer.truncate(length as usize);
-      Ok(buffer)
-    })();
-
-    match res {
-      Err(_err) => reply.error(65),
-      Ok(data) => reply.data(&data[..]),
-    }
+    let shared = self.shared.clone();
+    let handle = self.file_handles.get(&fh).cloned();
+    self.pool.run(move || {
+      let res: Result<_, ext2::Error> = (|| {
+        let handle = try!(handle.ok_or_else(bad_handle));
+        let mut handle = try!(lock_handle(&handle));
+        let handle = try!(handle.as_mut().ok_or_else(bad_handle));
+        let mut buffer: Vec<u8> = iter::repeat(0).take(size as usize).collect();
+        let length = try!(shared.read_file(handle, offset, &mut buffer[..]));
+        buffer.truncate(length as usize);
+        Ok(buffer)
+      })();
+
+      match res {
+        Err(err) => reply.error(fuse_errno(&err)),
+        Ok(data) => reply.data(&data[..]),
+      }
+    });
   }
 
-  fn write(&mut self, _req: &fuse::Request, _ino: u64, fh: u64, offset: u64,
+  fn write(&mut self, req: &fuse::Request, _ino: u64, fh: u64, offset: u64,
     data: &[u8], _flags: u32, reply: fuse::ReplyWrite)
   {
     println!("write (ino {}, fh {}, offset {}, size {})", _ino, fh, offset, data.len());
     let res: Result<_, ext2::Error> = (|| {
-      let handle = try!(self.file_handles.get_mut(&fh)
-          .ok_or_else(|| ext2::Error::new(format!("Bad file handle"))));
-      let length = try!(ext2::write_file(&mut self.fs, handle, offset, data));
-      Ok(length)
+      let handle = try!(self.file_handles.get(&fh).ok_or_else(bad_handle));
+      let mut handle = try!(lock_handle(handle));
+      let handle = try!(handle.as_mut().ok_or_else(bad_handle));
+      self.shared.write_file(&credentials(req), handle, offset, data)
     })();
 
     match res {
-      Err(_err) => { println!("{:?}", _err); reply.error(65) },
+      Err(err) => { println!("{:?}", err); reply.error(fuse_errno(&err)) },
       Ok(length) => reply.written(length as u32),
     }
   }
@@ -286,26 +391,82 @@ impl fuse::Filesystem for Fuse {
   {
     println!("release (ino {}, fh {})", _ino, fh);
     let res: Result<_, ext2::Error> = (|| {
-      match self.file_handles.remove(&fh) {
-        Some(handle) => ext2::close_file(&mut self.fs, handle),
+      let handle = match self.file_handles.remove(&fh) {
+        Some(handle) => try!(lock_handle(&handle)).take(),
+        None => None,
+      };
+      match handle {
+        Some(handle) => ext2::close_file(&mut *try!(self.shared.write()), handle),
         None => Ok(()),
       }
     })();
 
     match res {
-      Err(_err) => reply.error(65),
+      Err(err) => reply.error(fuse_errno(&err)),
+      Ok(()) => reply.ok(),
+    }
+  }
+
+  fn fsync(&mut self, _req: &fuse::Request, ino: u64, fh: u64,
+    datasync: bool, reply: fuse::ReplyEmpty)
+  {
+    println!("fsync (ino {}, fh {}, datasync {})", ino, fh, datasync);
+    let res = self.shared.write().and_then(|mut fs|
+      ext2::sync_file(&mut fs, ext2_ino(ino), datasync));
+    match res {
+      Err(err) => reply.error(fuse_errno(&err)),
       Ok(()) => reply.ok(),
     }
   }
 
-  fn opendir(&mut self, _req: &fuse::Request, ino: u64,
+  fn statfs(&mut self, _req: &fuse::Request, _ino: u64, reply: fuse::ReplyStatfs) {
+    println!("statfs");
+    let shared = self.shared.clone();
+    self.pool.run(move || {
+      match shared.statfs() {
+        Err(err) => reply.error(fuse_errno(&err)),
+        Ok(stat) => reply.statfs(stat.blocks, stat.free_blocks, stat.available_blocks,
+          stat.inodes, stat.free_inodes, stat.block_size as u32,
+          stat.name_len as u32, stat.block_size as u32),
+      }
+    });
+  }
+
+  fn bmap(&mut self, _req: &fuse::Request, ino: u64, blocksize: u32,
+    idx: u64, reply: fuse::ReplyBmap)
+  {
+    println!("bmap (ino {}, blocksize {}, idx {})", ino, blocksize, idx);
+    if blocksize == 0 {
+      return reply.error(libc::EINVAL)
+    }
+    let res: Result<_, ext2::Error> = (|| {
+      let mut fs = try!(self.shared.write());
+      let inode = try!(ext2::get_inode(&mut fs, ext2_ino(ino)));
+      let block_size = fs.block_size();
+      let offset = idx * blocksize as u64;
+      let extents = try!(ext2::map_range(&fs, &inode, offset / block_size, 1));
+      Ok(match extents.first().and_then(|extent| extent.block) {
+        Some(block) => (block * block_size + offset % block_size) / blocksize as u64,
+        None => 0,
+      })
+    })();
+
+    match res {
+      Err(err) => reply.error(fuse_errno(&err)),
+      Ok(block) => reply.bmap(block),
+    }
+  }
+
+  fn opendir(&mut self, req: &fuse::Request, ino: u64,
     _flags: u32, reply: fuse::ReplyOpen)
   {
     println!("opendir (ino {})", ino);
-    match ext2::open_dir(&mut self.fs, ext2_ino(ino)) {
-      Err(_err) => reply.error(65),
+    let res = self.shared.write().and_then(|mut fs|
+      ext2::open_dir(&mut fs, &credentials(req), ext2_ino(ino)));
+    match res {
+      Err(err) => reply.error(fuse_errno(&err)),
       Ok(dir_handle) => {
-        self.dir_handles.insert(self.next_fh, dir_handle);
+        self.dir_handles.insert(self.next_fh, Arc::new(Mutex::new(dir_handle)));
         self.next_fh += 1;
         reply.opened(self.next_fh - 1, 0)
       },
@@ -316,29 +477,31 @@ impl fuse::Filesystem for Fuse {
     offset: u64, mut reply: fuse::ReplyDirectory)
   {
     println!("readdir (ino {}, fh {}, offset {})", ino, fh, offset);
-    let res: Result<_, ext2::Error> = (|| {
-      let handle = try!(self.dir_handles.get_mut(&fh)
-          .ok_or_else(|| ext2::Error::new(format!("Bad dir handle"))));
-
-      while let Some((next_handle, line)) =
-        try!(ext2::read_dir(&mut self.fs, *handle)) 
-      {
-        let ino = fuse_ino(line.ino);
-        let file_type = fuse_file_type(line.file_type);
-        let name = <OsStr as OsStrExt>::from_bytes(&line.name[..]);
-        if reply.add(ino, 0, file_type, name) {
-          break
-        } else {
-          *handle = next_handle;
+    let shared = self.shared.clone();
+    let handle = self.dir_handles.get(&fh).cloned();
+    self.pool.run(move || {
+      let res: Result<_, ext2::Error> = (|| {
+        let handle = try!(handle.ok_or_else(bad_handle));
+        let mut handle = try!(lock_handle(&handle));
+
+        while let Some((next_handle, line)) = try!(shared.read_dir(*handle)) {
+          let ino = fuse_ino(line.ino);
+          let file_type = fuse_file_type(line.file_type);
+          let name = <OsStr as OsStrExt>::from_bytes(&line.name[..]);
+          if reply.add(ino, 0, file_type, name) {
+            break
+          } else {
+            *handle = next_handle;
+          }
         }
-      }
-      Ok(())
-    })();
+        Ok(())
+      })();
 
-    match res {
-      Err(_err) => reply.error(65),
-      Ok(()) => reply.ok(),
-    }
+      match res {
+        Err(err) => reply.error(fuse_errno(&err)),
+        Ok(()) => reply.ok(),
+      }
+    });
   }
 
   fn releasedir(&mut self, _req: &fuse::Request, _ino: u64, fh: u64,
@@ -347,18 +510,41 @@ impl fuse::Filesystem for Fuse {
     println!("releasedir (ino {}, fh {})", _ino, fh);
     let res: Result<_, ext2::Error> = (|| {
       match self.dir_handles.remove(&fh) {
-        Some(handle) => ext2::close_dir(&mut self.fs, handle),
+        Some(handle) => {
+          let handle = *try!(lock_handle(&handle));
+          ext2::close_dir(&mut *try!(self.shared.write()), handle)
+        },
         None => Ok(()),
       }
     })();
 
     match res {
-      Err(_err) => reply.error(65),
+      Err(err) => reply.error(fuse_errno(&err)),
+      Ok(()) => reply.ok(),
+    }
+  }
+
+  fn fsyncdir(&mut self, _req: &fuse::Request, ino: u64, fh: u64,
+    datasync: bool, reply: fuse::ReplyEmpty)
+  {
+    println!("fsyncdir (ino {}, fh {}, datasync {})", ino, fh, datasync);
+    let res = self.shared.write().and_then(|mut fs|
+      ext2::sync_file(&mut fs, ext2_ino(ino), datasync));
+    match res {
+      Err(err) => reply.error(fuse_errno(&err)),
       Ok(()) => reply.ok(),
     }
   }
 }
 
+fn bad_handle() -> ext2::Error {
+  ext2::Error::new(format!("Bad handle"))
+}
+
+fn lock_handle<T>(handle: &Mutex<T>) -> Result<MutexGuard<'_, T>, ext2::Error> {
+  handle.lock().map_err(|_| ext2::Error::new(format!("Handle lock is poisoned")))
+}
+
 fn print_error(err: &error::Error) {
   println!("Error: {}", err);
   match err.cause() {
@@ -367,10 +553,66 @@ fn print_error(err: &error::Error) {
   }
 }
 
+fn fuse_errno(err: &ext2::Error) -> libc::c_int {
+  match err.kind() {
+    ext2::ErrorKind::Other => 65,
+    ext2::ErrorKind::Io => libc::EIO,
+    ext2::ErrorKind::NotFound => libc::ENOENT,
+    ext2::ErrorKind::AlreadyExists => libc::EEXIST,
+    ext2::ErrorKind::IsDir => libc::EISDIR,
+    ext2::ErrorKind::NotDir => libc::ENOTDIR,
+    ext2::ErrorKind::NotEmpty => libc::ENOTEMPTY,
+    ext2::ErrorKind::Busy => libc::EBUSY,
+    ext2::ErrorKind::InvalidInput => libc::EINVAL,
+    ext2::ErrorKind::NoSpace => libc::ENOSPC,
+    ext2::ErrorKind::FileTooLarge => libc::EFBIG,
+    ext2::ErrorKind::NotPermitted => libc::EPERM,
+    ext2::ErrorKind::PermissionDenied => libc::EACCES,
+    ext2::ErrorKind::QuotaExceeded => libc::EDQUOT,
+  }
+}
+
+fn open_access(flags: u32) -> u16 {
+  match flags as libc::c_int & libc::O_ACCMODE {
+    libc::O_WRONLY => ext2::ACCESS_WRITE,
+    libc::O_RDWR => ext2::ACCESS_READ | ext2::ACCESS_WRITE,
+    _ => ext2::ACCESS_READ,
+  }
+}
+
 fn ext2_ino(fuse_ino: u64) -> u64 {
   if fuse_ino == 1 { ext2::ROOT_INO } else { fuse_ino }
 }
 
+fn credentials(req: &fuse::Request) -> ext2::Credentials {
+  ext2::Credentials {
+    uid: req.uid(),
+    gid: req.gid(),
+    groups: supplementary_groups(req.pid()),
+  }
+}
+
+fn supplementary_groups(pid: u32) -> Vec<u32> {
+  use std::io::{Read};
+  let mut status = String::new();
+  let path = format!("/proc/{}/status", pid);
+  if fs::File::open(&path).and_then(|mut f| f.read_to_string(&mut status)).is_err() {
+    return Vec::new()
+  }
+
+  for line in status.lines() {
+    if line.starts_with("Groups:") {
+      return line["Groups:".len()..].split_whitespace()
+        .filter_map(|gid| gid.parse().ok()).collect()
+    }
+  }
+  Vec::new()
+}
+
+fn owner_attr(creds: &ext2::Credentials) -> ext2::FileAttr {
+  ext2::FileAttr { uid: creds.uid, gid: creds.gid, .. ext2::FileAttr::default() }
+}
+
 fn ext2_mode(mode: u16) -> Result<ext2::Mode, ext2::Error> {
   ext2::inode_mode_from_linux_mode(mode)
 }
@@ -387,19 +629,26 @@ fn inode_to_file_attr(inode: &ext2::Inode) -> fuse::FileAttr {
     atime: fuse_timespec(inode.attr.atime),
     ctime: fuse_timespec(inode.attr.ctime),
     mtime: fuse_timespec(inode.attr.mtime),
-    crtime: fuse_timespec(0),
+    crtime: fuse_timespec(inode.attr.crtime),
     kind: fuse_file_type(inode.mode.file_type),
     perm: inode.mode.access_rights,
     nlink: inode.links_count as u32,
     uid: inode.attr.uid,
     gid: inode.attr.gid,
-    rdev: 0,
+    rdev: match inode.mode.file_type {
+      ext2::FileType::CharDev | ext2::FileType::BlockDev => inode.rdev(),
+      _ => 0,
+    },
     flags: 0,
   }
 }
 
-fn fuse_timespec(epoch: u32) -> time::Timespec {
-  time::Timespec::new(epoch as i64, 0)
+fn fuse_timespec(timestamp: ext2::Timestamp) -> time::Timespec {
+  time::Timespec::new(timestamp.sec, timestamp.nsec as i32)
+}
+
+fn ext2_timestamp(timespec: time::Timespec) -> ext2::Timestamp {
+  ext2::Timestamp { sec: timespec.sec, nsec: timespec.nsec as u32 }
 }
 
 fn fuse_file_type(ftype: ext2::FileType) -> fuse::FileType {
@@ -409,7 +658,8 @@ fn fuse_file_type(ftype: ext2::FileType) -> fuse::FileType {
     ext2::FileType::CharDev => fuse::FileType::CharDevice,
     ext2::FileType::BlockDev => fuse::FileType::BlockDevice,
     ext2::FileType::Fifo => fuse::FileType::NamedPipe,
-    ext2::FileType::Socket => panic!("Fuse cannot handle sockets"),
+    // fuse has no file type for sockets
+    ext2::FileType::Socket => fuse::FileType::NamedPipe,
     ext2::FileType::Symlink => fuse::FileType::Symlink,
   }
 }
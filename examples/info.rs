@@ -3,7 +3,7 @@ use std::{error, fs, str};
 
 fn mein() -> Result<(), ext2::Error> {
   let file = try!(fs::File::open("test.ext2"));
-  let volume = ext2::FileVolume(file);
+  let volume = ext2::FileVolume::new(file);
   let mut fs = try!(ext2::Filesystem::new(Box::new(volume)));
 
   let root_inode = try!(fs.read_inode(ext2::Filesystem::ROOT_INO));
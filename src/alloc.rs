@@ -1,20 +1,152 @@
+use std::{cmp};
 use prelude::*;
 
-pub fn alloc_block(fs: &mut Filesystem, first_group_idx: u64) -> Result<Option<u64>> {
-  alloc(fs, first_group_idx, alloc_block_in_group)
+const ZERO_RUN_BLOCKS: u64 = 256;
+
+pub fn alloc_blocks(fs: &mut Filesystem, owner_ino: u64,
+  goal: u64, count: u64) -> Result<Option<(u64, u64)>>
+{
+  assert!(count > 0);
+  let count = cmp::min(count, available_blocks(fs));
+  if count == 0 {
+    return Ok(None)
+  }
+
+  let goal = clamp_goal(fs, goal);
+
+  let window_size = fs.options.reservation_blocks;
+  if window_size == 0 {
+    return match find_free_block(fs, goal, None) {
+      Some(first) => alloc_run(fs, first, group_end_block(fs, first), count),
+      None => Ok(None),
+    }
+  }
+
+  if let Some(window) = fs.reservations.get(&owner_ino).cloned() {
+    let from = if goal >= window.start && goal < window.end { goal } else { window.start };
+    for &(begin, end) in [(from, window.end), (window.start, from)].iter() {
+      if let Some(first) = find_free_in_range(fs, begin, end) {
+        return alloc_run(fs, first, window.end, count);
+      }
+    }
+  }
+
+  let first = match find_free_block(fs, goal, Some(owner_ino)) {
+    Some(first) => first,
+    None => {
+      fs.reservations.remove(&owner_ino);
+      return match find_free_block(fs, goal, None) {
+        Some(first) => alloc_run(fs, first, group_end_block(fs, first), count),
+        None => Ok(None),
+      }
+    },
+  };
+
+  let mut end = cmp::min(first + cmp::max(window_size, count), group_end_block(fs, first));
+  for (&ino, window) in fs.reservations.iter() {
+    if ino != owner_ino && window.start > first && window.start < end {
+      end = window.start;
+    }
+  }
+  fs.reservations.insert(owner_ino, Reservation { start: first, end: end });
+  alloc_run(fs, first, end, count)
+}
+
+pub fn alloc_extent(fs: &mut Filesystem, goal: u64,
+  min_count: u64, max_count: u64) -> Result<Option<(u64, u64)>>
+{
+  assert!(min_count > 0 && min_count <= max_count);
+  let max_count = cmp::min(max_count, available_blocks(fs));
+  if max_count < min_count {
+    return Ok(None)
+  }
+
+  let goal = clamp_goal(fs, goal);
+  let (goal_group_idx, _) = get_block_group(fs, goal);
+  let group_count = fs.group_count();
+  for i in 0..group_count {
+    let group_idx = (goal_group_idx + i) % group_count;
+    if largest_free_run(fs, group_idx) < min_count {
+      continue
+    }
+
+    let begin = if i == 0 { goal } else { group_first_block(fs, group_idx) };
+    let runs = free_extents_from(fs, group_idx, begin);
+    if let Some(&(first, length)) = runs.iter().find(|&&(_, length)| length >= min_count) {
+      return alloc_run(fs, first, first + length, max_count);
+    }
+  }
+  Ok(None)
+}
+
+pub fn free_extents(fs: &Filesystem, group_idx: u64) -> Vec<(u64, u64)> {
+  let begin = group_first_block(fs, group_idx);
+  free_extents_from(fs, group_idx, begin)
+}
+
+pub fn largest_free_run(fs: &mut Filesystem, group_idx: u64) -> u64 {
+  if let Some(largest) = group_mut(fs, group_idx).largest_free_run {
+    return largest
+  }
+  let largest = free_extents(fs, group_idx).iter()
+    .fold(0, |largest, &(_, length)| cmp::max(largest, length));
+  group_mut(fs, group_idx).largest_free_run = Some(largest);
+  largest
+}
+
+pub fn with_reserved_access<T, F>(fs: &mut Filesystem, creds: &Credentials,
+  f: F) -> Result<T>
+  where F: FnOnce(&mut Filesystem) -> Result<T>
+{
+  let saved = (fs.use_reserved, fs.ignore_quota);
+  fs.use_reserved = may_use_reserved(fs, creds);
+  fs.ignore_quota = creds.is_root();
+  let result = f(fs);
+  fs.use_reserved = saved.0;
+  fs.ignore_quota = saved.1;
+  result
+}
+
+pub fn may_use_reserved(fs: &Filesystem, creds: &Credentials) -> bool {
+  creds.is_root() ||
+    creds.uid == fs.superblock.def_resuid as u32 ||
+    creds.in_group(fs.superblock.def_resgid as u32)
+}
+
+pub fn discard_reservation(fs: &mut Filesystem, owner_ino: u64) {
+  fs.reservations.remove(&owner_ino);
 }
 
 pub fn alloc_inode(fs: &mut Filesystem, first_group_idx: u64) -> Result<Option<u64>> {
   alloc(fs, first_group_idx, alloc_inode_in_group)
 }
 
+pub fn alloc_dir_inode(fs: &mut Filesystem, parent_ino: u64,
+  name: &[u8]) -> Result<Option<u64>>
+{
+  let (parent_group_idx, _) = get_ino_group(fs, parent_ino);
+  let group_idx = if parent_ino == ROOT_INO {
+    find_top_dir_group(fs, name)
+  } else {
+    find_dir_group(fs, parent_group_idx)
+  };
+  alloc_inode(fs, group_idx.unwrap_or(parent_group_idx))
+}
+
 pub fn dealloc_block(fs: &mut Filesystem, block: u64) -> Result<()> {
+  if fs.options.reproducible.is_some() {
+    try!(zero_block(fs, block));
+  }
+
   let (group_idx, local_idx) = get_block_group(fs, block);
-  let group_id = group_idx as usize;
-  let (local_byte, local_bit) = (local_idx / 8, local_idx % 8);
-  fs.groups[group_id].desc.free_blocks_count += 1;
-  fs.groups[group_id].block_bitmap[local_byte as usize] &= !(1 << local_bit);
-  fs.groups[group_id].dirty = true;
+  {
+    let group = group_mut(fs, group_idx);
+    group.desc.free_blocks_count += 1;
+    set_bit_in_bitmap(&mut group.block_bitmap[..], local_idx, false);
+    group.block_hint = cmp::min(group.block_hint, local_idx);
+    group.largest_free_run = None;
+  }
+  mark_group_dirty(fs, group_idx);
   fs.superblock.free_blocks_count += 1;
   fs.superblock_dirty = true;
   Ok(())
@@ -22,18 +154,308 @@ pub fn dealloc_block(fs: &mut Filesystem, block: u64) -> Result<()> {
 
 pub fn dealloc_inode(fs: &mut Filesystem, ino: u64) -> Result<()> {
   let (group_idx, local_idx) = get_ino_group(fs, ino);
-  let group_id = group_idx as usize;
-  let (local_byte, local_bit) = (local_idx / 8, local_idx % 8);
-  fs.groups[group_id].desc.free_inodes_count += 1;
-  fs.groups[group_id].inode_bitmap[local_byte as usize] &= !(1 << local_bit);
-  fs.groups[group_id].dirty = true;
+  {
+    let group = group_mut(fs, group_idx);
+    group.desc.free_inodes_count += 1;
+    set_bit_in_bitmap(&mut group.inode_bitmap[..], local_idx, false);
+    group.inode_hint = cmp::min(group.inode_hint, local_idx);
+  }
+  mark_group_dirty(fs, group_idx);
   fs.superblock.free_inodes_count += 1;
   fs.superblock_dirty = true;
   Ok(())
 }
 
+pub fn is_block_free(fs: &Filesystem, block: u64) -> bool {
+  let (group_idx, local_idx) = get_block_group(fs, block);
+  !get_bit_in_bitmap(&lock_group(fs, group_idx).block_bitmap[..], local_idx)
+}
+
+pub fn zero_free_space(fs: &mut Filesystem) -> Result<()> {
+  let block_size = fs.block_size();
+  let inode_size = fs.superblock.inode_size as u64;
+  let inodes_per_group = fs.superblock.inodes_per_group as u64;
+  let slots_size = inodes_per_group * inode_size;
+  let table_size = (slots_size + block_size - 1) / block_size * block_size;
+
+  for group_idx in 0..fs.group_count() {
+    for (first, length) in free_extents(fs, group_idx) {
+      let mut block = first;
+      while block < first + length {
+        let count = cmp::min(first + length - block, ZERO_RUN_BLOCKS);
+        try!(fs.volume.write(block * block_size, &make_buffer(count * block_size)[..]));
+        block += count;
+      }
+    }
+
+    let (table_offset, free_slots) = {
+      let group = group_mut(fs, group_idx);
+      (group.desc.inode_table as u64 * block_size,
+        find_zero_runs_in_bitmap(&group.inode_bitmap[..], 0, inodes_per_group))
+    };
+    for (first, length) in free_slots {
+      try!(fs.volume.write(table_offset + first * inode_size,
+        &make_buffer(length * inode_size)[..]));
+    }
+    if table_size > slots_size {
+      try!(fs.volume.write(table_offset + slots_size,
+        &make_buffer(table_size - slots_size)[..]));
+    }
+  }
+  Ok(())
+}
+
+fn zero_block(fs: &mut Filesystem, block: u64) -> Result<()> {
+  let zeros = make_buffer(fs.block_size());
+  let offset = block * fs.block_size();
+  fs.volume.write(offset, &zeros[..])
+}
+
+fn mark_block_used(fs: &mut Filesystem, block: u64) -> Result<()> {
+  let (group_idx, local_idx) = get_block_group(fs, block);
+  {
+    let group = group_mut(fs, group_idx);
+    set_bit_in_bitmap(&mut group.block_bitmap[..], local_idx, true);
+    group.desc.free_blocks_count -= 1;
+    if group.block_hint == local_idx {
+      group.block_hint = local_idx + 1;
+    }
+    group.largest_free_run = None;
+  }
+  mark_group_dirty(fs, group_idx);
+  fs.superblock.free_blocks_count -= 1;
+  fs.superblock_dirty = true;
+
+  if fs.options.reproducible.is_some() {
+    try!(zero_block(fs, block));
+  }
+  Ok(())
+}
+
+// the caller accounts the block in the superblock once it holds the
+// filesystem exclusively again
+pub fn alloc_block_shared(fs: &Filesystem, owner_ino: u64, goal: u64) -> Option<u64> {
+  let goal = clamp_goal(fs, goal);
+
+  loop {
+    let block = match find_free_block(fs, goal, Some(owner_ino))
+        .or_else(|| find_free_block(fs, goal, None)) {
+      Some(block) => block,
+      None => return None,
+    };
+
+    let (group_idx, local_idx) = get_block_group(fs, block);
+    let mut group = lock_group(fs, group_idx);
+    if get_bit_in_bitmap(&group.block_bitmap[..], local_idx) {
+      continue
+    }
+    set_bit_in_bitmap(&mut group.block_bitmap[..], local_idx, true);
+    group.desc.free_blocks_count -= 1;
+    if group.block_hint == local_idx {
+      group.block_hint = local_idx + 1;
+    }
+    group.largest_free_run = None;
+    mark_group_dirty(fs, group_idx);
+    return Some(block)
+  }
+}
+
+pub fn dealloc_block_shared(fs: &Filesystem, block: u64) {
+  let (group_idx, local_idx) = get_block_group(fs, block);
+  let mut group = lock_group(fs, group_idx);
+  group.desc.free_blocks_count += 1;
+  set_bit_in_bitmap(&mut group.block_bitmap[..], local_idx, false);
+  group.block_hint = cmp::min(group.block_hint, local_idx);
+  group.largest_free_run = None;
+}
+
+fn available_blocks(fs: &Filesystem) -> u64 {
+  let free_blocks = fs.superblock.free_blocks_count as u64;
+  if fs.use_reserved {
+    free_blocks
+  } else {
+    free_blocks.saturating_sub(fs.superblock.r_blocks_count as u64)
+  }
+}
+
+fn clamp_goal(fs: &Filesystem, goal: u64) -> u64 {
+  if goal < fs.superblock.first_data_block as u64 ||
+    goal >= fs.superblock.blocks_count as u64
+  {
+    fs.superblock.first_data_block as u64
+  } else {
+    goal
+  }
+}
+
+fn alloc_run(fs: &mut Filesystem, first: u64, end: u64,
+  count: u64) -> Result<Option<(u64, u64)>>
+{
+  let mut length = 0;
+  while length < count && first + length < end && is_block_free(fs, first + length) {
+    try!(mark_block_used(fs, first + length));
+    length += 1;
+  }
+  Ok(Some((first, length)))
+}
+
+fn find_free_block(fs: &Filesystem, goal: u64, owner_ino: Option<u64>) -> Option<u64> {
+  let (goal_group_idx, goal_local_idx) = get_block_group(fs, goal);
+  let group_count = fs.group_count();
+  let mut first_search = true;
+  for group_idx in (goal_group_idx..group_count).chain(0..goal_group_idx + 1) {
+    let (free_blocks, block_hint) = {
+      let group = lock_group(fs, group_idx);
+      (group.desc.free_blocks_count, group.block_hint)
+    };
+    if free_blocks == 0 {
+      first_search = false;
+      continue
+    }
+
+    let group_begin = group_first_block(fs, group_idx);
+    let begin = group_begin + cmp::max(block_hint,
+      if first_search { goal_local_idx } else { 0 });
+    first_search = false;
+
+    let mut from = begin;
+    let end = group_end_block(fs, begin);
+    while let Some(block) = find_free_in_range(fs, from, end) {
+      match reserved_by_other(fs, block, owner_ino) {
+        Some(window_end) => from = window_end,
+        None => return Some(block),
+      }
+    }
+  }
+  None
+}
+
+fn find_free_in_range(fs: &Filesystem, begin: u64, end: u64) -> Option<u64> {
+  if begin >= end {
+    return None
+  }
+  let (group_idx, local_begin) = get_block_group(fs, begin);
+  let local_end = local_begin + (end - begin);
+  let group = lock_group(fs, group_idx);
+  find_bit_in_bitmap(&group.block_bitmap[..], local_begin, local_end, false)
+    .map(|local_idx| begin - local_begin + local_idx)
+}
+
+fn free_extents_from(fs: &Filesystem, group_idx: u64, begin: u64) -> Vec<(u64, u64)> {
+  let group_begin = group_first_block(fs, group_idx);
+  let local_end = group_end_block(fs, group_begin) - group_begin;
+  let group = lock_group(fs, group_idx);
+  find_zero_runs_in_bitmap(&group.block_bitmap[..], begin - group_begin, local_end).into_iter()
+    .map(|(local_idx, length)| (group_begin + local_idx, length))
+    .collect()
+}
+
+fn reserved_by_other(fs: &Filesystem, block: u64, owner_ino: Option<u64>) -> Option<u64> {
+  let owner_ino = match owner_ino {
+    Some(owner_ino) => owner_ino,
+    None => return None,
+  };
+  for (&ino, window) in fs.reservations.iter() {
+    if ino != owner_ino && block >= window.start && block < window.end {
+      return Some(window.end)
+    }
+  }
+  None
+}
+
+pub fn group_first_block(fs: &Filesystem, group_idx: u64) -> u64 {
+  group_idx * fs.superblock.blocks_per_group as u64 +
+    fs.superblock.first_data_block as u64
+}
+
+fn group_end_block(fs: &Filesystem, block: u64) -> u64 {
+  let (group_idx, _) = get_block_group(fs, block);
+  cmp::min(group_first_block(fs, group_idx + 1), fs.superblock.blocks_count as u64)
+}
+
+#[derive(Debug, Copy, Clone)]
+struct GroupAverages {
+  free_inodes: u64,
+  free_blocks: u64,
+  dirs: u64,
+}
+
+fn group_averages(fs: &Filesystem) -> GroupAverages {
+  let group_count = fs.group_count();
+  let dirs = (0..group_count)
+    .fold(0, |sum, group_idx| sum + lock_group(fs, group_idx).desc.used_dirs_count as u64);
+  GroupAverages {
+    free_inodes: fs.superblock.free_inodes_count as u64 / group_count,
+    free_blocks: fs.superblock.free_blocks_count as u64 / group_count,
+    dirs: dirs / group_count,
+  }
+}
+
+fn find_top_dir_group(fs: &Filesystem, name: &[u8]) -> Option<u64> {
+  let group_count = fs.group_count();
+  let averages = group_averages(fs);
+  let start = name.iter()
+    .fold(0u64, |hash, &byte| hash.wrapping_mul(31).wrapping_add(byte as u64))
+    % group_count;
+
+  let mut best: Option<(u64, u16)> = None;
+  for i in 0..group_count {
+    let group_idx = (start + i) % group_count;
+    let desc = lock_group(fs, group_idx).desc;
+    if (desc.free_inodes_count as u64) < averages.free_inodes ||
+      (desc.free_blocks_count as u64) < averages.free_blocks ||
+      desc.free_inodes_count == 0
+    {
+      continue
+    }
+    match best {
+      Some((_, best_dirs)) if best_dirs <= desc.used_dirs_count => (),
+      _ => best = Some((group_idx, desc.used_dirs_count)),
+    }
+  }
+
+  best.map(|(group_idx, _)| group_idx)
+    .or_else(|| find_group_with_free_inodes(fs, start, averages.free_inodes))
+}
+
+fn find_dir_group(fs: &Filesystem, parent_group_idx: u64) -> Option<u64> {
+  let group_count = fs.group_count();
+  let averages = group_averages(fs);
+  let inodes_per_group = fs.superblock.inodes_per_group as u64;
+  let blocks_per_group = fs.superblock.blocks_per_group as u64;
+  let max_dirs = averages.dirs + inodes_per_group / 16;
+  let min_inodes = averages.free_inodes.saturating_sub(inodes_per_group / 4);
+  let min_blocks = averages.free_blocks.saturating_sub(blocks_per_group / 4);
+
+  for i in 0..group_count {
+    let group_idx = (parent_group_idx + i) % group_count;
+    let desc = lock_group(fs, group_idx).desc;
+    if (desc.used_dirs_count as u64) < max_dirs &&
+      (desc.free_inodes_count as u64) >= min_inodes &&
+      (desc.free_blocks_count as u64) >= min_blocks &&
+      desc.free_inodes_count > 0
+    {
+      return Some(group_idx)
+    }
+  }
+
+  find_group_with_free_inodes(fs, parent_group_idx, averages.free_inodes)
+}
+
+fn find_group_with_free_inodes(fs: &Filesystem, start: u64,
+  min_inodes: u64) -> Option<u64>
+{
+  let group_count = fs.group_count();
+  (0..group_count)
+    .map(|i| (start + i) % group_count)
+    .find(|&group_idx| {
+      let free_inodes = lock_group(fs, group_idx).desc.free_inodes_count as u64;
+      free_inodes > 0 && free_inodes >= min_inodes
+    })
+}
+
 fn alloc(fs: &mut Filesystem, first_group_idx: u64,
-  alloc_in_group: fn(&mut Filesystem, u64) -> Result<Option<u64>>) 
+  alloc_in_group: fn(&mut Filesystem, u64) -> Result<Option<u64>>)
   -> Result<Option<u64>>
 {
   Ok(match try!(alloc_in_group(fs, first_group_idx)) {
@@ -50,57 +472,90 @@ fn alloc(fs: &mut Filesystem, first_group_idx: u64,
   })
 }
 
-fn alloc_block_in_group(fs: &mut Filesystem, group_idx: u64) -> Result<Option<u64>> {
-  let group_id = group_idx as usize;
-  if fs.groups[group_id].desc.free_blocks_count == 0 {
-    return Ok(None)
-  }
+fn alloc_inode_in_group(fs: &mut Filesystem, group_idx: u64) -> Result<Option<u64>> {
+  let inodes_per_group = fs.superblock.inodes_per_group as u64;
+  let local_idx = {
+    let group = group_mut(fs, group_idx);
+    if group.desc.free_inodes_count == 0 {
+      return Ok(None)
+    }
 
-  match find_zero_bit_in_bitmap(&fs.groups[group_id].block_bitmap[..]) {
-    Some((byte, bit)) => {
-      fs.groups[group_id].block_bitmap[byte as usize] |= 1 << bit;
-      fs.groups[group_id].desc.free_blocks_count -= 1;
-      fs.groups[group_id].dirty = true;
-      fs.superblock.free_blocks_count -= 1;
-      fs.superblock_dirty = true;
-      Ok(Some(group_idx * fs.superblock.blocks_per_group as u64 +
-              fs.superblock.first_data_block as u64 +
-              byte * 8 + bit))
-    },
-    None => Ok(None),
-  }
+    let local_idx = match find_bit_in_bitmap(&group.inode_bitmap[..],
+        group.inode_hint, inodes_per_group, false) {
+      Some(local_idx) => local_idx,
+      None => return Ok(None),
+    };
+    set_bit_in_bitmap(&mut group.inode_bitmap[..], local_idx, true);
+    group.inode_hint = local_idx + 1;
+    group.desc.free_inodes_count -= 1;
+    local_idx
+  };
+  mark_group_dirty(fs, group_idx);
+
+  fs.superblock.free_inodes_count -= 1;
+  fs.superblock_dirty = true;
+  Ok(Some(group_idx * inodes_per_group + local_idx + 1))
 }
 
-fn alloc_inode_in_group(fs: &mut Filesystem, group_idx: u64) -> Result<Option<u64>> {
-  let group_id = group_idx as usize;
-  if fs.groups[group_id].desc.free_inodes_count == 0 {
-    return Ok(None)
-  }
+#[cfg(test)]
+mod test {
+  use prelude::*;
+  use test_fs::{make_fs, make_volume, mount};
 
-  match find_zero_bit_in_bitmap(&fs.groups[group_id].inode_bitmap[..]) {
-    Some((byte, bit)) => {
-      fs.groups[group_id].inode_bitmap[byte as usize] |= 1 << bit;
-      fs.groups[group_id].desc.free_inodes_count -= 1;
-      fs.groups[group_id].dirty = true;
-      fs.superblock.free_inodes_count -= 1;
-      fs.superblock_dirty = true;
-      Ok(Some(group_idx * fs.superblock.inodes_per_group as u64 + 
-              8 * byte + bit + 1))
-    },
-    None => Ok(None),
+  fn largest(extents: &[(u64, u64)]) -> u64 {
+    extents.iter().map(|&(_, length)| length).max().unwrap_or(0)
   }
-}
 
-fn find_zero_bit_in_bitmap(bitmap: &[u8]) -> Option<(u64, u64)> {
-  for byte in 0..bitmap.len() as u64 {
-    if bitmap[byte as usize] == 0xff {
-      continue
-    }
-    for bit in 0..8 {
-      if (bitmap[byte as usize] & (1 << bit)) == 0 {
-        return Some((byte, bit))
-      }
+  #[test]
+  fn free_run_summary() {
+    let mut fs = make_fs(1024);
+    let extents = free_extents(&fs, 0);
+    assert_eq!(extents.len(), 1);
+    let (first, length) = extents[0];
+    assert_eq!(first + length, fs.superblock.blocks_count as u64);
+    assert_eq!(largest_free_run(&mut fs, 0), length);
+
+    assert_eq!(alloc_extent(&mut fs, first + 10, 5, 5).unwrap(), Some((first + 10, 5)));
+    assert_eq!(free_extents(&fs, 0), vec![(first, 10), (first + 15, length - 15)]);
+    assert_eq!(largest_free_run(&mut fs, 0), length - 15);
+
+    dealloc_block(&mut fs, first + 12).unwrap();
+    let extents = free_extents(&fs, 0);
+    assert_eq!(extents, vec![(first, 10), (first + 12, 1), (first + 15, length - 15)]);
+    assert_eq!(largest_free_run(&mut fs, 0), largest(&extents[..]));
+
+    assert_eq!(alloc_extent(&mut fs, first, length - 14, length).unwrap(), None);
+    assert_eq!(alloc_extent(&mut fs, first, 11, 20).unwrap(), Some((first + 15, 20)));
+    assert_eq!(largest_free_run(&mut fs, 0), length - 35);
+
+    for block in (first + 10..first + 12).chain(first + 13..first + 35) {
+      dealloc_block(&mut fs, block).unwrap();
     }
+    assert_eq!(free_extents(&fs, 0), vec![(first, length)]);
+    assert_eq!(largest_free_run(&mut fs, 0), length);
+  }
+
+  #[test]
+  fn extent_goal_out_of_range() {
+    let mut fs = make_fs(1024);
+    let (first, _) = free_extents(&fs, 0)[0];
+    assert_eq!(alloc_extent(&mut fs, 0, 2, 2).unwrap(), Some((first, 2)));
+    let blocks_count = fs.superblock.blocks_count as u64;
+    assert_eq!(alloc_extent(&mut fs, blocks_count + 5, 2, 2).unwrap(), Some((first + 2, 2)));
+  }
+
+  #[test]
+  fn full_group_hint() {
+    let mut fs = mount(&make_volume(1024, 2, 128));
+    let (first, length) = free_extents(&fs, 0)[0];
+    assert_eq!(alloc_extent(&mut fs, first, length, length).unwrap(), Some((first, length)));
+    let group_size = fs.superblock.blocks_per_group as u64;
+    group_mut(&mut fs, 0).block_hint = group_size;
+
+    let (block, _) = alloc_blocks(&mut fs, 12, first, 1).unwrap().unwrap();
+    assert_eq!(get_block_group(&fs, block).0, 1);
+
+    dealloc_block(&mut fs, first + 3).unwrap();
+    assert_eq!(alloc_blocks(&mut fs, 13, first, 1).unwrap(), Some((first + 3, 1)));
   }
-  None
 }
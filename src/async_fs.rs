@@ -0,0 +1,701 @@
+use std::{cmp, future};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::collections::hash_map::{Entry};
+use std::future::{Future};
+use std::marker::{PhantomData};
+use std::pin::{Pin};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use prelude::*;
+
+pub type VolumeFuture<T> = Pin<Box<Future<Output = Result<T>> + Send>>;
+
+pub trait AsyncVolume: Send + Sync {
+  fn read_at(&self, offset: u64, length: u64) -> VolumeFuture<Vec<u8>>;
+  fn write_at(&self, offset: u64, data: Vec<u8>) -> VolumeFuture<()>;
+
+  fn sync(&self) -> VolumeFuture<()> {
+    Box::pin(future::ready(Ok(())))
+  }
+}
+
+pub struct AsyncFilesystem {
+  fs: Filesystem,
+  cache: Arc<Mutex<BlockCache>>,
+  volume: Arc<AsyncVolume>,
+}
+
+impl AsyncFilesystem {
+  pub fn fs(&self) -> &Filesystem {
+    &self.fs
+  }
+
+  fn fetcher(&self) -> Fetcher {
+    Fetcher::new(self.cache.clone(), self.volume.clone())
+  }
+}
+
+pub fn mount_fs_async(volume: Arc<AsyncVolume>, options: MountOptions)
+  -> impl Future<Output = Result<AsyncFilesystem>>
+{
+  let cache = Arc::new(Mutex::new(BlockCache::new()));
+  let fetcher = Fetcher::new(cache.clone(), volume.clone());
+  Retry::new(fetcher, move || {
+    try!(lock_cache(&cache)).discard_dirty();
+    let fs = try!(mount_fs_with_options(
+      Box::new(CachedVolume(cache.clone())), options.clone()));
+    try!(lock_cache(&cache)).fetch_size = fs.block_size();
+    Ok(AsyncFilesystem { fs: fs, cache: cache.clone(), volume: volume.clone() })
+  })
+}
+
+pub fn lookup_in_dir_async<'a>(afs: &'a mut AsyncFilesystem, creds: &'a Credentials,
+  dir_ino: u64, name: &'a [u8]) -> impl Future<Output = Result<Option<u64>>> + 'a
+{
+  let fetcher = afs.fetcher();
+  let fs = &mut afs.fs;
+  Retry::new(fetcher, move || lookup_in_dir(fs, creds, dir_ino, name))
+}
+
+pub fn open_file_async<'a>(afs: &'a mut AsyncFilesystem, creds: &'a Credentials,
+  ino: u64, access: u16) -> impl Future<Output = Result<FileHandle>> + 'a
+{
+  let fetcher = afs.fetcher();
+  let fs = &mut afs.fs;
+  Retry::new(fetcher, move || open_file(fs, creds, ino, access))
+}
+
+pub fn read_file_async<'a>(afs: &'a mut AsyncFilesystem, handle: &'a mut FileHandle,
+  offset: u64, buffer: &'a mut [u8]) -> impl Future<Output = Result<u64>> + 'a
+{
+  let fetcher = afs.fetcher();
+  let fs = &mut afs.fs;
+  Retry::new(fetcher, move || read_file(fs, handle, offset, buffer))
+}
+
+pub fn write_file_async<'a>(afs: &'a mut AsyncFilesystem, creds: &'a Credentials,
+  handle: &'a mut FileHandle, offset: u64, buffer: &'a [u8])
+  -> impl Future<Output = Result<u64>> + 'a
+{
+  let fetcher = afs.fetcher();
+  let cache = afs.cache.clone();
+  let fs = &mut afs.fs;
+  let mut prefetched = false;
+  Retry::new(fetcher, move || {
+    if !prefetched {
+      try!(prefetch_write(fs, handle.ino(), offset, buffer.len() as u64));
+      prefetched = true;
+    }
+    let result = write_file(fs, creds, handle, offset, buffer);
+    strict(&cache, result)
+  })
+}
+
+pub fn close_file_async<'a>(afs: &'a mut AsyncFilesystem, handle: FileHandle)
+  -> impl Future<Output = Result<()>> + 'a
+{
+  let fetcher = afs.fetcher();
+  let cache = afs.cache.clone();
+  let fs = &mut afs.fs;
+  let mut handle = Some(handle);
+  Retry::new(fetcher, move || {
+    let ino = match handle {
+      Some(ref handle) => handle.ino(),
+      None => return Err(Error::new(format!("File handle was already closed"))),
+    };
+    try!(prefetch_close(fs, ino));
+    let result = close_file(fs, handle.take().unwrap());
+    strict(&cache, result)
+  })
+}
+
+pub fn open_dir_async<'a>(afs: &'a mut AsyncFilesystem, creds: &'a Credentials,
+  ino: u64) -> impl Future<Output = Result<DirHandle>> + 'a
+{
+  let fetcher = afs.fetcher();
+  let fs = &mut afs.fs;
+  Retry::new(fetcher, move || open_dir(fs, creds, ino))
+}
+
+pub fn read_dir_async<'a>(afs: &'a mut AsyncFilesystem, handle: DirHandle)
+  -> impl Future<Output = Result<Option<(DirHandle, DirLine)>>> + 'a
+{
+  let fetcher = afs.fetcher();
+  let fs = &mut afs.fs;
+  Retry::new(fetcher, move || read_dir(fs, handle))
+}
+
+pub fn flush_fs_async<'a>(afs: &'a mut AsyncFilesystem)
+  -> impl Future<Output = Result<()>> + 'a
+{
+  FlushFs {
+    fetcher: afs.fetcher(),
+    fs: &mut afs.fs,
+    prefetched: false,
+    steps: None,
+    step: None,
+  }
+}
+
+// the superblock goes out after everything else has reached the volume, the
+// same order flush_fs keeps with its syncs
+enum FlushStep {
+  Write(u64, Vec<u8>),
+  Zero(u64, u64),
+  Sync,
+}
+
+struct FlushFs<'a> {
+  fs: &'a mut Filesystem,
+  fetcher: Fetcher,
+  prefetched: bool,
+  steps: Option<VecDeque<FlushStep>>,
+  step: Option<VolumeFuture<()>>,
+}
+
+impl<'a> Future for FlushFs<'a> {
+  type Output = Result<()>;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+    let this = &mut *self;
+    if this.steps.is_none() {
+      let cache = this.fetcher.cache.clone();
+      let fs = &mut *this.fs;
+      let prefetched = &mut this.prefetched;
+      let mut op = || {
+        if !*prefetched {
+          try!(prefetch_flush(fs));
+          *prefetched = true;
+        }
+        let result = flush_fs(fs);
+        strict(&cache, result)
+      };
+      match this.fetcher.poll_run(cx, &mut op) {
+        Poll::Ready(Ok(())) => {},
+        Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+        Poll::Pending => return Poll::Pending,
+      }
+      match lock_cache(&this.fetcher.cache) {
+        Ok(cache) => this.steps = Some(cache.flush_steps()),
+        Err(err) => return Poll::Ready(Err(err)),
+      }
+    }
+
+    loop {
+      if let Some(mut step) = this.step.take() {
+        match step.as_mut().poll(cx) {
+          Poll::Ready(Ok(())) => {},
+          Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+          Poll::Pending => {
+            this.step = Some(step);
+            return Poll::Pending
+          },
+        }
+      }
+      let volume = &this.fetcher.volume;
+      this.step = match this.steps.as_mut().and_then(|steps| steps.pop_front()) {
+        Some(FlushStep::Write(offset, data)) => Some(volume.write_at(offset, data)),
+        Some(FlushStep::Zero(offset, length)) => {
+          let chunk = cmp::min(length, ZERO_WRITE_SIZE);
+          if let Some(ref mut steps) = this.steps {
+            if chunk < length {
+              steps.push_front(FlushStep::Zero(offset + chunk, length - chunk));
+            }
+          }
+          Some(volume.write_at(offset, make_buffer(chunk)))
+        },
+        Some(FlushStep::Sync) => Some(volume.sync()),
+        None => break,
+      };
+    }
+
+    match lock_cache(&this.fetcher.cache) {
+      Ok(mut cache) => cache.clear(),
+      Err(err) => return Poll::Ready(Err(err)),
+    }
+    let unpinned_inos: Vec<u64> = this.fs.inode_cache.keys()
+      .filter(|ino| !this.fs.pinned_inos.contains_key(ino))
+      .cloned().collect();
+    for ino in unpinned_inos {
+      this.fs.inode_cache.remove(&ino);
+      this.fs.inode_lru.remove(&ino);
+    }
+    Poll::Ready(Ok(()))
+  }
+}
+
+fn prefetch_write(fs: &Filesystem, ino: u64, offset: u64, length: u64) -> Result<()> {
+  try!(prefetch_evictions(fs));
+  try!(prefetch_inode_slot(fs, ino));
+  let inode = try!(peek_inode(fs, ino));
+  let block_size = fs.block_size();
+  let first_block = (offset / block_size).saturating_sub(1);
+  let end_block = cmp::min(max_inode_blocks(fs),
+    (offset + length + block_size - 1) / block_size);
+  try!(map_range(fs, &inode, first_block, end_block.saturating_sub(first_block)));
+  Ok(())
+}
+
+fn prefetch_close(fs: &Filesystem, ino: u64) -> Result<()> {
+  try!(prefetch_evictions(fs));
+  try!(prefetch_inode_slot(fs, ino));
+  let inode = try!(peek_inode(fs, ino));
+  if inode.links_count == 0 && fs.open_counts.get(&ino) == Some(&1) {
+    try!(prefetch_blocks(fs, &inode));
+    let mut orphan_ino = fs.superblock.last_orphan as u64;
+    while orphan_ino != 0 && orphan_ino != ino {
+      orphan_ino = try!(peek_inode(fs, orphan_ino)).attr.dtime as u64;
+    }
+  }
+  Ok(())
+}
+
+fn prefetch_flush(fs: &Filesystem) -> Result<()> {
+  try!(prefetch_evictions(fs));
+  for &ino in fs.unsorted_dirs.iter() {
+    try!(prefetch_inode_slot(fs, ino));
+    let inode = try!(peek_inode(fs, ino));
+    if inode.mode.file_type == FileType::Dir && inode.links_count > 0 {
+      let mut data = make_buffer(inode.size);
+      try!(read_inode_data(fs, &inode, 0, &mut data[..]));
+    }
+  }
+
+  let table_block = fs.superblock.first_data_block as u64 + 1;
+  for group_idx in dirty_group_idxs(fs) {
+    let mut desc_buf = make_buffer(32);
+    try!(fs.volume.read(table_block * fs.block_size() + group_idx * 32,
+      &mut desc_buf[..]));
+  }
+
+  for file in fs.user_quota.iter().chain(fs.group_quota.iter()) {
+    if file.dirty {
+      try!(prefetch_inode_slot(fs, file.ino));
+      let inode = try!(peek_inode(fs, file.ino));
+      try!(prefetch_blocks(fs, &inode));
+    }
+  }
+  Ok(())
+}
+
+// a dirty inode pushed out of the inode cache is written back on the spot,
+// and write_inode reads the slot before it writes
+fn prefetch_evictions(fs: &Filesystem) -> Result<()> {
+  for &ino in fs.dirty_inos.iter() {
+    try!(prefetch_inode_slot(fs, ino));
+  }
+  Ok(())
+}
+
+fn prefetch_inode_slot(fs: &Filesystem, ino: u64) -> Result<()> {
+  let (offset, inode_size) = try!(locate_inode(fs, ino));
+  let mut inode_buf = make_buffer(inode_size);
+  fs.volume.read(offset, &mut inode_buf[..])
+}
+
+fn prefetch_blocks(fs: &Filesystem, inode: &Inode) -> Result<()> {
+  let block_size = fs.block_size();
+  let end_block = cmp::min(max_inode_blocks(fs),
+    (inode.size + block_size - 1) / block_size);
+  try!(map_range(fs, inode, 0, end_block));
+  Ok(())
+}
+
+fn max_inode_blocks(fs: &Filesystem) -> u64 {
+  let per_block = fs.block_size() / 4;
+  12 + per_block + per_block * per_block + per_block * per_block * per_block
+}
+
+fn strict<T>(cache: &Mutex<BlockCache>, result: Result<T>) -> Result<T> {
+  if result.is_err() && try!(lock_cache(cache)).missing.take().is_some() {
+    return Err(Error::new(format!(
+      "Block needed by a mutating operation was not prefetched")))
+  }
+  result
+}
+
+struct Retry<T, F> {
+  fetcher: Fetcher,
+  op: F,
+  result: PhantomData<fn() -> T>,
+}
+
+impl<T, F: FnMut() -> Result<T>> Retry<T, F> {
+  fn new(fetcher: Fetcher, op: F) -> Retry<T, F> {
+    Retry { fetcher: fetcher, op: op, result: PhantomData }
+  }
+}
+
+impl<T, F: FnMut() -> Result<T> + Unpin> Future for Retry<T, F> {
+  type Output = Result<T>;
+
+  fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<T>> {
+    let this = &mut *self;
+    this.fetcher.poll_run(cx, &mut this.op)
+  }
+}
+
+struct Fetcher {
+  cache: Arc<Mutex<BlockCache>>,
+  volume: Arc<AsyncVolume>,
+  fetch: Option<(u64, u64, VolumeFuture<Vec<u8>>)>,
+}
+
+impl Fetcher {
+  fn new(cache: Arc<Mutex<BlockCache>>, volume: Arc<AsyncVolume>) -> Fetcher {
+    Fetcher { cache: cache, volume: volume, fetch: None }
+  }
+
+  fn poll_run<T, F>(&mut self, cx: &mut Context, op: &mut F) -> Poll<Result<T>>
+    where F: FnMut() -> Result<T>
+  {
+    loop {
+      if let Some((offset, length, mut fetch)) = self.fetch.take() {
+        let data = match fetch.as_mut().poll(cx) {
+          Poll::Ready(Ok(data)) => data,
+          Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+          Poll::Pending => {
+            self.fetch = Some((offset, length, fetch));
+            return Poll::Pending
+          },
+        };
+        if data.len() as u64 != length {
+          return Poll::Ready(Err(Error::new(format!(
+            "Async volume returned {} bytes at offset {}, expected {}",
+            data.len(), offset, length))))
+        }
+        match lock_cache(&self.cache) {
+          Ok(mut cache) => cache.fill(offset, &data[..]),
+          Err(err) => return Poll::Ready(Err(err)),
+        }
+      }
+
+      let result = op();
+      let missing = match lock_cache(&self.cache) {
+        Ok(mut cache) => cache.missing.take(),
+        Err(err) => return Poll::Ready(Err(err)),
+      };
+      match (result, missing) {
+        (Err(_), Some((offset, length))) =>
+          self.fetch = Some((offset, length, self.volume.read_at(offset, length))),
+        (result, _) => {
+          if let Ok(mut cache) = self.cache.lock() {
+            cache.trim();
+          }
+          return Poll::Ready(result)
+        },
+      }
+    }
+  }
+}
+
+const CACHE_BLOCK_SIZE: u64 = 1024;
+const CACHE_CLEAN_BLOCKS: usize = 16 * 1024;
+const SUPERBLOCK_IDX: u64 = 1024 / CACHE_BLOCK_SIZE;
+const ZERO_WRITE_SIZE: u64 = 256 * 1024;
+
+struct CachedBlock {
+  data: Vec<u8>,
+  valid: Option<Vec<bool>>,
+  dirty: bool,
+}
+
+// dirty blocks stay until the flush, clean blocks are evicted in lru order
+// between operations, never in the middle of one. whole blocks written as
+// zeros (zero_free_space zeroes every free block) are kept as runs of block
+// indices instead of buffers
+struct BlockCache {
+  blocks: HashMap<u64, CachedBlock>,
+  clean_lru: Lru<u64>,
+  zero_runs: BTreeMap<u64, u64>,
+  fetch_size: u64,
+  missing: Option<(u64, u64)>,
+}
+
+impl BlockCache {
+  fn new() -> BlockCache {
+    BlockCache {
+      blocks: HashMap::new(),
+      clean_lru: Lru::new(),
+      zero_runs: BTreeMap::new(),
+      fetch_size: CACHE_BLOCK_SIZE,
+      missing: None,
+    }
+  }
+
+  fn read(&mut self, offset: u64, buffer: &mut [u8]) -> bool {
+    let end = offset + buffer.len() as u64;
+    let mut missing: Option<(u64, u64)> = None;
+    let mut pos = offset;
+    while pos < end {
+      let idx = pos / CACHE_BLOCK_SIZE;
+      let begin = (pos % CACHE_BLOCK_SIZE) as usize;
+      let length = cmp::min(end - pos, CACHE_BLOCK_SIZE - begin as u64) as usize;
+      let dst = (pos - offset) as usize;
+      let hit = match self.blocks.get(&idx) {
+        Some(block) if block.valid.as_ref().map_or(true,
+            |valid| valid[begin..begin + length].iter().all(|&v| v)) => {
+          buffer[dst..dst + length].copy_from_slice(&block.data[begin..begin + length]);
+          if !block.dirty {
+            self.clean_lru.touch(idx);
+          }
+          true
+        },
+        Some(_) => false,
+        None if self.zero_run(idx).is_some() => {
+          for b in buffer[dst..dst + length].iter_mut() {
+            *b = 0;
+          }
+          true
+        },
+        None => false,
+      };
+      if !hit {
+        missing = Some((missing.map_or(idx, |(first, _)| first), idx));
+      }
+      pos += length as u64;
+    }
+
+    match missing {
+      Some((first, last)) => {
+        let fetch_size = self.fetch_size;
+        let begin = first * CACHE_BLOCK_SIZE / fetch_size * fetch_size;
+        let end = ((last + 1) * CACHE_BLOCK_SIZE + fetch_size - 1) / fetch_size * fetch_size;
+        self.missing = Some((begin, end - begin));
+        false
+      },
+      None => true,
+    }
+  }
+
+  fn write(&mut self, offset: u64, buffer: &[u8]) {
+    let end = offset + buffer.len() as u64;
+    let first_whole = (offset + CACHE_BLOCK_SIZE - 1) / CACHE_BLOCK_SIZE;
+    let end_whole = end / CACHE_BLOCK_SIZE;
+    if first_whole < end_whole {
+      let head = (first_whole * CACHE_BLOCK_SIZE - offset) as usize;
+      let tail = (end_whole * CACHE_BLOCK_SIZE - offset) as usize;
+      if buffer[head..tail].iter().all(|&b| b == 0) {
+        self.write_blocks(offset, &buffer[..head]);
+        self.add_zero_run(first_whole, end_whole);
+        self.write_blocks(end_whole * CACHE_BLOCK_SIZE, &buffer[tail..]);
+        return
+      }
+    }
+    self.write_blocks(offset, buffer);
+  }
+
+  fn write_blocks(&mut self, offset: u64, buffer: &[u8]) {
+    let end = offset + buffer.len() as u64;
+    let mut pos = offset;
+    while pos < end {
+      let idx = pos / CACHE_BLOCK_SIZE;
+      let begin = (pos % CACHE_BLOCK_SIZE) as usize;
+      let length = cmp::min(end - pos, CACHE_BLOCK_SIZE - begin as u64) as usize;
+      if !self.blocks.contains_key(&idx) {
+        let valid = if self.take_zero_block(idx) {
+          None
+        } else {
+          Some(vec![false; CACHE_BLOCK_SIZE as usize])
+        };
+        self.blocks.insert(idx, CachedBlock {
+          data: make_buffer(CACHE_BLOCK_SIZE),
+          valid: valid,
+          dirty: false,
+        });
+      }
+      let block = self.blocks.get_mut(&idx).unwrap();
+      let src = (pos - offset) as usize;
+      block.data[begin..begin + length].copy_from_slice(&buffer[src..src + length]);
+      let complete = match block.valid {
+        Some(ref mut valid) => {
+          for v in valid[begin..begin + length].iter_mut() {
+            *v = true;
+          }
+          valid.iter().all(|&v| v)
+        },
+        None => true,
+      };
+      if complete {
+        block.valid = None;
+      }
+      block.dirty = true;
+      self.clean_lru.remove(&idx);
+      pos += length as u64;
+    }
+  }
+
+  fn zero_run(&self, idx: u64) -> Option<(u64, u64)> {
+    match self.zero_runs.range(..idx + 1).next_back() {
+      Some((&first, &end)) if idx < end => Some((first, end)),
+      _ => None,
+    }
+  }
+
+  fn add_zero_run(&mut self, first: u64, end: u64) {
+    if ((end - first) as usize) < self.blocks.len() {
+      for idx in first..end {
+        self.blocks.remove(&idx);
+        self.clean_lru.remove(&idx);
+      }
+    } else {
+      let idxs: Vec<u64> = self.blocks.keys()
+        .filter(|&&idx| idx >= first && idx < end).cloned().collect();
+      for idx in idxs {
+        self.blocks.remove(&idx);
+        self.clean_lru.remove(&idx);
+      }
+    }
+
+    let (mut first, mut end) = (first, end);
+    let touching: Vec<(u64, u64)> = self.zero_runs.range(..end + 1).rev()
+      .take_while(|&(_, &run_end)| run_end >= first)
+      .map(|(&run_first, &run_end)| (run_first, run_end)).collect();
+    for (run_first, run_end) in touching {
+      self.zero_runs.remove(&run_first);
+      first = cmp::min(first, run_first);
+      end = cmp::max(end, run_end);
+    }
+    self.zero_runs.insert(first, end);
+  }
+
+  fn take_zero_block(&mut self, idx: u64) -> bool {
+    match self.zero_run(idx) {
+      Some((first, end)) => {
+        self.zero_runs.remove(&first);
+        if first < idx {
+          self.zero_runs.insert(first, idx);
+        }
+        if idx + 1 < end {
+          self.zero_runs.insert(idx + 1, end);
+        }
+        true
+      },
+      None => false,
+    }
+  }
+
+  fn fill(&mut self, offset: u64, data: &[u8]) {
+    for (i, chunk) in data.chunks(CACHE_BLOCK_SIZE as usize).enumerate() {
+      let idx = offset / CACHE_BLOCK_SIZE + i as u64;
+      if self.zero_run(idx).is_some() {
+        continue
+      }
+      match self.blocks.entry(idx) {
+        Entry::Occupied(occupied) => {
+          let block = occupied.into_mut();
+          if let Some(valid) = block.valid.take() {
+            for j in 0..chunk.len() {
+              if !valid[j] {
+                block.data[j] = chunk[j];
+              }
+            }
+          }
+        },
+        Entry::Vacant(vacant) => {
+          vacant.insert(CachedBlock { data: chunk.to_vec(), valid: None, dirty: false });
+          self.clean_lru.touch(idx);
+        },
+      }
+    }
+  }
+
+  fn trim(&mut self) {
+    while self.clean_lru.len() > CACHE_CLEAN_BLOCKS {
+      match self.clean_lru.oldest(|_| true) {
+        Some(idx) => {
+          self.clean_lru.remove(&idx);
+          self.blocks.remove(&idx);
+        },
+        None => break,
+      }
+    }
+  }
+
+  fn flush_steps(&self) -> VecDeque<FlushStep> {
+    let mut idxs: Vec<u64> = self.blocks.iter()
+      .filter(|&(_, block)| block.dirty)
+      .map(|(&idx, _)| idx).collect();
+    idxs.sort();
+
+    let mut writes: VecDeque<(u64, Vec<u8>)> = VecDeque::new();
+    let mut superblock_writes: VecDeque<(u64, Vec<u8>)> = VecDeque::new();
+    for idx in idxs {
+      let block = &self.blocks[&idx];
+      let writes = if idx == SUPERBLOCK_IDX { &mut superblock_writes } else { &mut writes };
+      let mut begin = 0;
+      while begin < block.data.len() {
+        let is_valid = |i: usize| block.valid.as_ref().map_or(true, |valid| valid[i]);
+        if !is_valid(begin) {
+          begin += 1;
+          continue
+        }
+        let mut end = begin;
+        while end < block.data.len() && is_valid(end) {
+          end += 1;
+        }
+        push_write(writes, idx * CACHE_BLOCK_SIZE + begin as u64,
+          &block.data[begin..end]);
+        begin = end;
+      }
+    }
+
+    let mut steps: VecDeque<FlushStep> = VecDeque::new();
+    for (&first, &end) in self.zero_runs.iter() {
+      steps.push_back(FlushStep::Zero(first * CACHE_BLOCK_SIZE,
+        (end - first) * CACHE_BLOCK_SIZE));
+    }
+    steps.extend(writes.into_iter().map(|(offset, data)| FlushStep::Write(offset, data)));
+    if !steps.is_empty() {
+      steps.push_back(FlushStep::Sync);
+    }
+    if !superblock_writes.is_empty() {
+      steps.extend(superblock_writes.into_iter()
+        .map(|(offset, data)| FlushStep::Write(offset, data)));
+      steps.push_back(FlushStep::Sync);
+    }
+    steps
+  }
+
+  fn discard_dirty(&mut self) {
+    self.blocks.retain(|_, block| !block.dirty);
+    self.zero_runs.clear();
+  }
+
+  fn clear(&mut self) {
+    self.blocks.clear();
+    self.clean_lru.clear();
+    self.zero_runs.clear();
+  }
+}
+
+fn push_write(writes: &mut VecDeque<(u64, Vec<u8>)>, offset: u64, data: &[u8]) {
+  if let Some(&mut (last_offset, ref mut last_data)) = writes.back_mut() {
+    if last_offset + last_data.len() as u64 == offset {
+      last_data.extend_from_slice(data);
+      return
+    }
+  }
+  writes.push_back((offset, data.to_vec()));
+}
+
+struct CachedVolume(Arc<Mutex<BlockCache>>);
+
+impl Volume for CachedVolume {
+  fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<()> {
+    if try!(lock_cache(&self.0)).read(offset, buffer) {
+      Ok(())
+    } else {
+      Err(Error::new(format!("Volume data at offset {} is not cached yet", offset)))
+    }
+  }
+
+  fn write(&mut self, offset: u64, buffer: &[u8]) -> Result<()> {
+    try!(lock_cache(&self.0)).write(offset, buffer);
+    Ok(())
+  }
+}
+
+fn lock_cache(cache: &Mutex<BlockCache>) -> Result<MutexGuard<'_, BlockCache>> {
+  cache.lock().map_err(|_| Error::new(format!("Block cache lock is poisoned")))
+}
@@ -0,0 +1,104 @@
+use std::{cmp};
+use decode::{decode_u64};
+
+pub fn find_bit_in_bitmap(bitmap: &[u8], begin: u64, end: u64,
+  value: bool) -> Option<u64>
+{
+  let end = cmp::min(end, bitmap.len() as u64 * 8);
+  let mut idx = begin;
+  while idx < end {
+    if idx % 64 == 0 && idx + 64 <= end {
+      let word = decode_u64(&bitmap[(idx / 8) as usize..]);
+      let word = if value { word } else { !word };
+      if word == 0 {
+        idx += 64;
+        continue
+      }
+      return Some(idx + word.trailing_zeros() as u64)
+    }
+
+    let bit = (bitmap[(idx / 8) as usize] >> (idx % 8)) & 1;
+    if (bit == 1) == value {
+      return Some(idx)
+    }
+    idx += 1;
+  }
+  None
+}
+
+pub fn find_zero_runs_in_bitmap(bitmap: &[u8], begin: u64,
+  end: u64) -> Vec<(u64, u64)>
+{
+  let end = cmp::min(end, bitmap.len() as u64 * 8);
+  let mut runs = Vec::new();
+  let mut idx = begin;
+  while let Some(run_begin) = find_bit_in_bitmap(bitmap, idx, end, false) {
+    let run_end = find_bit_in_bitmap(bitmap, run_begin, end, true).unwrap_or(end);
+    runs.push((run_begin, run_end - run_begin));
+    idx = run_end;
+  }
+  runs
+}
+
+pub fn set_bit_in_bitmap(bitmap: &mut [u8], idx: u64, value: bool) {
+  if value {
+    bitmap[(idx / 8) as usize] |= 1 << (idx % 8);
+  } else {
+    bitmap[(idx / 8) as usize] &= !(1 << (idx % 8));
+  }
+}
+
+pub fn get_bit_in_bitmap(bitmap: &[u8], idx: u64) -> bool {
+  (bitmap[(idx / 8) as usize] & (1 << (idx % 8))) != 0
+}
+
+#[cfg(test)]
+mod test {
+  use bitmap::*;
+
+  fn bitmap_with_zeros(len: usize, zeros: &[(u64, u64)]) -> Vec<u8> {
+    let mut bitmap = vec![0xff; len];
+    for &(begin, end) in zeros.iter() {
+      for idx in begin..end {
+        set_bit_in_bitmap(&mut bitmap[..], idx, false);
+      }
+    }
+    bitmap
+  }
+
+  #[test]
+  fn hint_past_last_free_bit() {
+    let bitmap = bitmap_with_zeros(32, &[(3, 5), (70, 71)]);
+    assert_eq!(find_bit_in_bitmap(&bitmap[..], 0, 256, false), Some(3));
+    assert_eq!(find_bit_in_bitmap(&bitmap[..], 5, 256, false), Some(70));
+    assert_eq!(find_bit_in_bitmap(&bitmap[..], 71, 256, false), None);
+    assert_eq!(find_bit_in_bitmap(&bitmap[..], 256, 256, false), None);
+    assert_eq!(find_bit_in_bitmap(&bitmap[..], 300, 256, false), None);
+    assert_eq!(find_zero_runs_in_bitmap(&bitmap[..], 71, 256), vec![]);
+  }
+
+  #[test]
+  fn runs_across_words() {
+    let bitmap = bitmap_with_zeros(32, &[(60, 70), (127, 193)]);
+    assert_eq!(find_bit_in_bitmap(&bitmap[..], 0, 256, false), Some(60));
+    assert_eq!(find_bit_in_bitmap(&bitmap[..], 64, 256, false), Some(64));
+    assert_eq!(find_bit_in_bitmap(&bitmap[..], 64, 256, true), Some(70));
+    assert_eq!(find_bit_in_bitmap(&bitmap[..], 128, 256, true), Some(193));
+    assert_eq!(find_zero_runs_in_bitmap(&bitmap[..], 0, 256),
+      vec![(60, 10), (127, 66)]);
+    assert_eq!(find_zero_runs_in_bitmap(&bitmap[..], 65, 256),
+      vec![(65, 5), (127, 66)]);
+  }
+
+  #[test]
+  fn group_size_not_multiple_of_64() {
+    let mut bitmap = bitmap_with_zeros(32, &[(150, 200)]);
+    for idx in 200..256 {
+      set_bit_in_bitmap(&mut bitmap[..], idx, false);
+    }
+    assert_eq!(find_zero_runs_in_bitmap(&bitmap[..], 0, 200), vec![(150, 50)]);
+    assert_eq!(find_bit_in_bitmap(&bitmap[..], 192, 200, true), None);
+    assert_eq!(find_bit_in_bitmap(&bitmap[..], 199, 200, false), Some(199));
+    assert_eq!(find_bit_in_bitmap(&bitmap[..], 200, 200, false), None);
+  }
+}
@@ -0,0 +1,327 @@
+use std::{cmp};
+use prelude::*;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BlockExtent {
+  pub inode_block: u64,
+  pub block: Option<u64>,
+  pub length: u64,
+}
+
+#[derive(Debug)]
+pub struct BlockMap {
+  ino: u64,
+  version: u64,
+  levels: [Option<IndirectBlock>; 3],
+}
+
+#[derive(Debug)]
+struct IndirectBlock {
+  block: u64,
+  entries: Vec<u64>,
+}
+
+impl BlockMap {
+  pub fn new(ino: u64) -> BlockMap {
+    BlockMap { ino: ino, version: 0, levels: [None, None, None] }
+  }
+
+  fn reset(&mut self, fs: &Filesystem, ino: u64) {
+    let version = fs.block_map_version();
+    if self.ino != ino || self.version != version {
+      self.ino = ino;
+      self.version = version;
+      self.levels = [None, None, None];
+    }
+  }
+}
+
+pub fn map_range(fs: &Filesystem, inode: &Inode,
+  first_block: u64, count: u64) -> Result<Vec<BlockExtent>>
+{
+  map_range_with(fs, &mut BlockMap::new(inode.ino), inode, first_block, count)
+}
+
+pub fn map_range_with(fs: &Filesystem, map: &mut BlockMap, inode: &Inode,
+  first_block: u64, count: u64) -> Result<Vec<BlockExtent>>
+{
+  let end_block = first_block + count;
+  let mut extents: Vec<BlockExtent> = Vec::new();
+  let mut inode_block = first_block;
+  while inode_block < end_block {
+    let (block, span) = try!(lookup_block(fs, map, inode, inode_block));
+    let length = match block {
+      Some(_) => 1,
+      None => cmp::min(span, end_block - inode_block),
+    };
+
+    let merged = match extents.last_mut() {
+      Some(last) => {
+        let contiguous = match (last.block, block) {
+          (Some(last_block), Some(block)) => block == last_block + last.length,
+          (None, None) => true,
+          _ => false,
+        };
+        if contiguous {
+          last.length += length;
+        }
+        contiguous
+      },
+      None => false,
+    };
+    if !merged {
+      extents.push(BlockExtent { inode_block: inode_block, block: block, length: length });
+    }
+    inode_block += length;
+  }
+  Ok(extents)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FileExtents {
+  pub data: Vec<BlockExtent>,
+  pub metadata: Vec<MetadataBlock>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MetadataBlock {
+  pub block: u64,
+  pub level: usize,
+  pub inode_block: u64,
+}
+
+pub fn file_extents(fs: &mut Filesystem, ino: u64) -> Result<FileExtents> {
+  let inode = try!(get_inode(fs, ino));
+  let mut extents = FileExtents::default();
+  if !inode_has_blocks(fs, &inode) {
+    return Ok(extents)
+  }
+
+  let block_size = fs.block_size();
+  let block_count = (inode.size + block_size - 1) / block_size;
+  extents.data = try!(map_range(fs, &inode, 0, block_count));
+
+  let per_block = block_size / 4;
+  let mut inode_block = 12;
+  for level in 1..4 {
+    let block = inode.block[11 + level] as u64;
+    try!(collect_indirect(fs, block, level, inode_block, &mut extents.metadata));
+    inode_block += per_block.pow(level as u32);
+  }
+  Ok(extents)
+}
+
+fn collect_indirect(fs: &Filesystem, indirect_block: u64, level: usize,
+  inode_block: u64, metadata: &mut Vec<MetadataBlock>) -> Result<()>
+{
+  if indirect_block == 0 {
+    return Ok(())
+  }
+  metadata.push(MetadataBlock { block: indirect_block, level: level, inode_block: inode_block });
+  if level == 1 {
+    return Ok(())
+  }
+
+  let block_size = fs.block_size();
+  let span = (block_size / 4).pow(level as u32 - 1);
+  let mut buffer = make_buffer(block_size);
+  try!(fs.volume.read(indirect_block * block_size, &mut buffer[..]));
+  for i in 0..block_size / 4 {
+    let block = decode_u32(&buffer[i as usize * 4..]) as u64;
+    try!(collect_indirect(fs, block, level - 1, inode_block + i * span, metadata));
+  }
+  Ok(())
+}
+
+pub fn map_block(fs: &Filesystem, map: &mut BlockMap, inode: &Inode,
+  inode_block: u64) -> Result<Option<u64>>
+{
+  Ok(try!(lookup_block(fs, map, inode, inode_block)).0)
+}
+
+fn lookup_block(fs: &Filesystem, map: &mut BlockMap, inode: &Inode,
+  inode_block: u64) -> Result<(Option<u64>, u64)>
+{
+  map.reset(fs, inode.ino);
+  let per_block = fs.block_size() / 4;
+  let hole = |span: u64| -> Result<(Option<u64>, u64)> { Ok((None, span)) };
+  let found = |block: u64| -> Result<(Option<u64>, u64)> {
+    Ok(if block == 0 { (None, 1) } else { (Some(block), 1) })
+  };
+
+  match inode_block_to_pos(fs, inode_block) {
+    BlockPos::Level0(level0) =>
+      found(inode.block[level0 as usize] as u64),
+    BlockPos::Level1(level0) => {
+      let block1 = inode.block[12] as u64;
+      if block1 == 0 { return hole(per_block - level0) }
+      found(try!(map_entry(fs, map, 1, block1, level0)))
+    },
+    BlockPos::Level2(level1, level0) => {
+      let block2 = inode.block[13] as u64;
+      if block2 == 0 { return hole(per_block * (per_block - level1) - level0) }
+      let block1 = try!(map_entry(fs, map, 2, block2, level1));
+      if block1 == 0 { return hole(per_block - level0) }
+      found(try!(map_entry(fs, map, 1, block1, level0)))
+    },
+    BlockPos::Level3(level2, level1, level0) => {
+      let block3 = inode.block[14] as u64;
+      if block3 == 0 {
+        return hole(per_block * per_block * (per_block - level2) -
+          per_block * level1 - level0)
+      }
+      let block2 = try!(map_entry(fs, map, 3, block3, level2));
+      if block2 == 0 { return hole(per_block * (per_block - level1) - level0) }
+      let block1 = try!(map_entry(fs, map, 2, block2, level1));
+      if block1 == 0 { return hole(per_block - level0) }
+      found(try!(map_entry(fs, map, 1, block1, level0)))
+    },
+    BlockPos::OutOfRange =>
+      Err(Error::new(
+        format!("File block {} is out of range for reading", inode_block))),
+  }
+}
+
+pub fn map_entry(fs: &Filesystem, map: &mut BlockMap, level: usize,
+  indirect_block: u64, entry: u64) -> Result<u64>
+{
+  map.reset(fs, map.ino);
+  if let Some(ref cached) = map.levels[level - 1] {
+    if cached.block == indirect_block {
+      return Ok(cached.entries[entry as usize])
+    }
+  }
+
+  let block_size = fs.block_size();
+  let mut buffer = make_buffer(block_size);
+  try!(fs.volume.read(indirect_block * block_size, &mut buffer[..]));
+  let entries: Vec<u64> = (0..block_size / 4)
+    .map(|i| decode_u32(&buffer[i as usize * 4..]) as u64)
+    .collect();
+  let link = entries[entry as usize];
+  map.levels[level - 1] = Some(IndirectBlock { block: indirect_block, entries: entries });
+  Ok(link)
+}
+
+pub fn set_map_entry(fs: &mut Filesystem, map: &mut BlockMap, level: usize,
+  indirect_block: u64, entry: u64, link: u64) -> Result<()>
+{
+  let current = map.version == fs.block_map_version();
+  try!(write_indirect(fs, indirect_block, entry, link));
+  note_map_entry(fs, map, current, level, indirect_block, entry, link);
+  Ok(())
+}
+
+pub fn set_map_entry_shared(fs: &Filesystem, map: &mut BlockMap, level: usize,
+  indirect_block: u64, entry: u64, link: u64) -> Result<()>
+{
+  let current = map.version == fs.block_map_version();
+  let mut buffer = [0; 4];
+  let entry_offset = indirect_block * fs.block_size() + entry * 4;
+  assert!(entry < fs.block_size() / 4);
+  encode_u32(link as u32, &mut buffer[..]);
+  fs.bump_block_map_version();
+  try!(fs.volume.write_shared(entry_offset, &buffer[..]));
+  note_map_entry(fs, map, current, level, indirect_block, entry, link);
+  Ok(())
+}
+
+fn note_map_entry(fs: &Filesystem, map: &mut BlockMap, current: bool, level: usize,
+  indirect_block: u64, entry: u64, link: u64)
+{
+  if current {
+    map.version = fs.block_map_version();
+    if let Some(ref mut cached) = map.levels[level - 1] {
+      if cached.block == indirect_block {
+        cached.entries[entry as usize] = link;
+      }
+    }
+  }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlockSlot {
+  Inode(usize),
+  Indirect(u64, u64),
+  Missing,
+}
+
+pub fn find_block_slot(fs: &Filesystem, map: &mut BlockMap, inode: &Inode,
+  inode_block: u64) -> Result<BlockSlot>
+{
+  map.reset(fs, inode.ino);
+  match inode_block_to_pos(fs, inode_block) {
+    BlockPos::Level0(level0) =>
+      Ok(BlockSlot::Inode(level0 as usize)),
+    BlockPos::Level1(level0) =>
+      leaf_slot(fs, map, inode.block[12] as u64, &[], level0),
+    BlockPos::Level2(level1, level0) =>
+      leaf_slot(fs, map, inode.block[13] as u64, &[level1], level0),
+    BlockPos::Level3(level2, level1, level0) =>
+      leaf_slot(fs, map, inode.block[14] as u64, &[level2, level1], level0),
+    BlockPos::OutOfRange =>
+      Ok(BlockSlot::Missing),
+  }
+}
+
+fn leaf_slot(fs: &Filesystem, map: &mut BlockMap, root_block: u64,
+  path: &[u64], entry: u64) -> Result<BlockSlot>
+{
+  let mut block = root_block;
+  for (i, &path_entry) in path.iter().enumerate() {
+    if block == 0 {
+      return Ok(BlockSlot::Missing)
+    }
+    block = try!(map_entry(fs, map, path.len() + 1 - i, block, path_entry));
+  }
+  Ok(if block == 0 { BlockSlot::Missing } else { BlockSlot::Indirect(block, entry) })
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlockPos {
+  Level0(u64),
+  Level1(u64),
+  Level2(u64, u64),
+  Level3(u64, u64, u64),
+  OutOfRange,
+}
+
+pub fn inode_block_to_pos(fs: &Filesystem, inode_block: u64) -> BlockPos {
+  let indirect_1_size: u64 = fs.block_size() / 4;
+  let indirect_2_size = indirect_1_size * indirect_1_size;
+  let indirect_3_size = indirect_1_size * indirect_2_size;
+  if inode_block < 12 {
+    BlockPos::Level0(inode_block)
+  } else if inode_block < 12 + indirect_1_size {
+    BlockPos::Level1(inode_block - 12)
+  } else if inode_block < 12 + indirect_1_size + indirect_2_size {
+    let base = inode_block - 12 - indirect_1_size;
+    BlockPos::Level2(base / indirect_1_size, base % indirect_1_size)
+  } else if inode_block < 12 + indirect_1_size + indirect_2_size + indirect_3_size {
+    let base = inode_block - 12 - indirect_1_size - indirect_2_size;
+    BlockPos::Level3(base / indirect_2_size,
+      (base % indirect_2_size) / indirect_1_size,
+      (base % indirect_2_size) % indirect_1_size)
+  } else {
+    BlockPos::OutOfRange
+  }
+}
+
+pub fn read_indirect(fs: &Filesystem, indirect_block: u64, entry: u64) -> Result<u64> {
+  let mut buffer = [0; 4];
+  let entry_offset = indirect_block * fs.block_size() + entry * 4;
+  assert!(entry < fs.block_size() / 4);
+  try!(fs.volume.read(entry_offset, &mut buffer[..]));
+  Ok(decode_u32(&buffer[..]) as u64)
+}
+
+pub fn write_indirect(fs: &mut Filesystem, indirect_block: u64,
+  entry: u64, link: u64) -> Result<()>
+{
+  let mut buffer = [0; 4];
+  let entry_offset = indirect_block * fs.block_size() + entry * 4;
+  assert!(entry < fs.block_size() / 4);
+  encode_u32(link as u32, &mut buffer[..]);
+  fs.bump_block_map_version();
+  fs.volume.write(entry_offset, &buffer[..])
+}
@@ -0,0 +1,38 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use defs::*;
+
+pub trait Clock: Send + Sync {
+  fn now(&self) -> Timestamp;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> Timestamp {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+      Ok(since) => Timestamp {
+        sec: since.as_secs() as i64,
+        nsec: since.subsec_nanos(),
+      },
+      Err(err) => {
+        let before = err.duration();
+        if before.subsec_nanos() == 0 {
+          Timestamp { sec: -(before.as_secs() as i64), nsec: 0 }
+        } else {
+          Timestamp {
+            sec: -(before.as_secs() as i64) - 1,
+            nsec: 1_000_000_000 - before.subsec_nanos(),
+          }
+        }
+      },
+    }
+  }
+}
+
+pub struct FixedClock(pub Timestamp);
+
+impl Clock for FixedClock {
+  fn now(&self) -> Timestamp {
+    self.0
+  }
+}
@@ -0,0 +1,115 @@
+use prelude::*;
+
+pub fn cached_dentry(fs: &Filesystem, dir_ino: u64, name: &[u8]) -> Option<Option<u64>> {
+  fs.dentry_cache.get(&(dir_ino, name.to_vec())).cloned()
+}
+
+pub fn lookup_dentry(fs: &mut Filesystem, dir_inode: &Inode, name: &[u8])
+  -> Result<Option<u64>>
+{
+  let key = (dir_inode.ino, name.to_vec());
+  if let Some(&entry) = fs.dentry_cache.get(&key) {
+    fs.dentry_lru.touch(key);
+    fs.cache_stats.dentry_hits += 1;
+    return Ok(entry)
+  }
+
+  fs.cache_stats.dentry_misses += 1;
+  let entry = try!(find_in_dir(fs, dir_inode, name));
+  cache_dentry(fs, dir_inode.ino, name, entry);
+  Ok(entry)
+}
+
+pub fn cache_dentry(fs: &mut Filesystem, dir_ino: u64, name: &[u8], entry: Option<u64>) {
+  let key = (dir_ino, name.to_vec());
+  fs.dentry_cache.insert(key.clone(), entry);
+  fs.dentry_lru.touch(key);
+  refit_dentry_cache(fs);
+}
+
+pub fn forget_dentry(fs: &mut Filesystem, dir_ino: u64, name: &[u8]) {
+  let key = (dir_ino, name.to_vec());
+  if fs.dentry_cache.remove(&key).is_some() {
+    fs.dentry_lru.remove(&key);
+  }
+}
+
+pub fn forget_dir_dentries(fs: &mut Filesystem, dir_ino: u64) {
+  let keys: Vec<(u64, Vec<u8>)> = fs.dentry_cache.keys()
+    .filter(|&&(ino, _)| ino == dir_ino)
+    .cloned().collect();
+  for key in keys {
+    fs.dentry_cache.remove(&key);
+    fs.dentry_lru.remove(&key);
+  }
+}
+
+fn refit_dentry_cache(fs: &mut Filesystem) {
+  while fs.dentry_cache.len() > fs.options.cache.dentry_count {
+    match fs.dentry_lru.oldest(|_| true) {
+      Some(key) => {
+        fs.dentry_cache.remove(&key);
+        fs.dentry_lru.remove(&key);
+      },
+      None => break,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use prelude::*;
+  use shared::{SharedFilesystem};
+  use test_fs::{make_fs, make_file, make_dir};
+
+  fn lookup(fs: &mut Filesystem, dir_ino: u64, name: &[u8]) -> Option<u64> {
+    lookup_in_dir(fs, &Credentials::root(), dir_ino, name).unwrap()
+  }
+
+  #[test]
+  fn negative_entry_dropped_on_create() {
+    let mut fs = make_fs(1024);
+    let stats = cache_stats(&fs);
+    assert_eq!(lookup(&mut fs, ROOT_INO, b"f"), None);
+    assert_eq!(lookup(&mut fs, ROOT_INO, b"f"), None);
+    assert_eq!(cached_dentry(&fs, ROOT_INO, b"f"), Some(None));
+    assert_eq!(cache_stats(&fs).dentry_hits - stats.dentry_hits, 1);
+    assert_eq!(cache_stats(&fs).dentry_misses - stats.dentry_misses, 1);
+
+    let ino = make_file(&mut fs, ROOT_INO, b"f").ino;
+    assert_eq!(lookup(&mut fs, ROOT_INO, b"f"), Some(ino));
+
+    let dir_ino = make_dir(&mut fs, ROOT_INO, b"d").ino;
+    assert_eq!(lookup(&mut fs, dir_ino, b"g"), None);
+    move_between_dirs(&mut fs, &Credentials::root(), ROOT_INO, b"f", dir_ino, b"g").unwrap();
+    assert_eq!(lookup(&mut fs, ROOT_INO, b"f"), None);
+    assert_eq!(lookup(&mut fs, dir_ino, b"g"), Some(ino));
+
+    unlink_in_dir(&mut fs, &Credentials::root(), dir_ino, b"g").unwrap();
+    assert_eq!(lookup(&mut fs, dir_ino, b"g"), None);
+    let new_ino = make_file(&mut fs, dir_ino, b"g").ino;
+    assert_eq!(lookup(&mut fs, dir_ino, b"g"), Some(new_ino));
+  }
+
+  #[test]
+  fn shared_negative_entry_dropped_on_create() {
+    let shared = SharedFilesystem::new(make_fs(1024));
+    let root = Credentials::root();
+    assert_eq!(shared.lookup_in_dir(&root, ROOT_INO, b"f").unwrap(), None);
+    let ino = make_file(&mut shared.write().unwrap(), ROOT_INO, b"f").ino;
+    assert_eq!(shared.lookup_in_dir(&root, ROOT_INO, b"f").unwrap(), Some(ino));
+  }
+
+  #[test]
+  fn dentry_cache_keeps_recent_entries() {
+    let mut fs = make_fs(1024);
+    fs.options.cache.dentry_count = 2;
+    lookup(&mut fs, ROOT_INO, b"a");
+    lookup(&mut fs, ROOT_INO, b"b");
+    lookup(&mut fs, ROOT_INO, b"a");
+    lookup(&mut fs, ROOT_INO, b"c");
+    assert_eq!(cached_dentry(&fs, ROOT_INO, b"a"), Some(None));
+    assert_eq!(cached_dentry(&fs, ROOT_INO, b"b"), None);
+    assert_eq!(cached_dentry(&fs, ROOT_INO, b"c"), Some(None));
+  }
+}
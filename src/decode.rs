@@ -30,8 +30,19 @@ pub fn decode_superblock(bytes: &[u8], read_only: bool) -> Result<Superblock> {
             feature_ro_compat)));
   }
 
+  let mut uuid = [0; 16];
+  let mut volume_name = [0; 16];
+  let mut hash_seed = [0; 16];
+  if rev >= 1 {
+    uuid.copy_from_slice(&bytes[104..120]);
+    volume_name.copy_from_slice(&bytes[120..136]);
+    hash_seed.copy_from_slice(&bytes[236..252]);
+  }
+
   Ok(Superblock {
+    inodes_count: decode_u32(&bytes[0..]),
     blocks_count: decode_u32(&bytes[4..]),
+    r_blocks_count: decode_u32(&bytes[8..]),
     free_blocks_count: decode_u32(&bytes[12..]),
     free_inodes_count: decode_u32(&bytes[16..]),
     first_data_block: decode_u32(&bytes[20..]),
@@ -40,11 +51,19 @@ pub fn decode_superblock(bytes: &[u8], read_only: bool) -> Result<Superblock> {
     inodes_per_group: decode_u32(&bytes[40..]),
     state: state,
     rev_level: rev,
+    def_resuid: decode_u16(&bytes[80..]),
+    def_resgid: decode_u16(&bytes[82..]),
     first_ino: if rev >= 1 { decode_u32(&bytes[84..]) } else { 11 },
     inode_size: if rev >= 1 { decode_u16(&bytes[88..]) } else { 128 },
     feature_compat: feature_compat,
     feature_incompat: feature_incompat,
     feature_ro_compat: feature_ro_compat,
+    uuid: uuid,
+    volume_name: volume_name,
+    hash_seed: hash_seed,
+    last_orphan: if rev >= 1 { decode_u32(&bytes[232..]) } else { 0 },
+    usr_quota_inum: if rev >= 1 { decode_u32(&bytes[576..]) } else { 0 },
+    grp_quota_inum: if rev >= 1 { decode_u32(&bytes[580..]) } else { 0 },
   })
 }
 
@@ -66,7 +85,7 @@ pub fn decode_inode(superblock: &Superblock, ino: u64, bytes: &[u8]) -> Result<I
 
   let size_low = decode_u32(&bytes[4..]) as u64;
   let size_high =
-    if superblock.rev_level >= 1 && mode.file_type == FileType::Regular {
+    if inode_has_size_high(superblock, mode.file_type) {
       decode_u32(&bytes[108..])
     } else {
       0
@@ -77,31 +96,78 @@ pub fn decode_inode(superblock: &Superblock, ino: u64, bytes: &[u8]) -> Result<I
   let gid_low = decode_u16(&bytes[24..]) as u32;
   let gid_high = decode_u16(&bytes[122..]) as u32;
 
+  let flags = decode_u32(&bytes[32..]);
+  let blocks_low = decode_u32(&bytes[28..]) as u64;
+  let blocks_high =
+    if (superblock.feature_ro_compat & RO_COMPAT_HUGE_FILE) != 0 {
+      decode_u16(&bytes[116..])
+    } else {
+      0
+    } as u64;
+  let size_512 =
+    if (flags & INODE_FLAG_HUGE_FILE) != 0 {
+      (blocks_low + (blocks_high << 32)) << (superblock.log_block_size + 1)
+    } else {
+      blocks_low + (blocks_high << 32)
+    };
+
   let mut block = [0; 15];
   for i in 0..15 {
     block[i] = decode_u32(&bytes[40 + 4*i..])
   }
 
+  let extra_isize =
+    if bytes.len() >= 130 { decode_u16(&bytes[128..]) } else { 0 };
+  let extra = |offset: usize| -> Option<u32> {
+    if offset + 4 <= 128 + extra_isize as usize && offset + 4 <= bytes.len() {
+      Some(decode_u32(&bytes[offset..]))
+    } else {
+      None
+    }
+  };
+
   Ok(Inode {
     ino: ino,
     mode: mode,
     attr: FileAttr {
       uid: uid_low + (uid_high << 16),
       gid: gid_low + (gid_high << 16),
-      atime: decode_u32(&bytes[8..]),
-      ctime: decode_u32(&bytes[12..]),
-      mtime: decode_u32(&bytes[16..]),
+      atime: decode_timestamp(decode_u32(&bytes[8..]), extra(140)),
+      ctime: decode_timestamp(decode_u32(&bytes[12..]), extra(132)),
+      mtime: decode_timestamp(decode_u32(&bytes[16..]), extra(136)),
+      crtime: match extra(144) {
+        Some(crtime) => decode_timestamp(crtime, extra(148)),
+        None => Timestamp::default(),
+      },
       dtime: decode_u32(&bytes[20..]),
     },
     size: size_low + (size_high << 32),
-    size_512: decode_u32(&bytes[28..]),
+    size_512: size_512,
     links_count: decode_u16(&bytes[26..]),
-    flags: decode_u32(&bytes[32..]),
+    flags: flags,
     block: block,
     file_acl: decode_u32(&bytes[104..]),
+    extra_isize: extra_isize,
   })
 }
 
+// without large_dir, ext2 keeps dir_acl where other inodes keep the high size word
+pub fn inode_has_size_high(superblock: &Superblock, file_type: FileType) -> bool {
+  superblock.rev_level >= 1 && (file_type != FileType::Dir ||
+    (superblock.feature_incompat & INCOMPAT_LARGE_DIR) != 0)
+}
+
+pub fn decode_timestamp(base: u32, extra: Option<u32>) -> Timestamp {
+  let sec = base as i32 as i64;
+  match extra {
+    Some(extra) => Timestamp {
+      sec: sec + (((extra & 0b11) as i64) << 32),
+      nsec: extra >> 2,
+    },
+    None => Timestamp { sec: sec, nsec: 0 },
+  }
+}
+
 pub fn decode_inode_mode(mode: u16) -> Result<Mode> {
   let type_nibble = (mode & 0xf000) >> 12;
   let file_type = match type_nibble {
@@ -167,3 +233,8 @@ pub fn decode_u32(bytes: &[u8]) -> u32 {
   ((bytes[2] as u32) << 16) +
   ((bytes[3] as u32) << 24)
 }
+
+pub fn decode_u64(bytes: &[u8]) -> u64 {
+  (decode_u32(&bytes[0..]) as u64) +
+  ((decode_u32(&bytes[4..]) as u64) << 32)
+}
@@ -0,0 +1,231 @@
+use prelude::*;
+
+const MAX_COPY_BLOCKS: u64 = 256;
+
+#[derive(Debug, Copy, Clone)]
+pub struct DefragOptions {
+  pub compact_dirs: bool,
+  pub min_extents: u64,
+}
+
+impl Default for DefragOptions {
+  fn default() -> DefragOptions {
+    DefragOptions { compact_dirs: true, min_extents: 2 }
+  }
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct DefragReport {
+  pub before: Fragmentation,
+  pub after: Fragmentation,
+  pub files: u64,
+  pub blocks: u64,
+}
+
+#[derive(Debug)]
+struct Slot {
+  old_block: u64,
+  level: usize,
+  children: Vec<(u64, u64)>,
+}
+
+pub fn defrag_file(fs: &mut Filesystem, ino: u64) -> Result<DefragReport> {
+  if ino < fs.superblock.first_ino as u64 && ino != ROOT_INO {
+    return Err(Error::of_kind(ErrorKind::InvalidInput,
+      format!("Inode {} is reserved and cannot be defragmented", ino)))
+  } else if ino > fs.superblock.inodes_count as u64 {
+    return Err(Error::of_kind(ErrorKind::InvalidInput,
+      format!("Inode {} is out of range", ino)))
+  }
+
+  let (group_idx, local_idx) = get_ino_group(fs, ino);
+  if !get_bit_in_bitmap(&lock_group(fs, group_idx).inode_bitmap[..], local_idx) {
+    return Err(Error::of_kind(ErrorKind::NotFound,
+      format!("Inode {} is not allocated", ino)))
+  }
+  let inode = try!(get_inode(fs, ino));
+  if inode.links_count == 0 && !fs.open_counts.contains_key(&ino) {
+    return Err(Error::of_kind(ErrorKind::NotFound,
+      format!("Inode {} is deleted", ino)))
+  }
+
+  let before = try!(inode_fragmentation(fs, ino));
+  let blocks = try!(relocate_inode(fs, ino));
+  let after = try!(inode_fragmentation(fs, ino));
+  Ok(DefragReport {
+    before: before,
+    after: after,
+    files: if blocks > 0 { 1 } else { 0 },
+    blocks: blocks,
+  })
+}
+
+pub fn defrag_fs(fs: &mut Filesystem, options: DefragOptions) -> Result<DefragReport> {
+  let before = try!(fs_fragmentation(fs));
+  let mut report = DefragReport { before: before, ..DefragReport::default() };
+
+  for ino in used_inos(fs) {
+    let inode = try!(get_inode(fs, ino));
+    if options.compact_dirs && inode.mode.file_type == FileType::Dir {
+      try!(compact_dir(fs, ino));
+    }
+    if try!(inode_fragmentation(fs, ino)).extents < options.min_extents {
+      continue
+    }
+
+    match relocate_inode(fs, ino) {
+      Ok(0) => {},
+      Ok(blocks) => {
+        report.files += 1;
+        report.blocks += blocks;
+      },
+      Err(ref err) if err.kind() == ErrorKind::NoSpace => {},
+      Err(err) => return Err(err),
+    }
+  }
+
+  report.after = try!(fs_fragmentation(fs));
+  Ok(report)
+}
+
+fn relocate_inode(fs: &mut Filesystem, ino: u64) -> Result<u64> {
+  let mut inode = try!(get_inode(fs, ino));
+  if !inode_has_blocks(fs, &inode) {
+    return Ok(0)
+  }
+
+  let mut slots = Vec::new();
+  let mut roots = Vec::new();
+  for i in 0..15 {
+    let level = if i < 12 { 0 } else { i - 11 };
+    let block = inode.block[i] as u64;
+    if block != 0 {
+      roots.push((i, try!(plan_slots(fs, block, level, &mut slots))));
+    }
+  }
+
+  let in_place = slots.windows(2).all(|pair| pair[1].old_block == pair[0].old_block + 1);
+  if slots.is_empty() || in_place {
+    return Ok(0)
+  }
+
+  let count = slots.len() as u64;
+  let (group_idx, _) = get_ino_group(fs, ino);
+  let goal = group_first_block(fs, group_idx);
+  let first = match try!(alloc_extent(fs, goal, count, count)) {
+    Some((first, _)) => first,
+    None => return Err(Error::of_kind(ErrorKind::NoSpace, format!(
+      "No contiguous run of {} free blocks for inode {}", count, ino))),
+  };
+
+  // set_inode_block refuses to replace a mapped block and allocates indirect
+  // blocks on its own, so the new tree is built in the reserved run with
+  // copy_slots and switched over by a single inode update. the raw volume
+  // writes bypass write_indirect, so stale BlockMap cursors and readahead
+  // state have to be invalidated by hand
+  fs.bump_data_version();
+  fs.bump_block_map_version();
+  if let Err(err) = copy_slots(fs, &slots, first) {
+    for block in first..first + count {
+      try!(dealloc_block(fs, block));
+    }
+    return Err(err)
+  }
+
+  for &(i, slot_idx) in roots.iter() {
+    inode.block[i] = (first + slot_idx) as u32;
+  }
+  try!(update_inode(fs, &inode));
+  for slot in slots.iter() {
+    try!(dealloc_block(fs, slot.old_block));
+  }
+  Ok(count)
+}
+
+fn plan_slots(fs: &Filesystem, block: u64, level: usize,
+  slots: &mut Vec<Slot>) -> Result<u64>
+{
+  let slot_idx = slots.len() as u64;
+  slots.push(Slot { old_block: block, level: level, children: Vec::new() });
+  if level == 0 {
+    return Ok(slot_idx)
+  }
+
+  let block_size = fs.block_size();
+  let mut buffer = make_buffer(block_size);
+  try!(fs.volume.read(block * block_size, &mut buffer[..]));
+  let mut children = Vec::new();
+  for entry in 0..block_size / 4 {
+    let child = decode_u32(&buffer[entry as usize * 4..]) as u64;
+    if child != 0 {
+      children.push((entry, try!(plan_slots(fs, child, level - 1, slots))));
+    }
+  }
+  slots[slot_idx as usize].children = children;
+  Ok(slot_idx)
+}
+
+fn copy_slots(fs: &mut Filesystem, slots: &[Slot], first: u64) -> Result<()> {
+  let block_size = fs.block_size();
+  let mut i = 0;
+  while i < slots.len() {
+    if slots[i].level > 0 {
+      let mut buffer = make_buffer(block_size);
+      for &(entry, child_idx) in slots[i].children.iter() {
+        encode_u32((first + child_idx) as u32, &mut buffer[entry as usize * 4..]);
+      }
+      try!(fs.volume.write((first + i as u64) * block_size, &buffer[..]));
+      i += 1;
+      continue
+    }
+
+    let mut j = i + 1;
+    while j < slots.len() && (j - i) < MAX_COPY_BLOCKS as usize &&
+      slots[j].level == 0 && slots[j].old_block == slots[i].old_block + (j - i) as u64
+    {
+      j += 1;
+    }
+    let mut buffer = make_buffer((j - i) as u64 * block_size);
+    try!(fs.volume.read(slots[i].old_block * block_size, &mut buffer[..]));
+    try!(fs.volume.write((first + i as u64) * block_size, &buffer[..]));
+    i = j;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use prelude::*;
+  use super::{defrag_file};
+  use test_fs::{make_fs, make_file};
+
+  fn write_data(fs: &mut Filesystem, ino: u64, length: usize) {
+    let mut inode = get_inode(fs, ino).unwrap();
+    write_inode_data(fs, &mut inode, 0, &vec![7; length][..]).unwrap();
+    update_inode(fs, &inode).unwrap();
+  }
+
+  #[test]
+  fn defrag_rejects_free_inodes() {
+    let mut fs = make_fs(1024);
+    let ino = make_file(&mut fs, ROOT_INO, b"f").ino;
+    write_data(&mut fs, ino, 5000);
+    let open_ino = make_file(&mut fs, ROOT_INO, b"g").ino;
+    write_data(&mut fs, open_ino, 5000);
+
+    unlink_in_dir(&mut fs, &Credentials::root(), ROOT_INO, b"f").unwrap();
+    let free_blocks = statfs(&fs).free_blocks;
+    assert_eq!(defrag_file(&mut fs, ino).unwrap_err().kind(), ErrorKind::NotFound);
+    assert_eq!(defrag_file(&mut fs, ino + 2).unwrap_err().kind(), ErrorKind::NotFound);
+    assert_eq!(defrag_file(&mut fs, 1000).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(defrag_file(&mut fs, 0).unwrap_err().kind(), ErrorKind::InvalidInput);
+    assert_eq!(statfs(&fs).free_blocks, free_blocks);
+
+    // an unlinked file that is still open lives on until it is closed
+    let handle = open_file(&mut fs, &Credentials::root(), open_ino, ACCESS_READ).unwrap();
+    unlink_in_dir(&mut fs, &Credentials::root(), ROOT_INO, b"g").unwrap();
+    assert_eq!(defrag_file(&mut fs, open_ino).unwrap().after.extents, 1);
+    close_file(&mut fs, handle).unwrap();
+    assert_eq!(defrag_file(&mut fs, open_ino).unwrap_err().kind(), ErrorKind::NotFound);
+  }
+}
@@ -1,6 +1,8 @@
 #[derive(Debug, Copy, Clone)]
 pub struct Superblock {
+  pub inodes_count: u32,
   pub blocks_count: u32,
+  pub r_blocks_count: u32,
   pub free_blocks_count: u32,
   pub free_inodes_count: u32,
   pub first_data_block: u32,
@@ -9,16 +11,38 @@ pub struct Superblock {
   pub inodes_per_group: u32,
   pub state: u16,
   pub rev_level: u32,
+  pub def_resuid: u16,
+  pub def_resgid: u16,
   pub first_ino: u32,
   pub inode_size: u16,
   pub feature_compat: u32,
   pub feature_incompat: u32,
   pub feature_ro_compat: u32,
+  pub uuid: [u8; 16],
+  pub volume_name: [u8; 16],
+  pub hash_seed: [u8; 16],
+  pub last_orphan: u32,
+  pub usr_quota_inum: u32,
+  pub grp_quota_inum: u32,
 }
 
 pub const SUPERBLOCK_MAGIC: u16 = 0xef53;
-pub const SUPPORTED_INCOMPAT_FEATURES: u32 = 0x0002;
-pub const SUPPORTED_RO_COMPAT_FEATURES: u32 = 0;
+pub const SUPPORTED_INCOMPAT_FEATURES: u32 = 0x0002 | INCOMPAT_LARGE_DIR;
+pub const SUPPORTED_RO_COMPAT_FEATURES: u32 =
+  RO_COMPAT_LARGE_FILE | RO_COMPAT_HUGE_FILE | RO_COMPAT_QUOTA;
+
+pub const INCOMPAT_LARGE_DIR: u32 = 0x4000;
+
+pub const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+pub const RO_COMPAT_HUGE_FILE: u32 = 0x0008;
+pub const RO_COMPAT_QUOTA: u32 = 0x0100;
+
+pub const INODE_FLAG_HUGE_FILE: u32 = 0x40000;
+
+pub const INODE_EXTRA_ISIZE: u16 = 32;
+
+pub const RENAME_NOREPLACE: u32 = 1;
+pub const RENAME_EXCHANGE: u32 = 2;
 
 #[derive(Debug, Copy, Clone)]
 pub struct GroupDesc {
@@ -36,11 +60,37 @@ pub struct Inode {
   pub mode: Mode,
   pub attr: FileAttr,
   pub size: u64,
-  pub size_512: u32,
+  pub size_512: u64,
   pub links_count: u16,
   pub flags: u32,
   pub block: [u32; 15],
   pub file_acl: u32,
+  pub extra_isize: u16,
+}
+
+impl Inode {
+  pub fn rdev(&self) -> u32 {
+    if self.block[0] != 0 {
+      let major = (self.block[0] >> 8) & 0xff;
+      let minor = self.block[0] & 0xff;
+      (major << 8) | minor
+    } else {
+      self.block[1]
+    }
+  }
+
+  pub fn set_rdev(&mut self, rdev: u32) {
+    let major = (rdev & 0xfff00) >> 8;
+    let minor = (rdev & 0xff) | ((rdev >> 12) & 0xfff00);
+    if major < 0x100 && minor < 0x100 {
+      self.block[0] = (major << 8) | minor;
+      self.block[1] = 0;
+    } else {
+      self.block[0] = 0;
+      self.block[1] = rdev;
+      self.block[2] = 0;
+    }
+  }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -52,16 +102,25 @@ pub struct Mode {
   pub access_rights: u16,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Default)]
 pub struct FileAttr {
   pub uid: u32,
   pub gid: u32,
-  pub atime: u32,
-  pub ctime: u32,
-  pub mtime: u32,
+  pub atime: Timestamp,
+  pub ctime: Timestamp,
+  pub mtime: Timestamp,
+  pub crtime: Timestamp,
   pub dtime: u32,
 }
 
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Timestamp {
+  pub sec: i64,
+  pub nsec: u32,
+}
+
+pub const UTIME_NOW: u32 = (1 << 30) - 1;
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum FileType {
   Regular,
@@ -81,3 +140,10 @@ pub struct DirEntry {
   pub file_type: Option<FileType>,
 }
 
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AtimeMode {
+  Strict,
+  Relative,
+  Never,
+}
@@ -7,6 +7,12 @@ pub struct DirHandle {
   offset: u64,
 }
 
+impl DirHandle {
+  pub fn ino(&self) -> u64 {
+    self.ino
+  }
+}
+
 #[derive(Debug)]
 pub struct DirLine {
   pub ino: u64,
@@ -14,17 +20,24 @@ pub struct DirLine {
   pub name: Vec<u8>,
 }
 
-pub fn lookup_in_dir(fs: &mut Filesystem, dir_ino: u64, name: &[u8]) 
-  -> Result<Option<u64>> 
+pub fn lookup_in_dir(fs: &mut Filesystem, creds: &Credentials,
+  dir_ino: u64, name: &[u8]) -> Result<Option<u64>>
 {
   let dir_inode = try!(get_inode(fs, dir_ino));
   if dir_inode.mode.file_type != FileType::Dir {
-    return Err(Error::new(format!("inode {} is not a directory", dir_ino)))
+    return Err(Error::of_kind(ErrorKind::NotDir,
+      format!("inode {} is not a directory", dir_ino)))
   }
+  try!(check_access(creds, &dir_inode, ACCESS_EXEC));
+  lookup_dentry(fs, &dir_inode, name)
+}
 
+pub fn find_in_dir(fs: &Filesystem, dir_inode: &Inode, name: &[u8])
+  -> Result<Option<u64>>
+{
   let mut offset = 0;
   while offset < dir_inode.size {
-    let (entry, entry_name, next_offset) = try!(read_dir_entry(fs, &dir_inode, offset));
+    let (entry, entry_name, next_offset) = try!(read_dir_entry(fs, dir_inode, offset));
     if entry.ino != 0 && name == &entry_name[..] {
       return Ok(Some(entry.ino as u64))
     }
@@ -38,7 +51,8 @@ pub fn remove_from_dir(fs: &mut Filesystem, dir_ino: u64, name: &[u8])
 {
   let mut dir_inode = try!(get_inode(fs, dir_ino));
   if dir_inode.mode.file_type != FileType::Dir {
-    return Err(Error::new(format!("inode {} is not a directory", dir_ino)))
+    return Err(Error::of_kind(ErrorKind::NotDir,
+      format!("inode {} is not a directory", dir_ino)))
   }
 
   let mut offset = 0;
@@ -58,65 +72,314 @@ pub fn remove_from_dir(fs: &mut Filesystem, dir_ino: u64, name: &[u8])
   Ok(false)
 }
 
-pub fn move_between_dirs(fs: &mut Filesystem,
+pub fn unlink_in_dir(fs: &mut Filesystem, creds: &Credentials,
+  dir_ino: u64, name: &[u8]) -> Result<bool>
+{
+  let mut dir_inode = try!(get_inode(fs, dir_ino));
+  if dir_inode.mode.file_type != FileType::Dir {
+    return Err(Error::of_kind(ErrorKind::NotDir,
+      format!("inode {} is not a directory", dir_ino)))
+  } else if name == b"." || name == b".." {
+    return Err(Error::of_kind(ErrorKind::Busy,
+      format!("Cannot unlink '.' or '..' entries")))
+  }
+
+  let pos = match try!(find_dir_entry(fs, &dir_inode, name)) {
+    Some(pos) => pos,
+    None => return Ok(false),
+  };
+  let mut entry_inode = try!(get_inode(fs, pos.ino));
+  if entry_inode.mode.file_type == FileType::Dir {
+    return Err(Error::of_kind(ErrorKind::IsDir,
+      format!("Cannot unlink directory {}", entry_inode.ino)))
+  }
+  try!(check_access(creds, &dir_inode, ACCESS_WRITE | ACCESS_EXEC));
+  try!(check_sticky(creds, &dir_inode, &entry_inode));
+
+  try!(unlink_inode(fs, &mut entry_inode));
+  try!(erase_dir_entry(fs, &mut dir_inode, pos.offset, pos.prev_offset, pos.next_offset));
+  Ok(true)
+}
+
+pub fn rmdir_in_dir(fs: &mut Filesystem, creds: &Credentials,
+  dir_ino: u64, name: &[u8]) -> Result<bool>
+{
+  let mut dir_inode = try!(get_inode(fs, dir_ino));
+  if dir_inode.mode.file_type != FileType::Dir {
+    return Err(Error::of_kind(ErrorKind::NotDir,
+      format!("inode {} is not a directory", dir_ino)))
+  } else if name == b"." {
+    return Err(Error::of_kind(ErrorKind::InvalidInput,
+      format!("Cannot remove the '.' entry")))
+  } else if name == b".." {
+    return Err(Error::of_kind(ErrorKind::Busy,
+      format!("Cannot remove the '..' entry")))
+  }
+
+  let pos = match try!(find_dir_entry(fs, &dir_inode, name)) {
+    Some(pos) => pos,
+    None => return Ok(false),
+  };
+  let mut entry_inode = try!(get_inode(fs, pos.ino));
+  if entry_inode.mode.file_type != FileType::Dir {
+    return Err(Error::of_kind(ErrorKind::NotDir,
+      format!("inode {} is not a directory", entry_inode.ino)))
+  } else if !try!(is_dir_empty(fs, &entry_inode)) {
+    return Err(Error::of_kind(ErrorKind::NotEmpty,
+      format!("Cannot remove non-empty directory {}", entry_inode.ino)))
+  }
+  try!(check_access(creds, &dir_inode, ACCESS_WRITE | ACCESS_EXEC));
+  try!(check_sticky(creds, &dir_inode, &entry_inode));
+
+  try!(unlink_inode(fs, &mut entry_inode));
+  try!(erase_dir_entry(fs, &mut dir_inode, pos.offset, pos.prev_offset, pos.next_offset));
+  Ok(true)
+}
+
+pub fn move_between_dirs(fs: &mut Filesystem, creds: &Credentials,
   source_dir_ino: u64, source_name: &[u8],
   target_dir_ino: u64, target_name: &[u8]) -> Result<bool>
 {
-  let mut source_dir_inode = try!(get_inode(fs, source_dir_ino));
-  let mut target_dir_inode = try!(get_inode(fs, target_dir_ino));
+  rename_in_dirs(fs, creds, source_dir_ino, source_name, target_dir_ino, target_name, 0)
+}
+
+pub fn rename_in_dirs(fs: &mut Filesystem, creds: &Credentials,
+  source_dir_ino: u64, source_name: &[u8],
+  target_dir_ino: u64, target_name: &[u8], flags: u32) -> Result<bool>
+{
+  let source_dir_inode = try!(get_inode(fs, source_dir_ino));
+  let target_dir_inode = try!(get_inode(fs, target_dir_ino));
 
   if source_dir_inode.mode.file_type != FileType::Dir {
-    return Err(Error::new(format!("source inode {} is not a directory", source_dir_ino)))
+    return Err(Error::of_kind(ErrorKind::NotDir,
+      format!("source inode {} is not a directory", source_dir_ino)))
   } else if target_dir_inode.mode.file_type != FileType::Dir {
-    return Err(Error::new(format!("target inode {} is not a directory", target_dir_ino)))
+    return Err(Error::of_kind(ErrorKind::NotDir,
+      format!("target inode {} is not a directory", target_dir_ino)))
+  } else if (flags & !(RENAME_NOREPLACE | RENAME_EXCHANGE)) != 0 ||
+      (flags & RENAME_NOREPLACE != 0 && flags & RENAME_EXCHANGE != 0) {
+    return Err(Error::of_kind(ErrorKind::InvalidInput,
+      format!("Invalid rename flags 0x{:x}", flags)))
   }
 
-  let mut offset = 0;
-  let mut prev_offset = 0;
-  while offset < source_dir_inode.size {
-    let (entry, entry_name, next_offset) =
-      try!(read_dir_entry(fs, &source_dir_inode, offset));
-    if entry.ino != 0 && source_name == &entry_name[..] {
-      let mut entry_inode = try!(get_inode(fs, entry.ino as u64));
-      try!(add_dir_entry(fs, &mut target_dir_inode, &mut entry_inode, target_name));
-      try!(unlink_inode(fs, &mut entry_inode));
-      try!(erase_dir_entry(fs, &mut source_dir_inode, offset, prev_offset, next_offset));
-      return Ok(true);
+  for name in [source_name, target_name].iter() {
+    if name == b"." || name == b".." {
+      return Err(Error::of_kind(ErrorKind::Busy,
+        format!("Cannot rename '.' or '..' entries")))
     }
-    prev_offset = offset;
-    offset = next_offset;
   }
 
-  Ok(false)
+  let source_pos = match try!(find_dir_entry(fs, &source_dir_inode, source_name)) {
+    Some(pos) => pos,
+    None => return Ok(false),
+  };
+  let target_pos = try!(find_dir_entry(fs, &target_dir_inode, target_name));
+  let source_inode = try!(get_inode(fs, source_pos.ino));
+
+  try!(check_access(creds, &source_dir_inode, ACCESS_WRITE | ACCESS_EXEC));
+  try!(check_access(creds, &target_dir_inode, ACCESS_WRITE | ACCESS_EXEC));
+  try!(check_sticky(creds, &source_dir_inode, &source_inode));
+  if let Some(ref target_pos) = target_pos {
+    if target_pos.ino == source_pos.ino {
+      return Ok(true)
+    }
+    let target_inode = try!(get_inode(fs, target_pos.ino));
+    try!(check_sticky(creds, &target_dir_inode, &target_inode));
+    if flags & RENAME_EXCHANGE != 0 && target_inode.mode.file_type == FileType::Dir &&
+      source_dir_ino != target_dir_ino
+    {
+      try!(check_access(creds, &target_inode, ACCESS_WRITE));
+    }
+  }
+  if source_inode.mode.file_type == FileType::Dir && source_dir_ino != target_dir_ino {
+    try!(check_access(creds, &source_inode, ACCESS_WRITE));
+  }
+
+  if flags & RENAME_EXCHANGE != 0 {
+    match target_pos {
+      Some(target_pos) => {
+        try!(exchange_entries(fs, source_dir_ino, source_pos, target_dir_ino, target_pos));
+        return Ok(true)
+      },
+      None => return Err(Error::of_kind(ErrorKind::NotFound, format!(
+        "Cannot exchange with nonexistent entry in directory {}", target_dir_ino))),
+    }
+  }
+
+  if source_inode.mode.file_type == FileType::Dir &&
+    try!(is_dir_ancestor(fs, source_inode.ino, target_dir_ino))
+  {
+    return Err(Error::of_kind(ErrorKind::InvalidInput, format!(
+      "Cannot move directory {} into its own subtree", source_inode.ino)))
+  }
+
+  match target_pos {
+    Some(_) if flags & RENAME_NOREPLACE != 0 =>
+      return Err(Error::of_kind(ErrorKind::AlreadyExists,
+        format!("Target entry of rename already exists"))),
+    Some(target_pos) => {
+      let mut target_inode = try!(get_inode(fs, target_pos.ino));
+      let source_is_dir = source_inode.mode.file_type == FileType::Dir;
+      let target_is_dir = target_inode.mode.file_type == FileType::Dir;
+      if source_is_dir && !target_is_dir {
+        return Err(Error::of_kind(ErrorKind::NotDir, format!(
+          "Cannot replace non-directory {} with directory {}",
+          target_inode.ino, source_inode.ino)))
+      } else if !source_is_dir && target_is_dir {
+        return Err(Error::of_kind(ErrorKind::IsDir, format!(
+          "Cannot replace directory {} with non-directory {}",
+          target_inode.ino, source_inode.ino)))
+      } else if target_is_dir && !try!(is_dir_empty(fs, &target_inode)) {
+        return Err(Error::of_kind(ErrorKind::NotEmpty, format!(
+          "Cannot replace non-empty directory {}", target_inode.ino)))
+      }
+
+      let mut target_dir_inode = try!(get_inode(fs, target_dir_ino));
+      try!(replace_dir_entry(fs, &mut target_dir_inode, &target_pos, &source_inode));
+      try!(unlink_inode(fs, &mut target_inode));
+    },
+    None => {
+      let mut target_dir_inode = try!(get_inode(fs, target_dir_ino));
+      let mut source_inode = source_inode;
+      try!(with_reserved_access(fs, creds, |fs|
+        add_dir_entry(fs, &mut target_dir_inode, &mut source_inode, target_name)));
+      source_inode.links_count -= 1;
+      try!(update_inode(fs, &source_inode));
+    },
+  }
+
+  let mut source_dir_inode = try!(get_inode(fs, source_dir_ino));
+  let source_pos = match try!(find_dir_entry(fs, &source_dir_inode, source_name)) {
+    Some(pos) => pos,
+    None => return Err(Error::new(format!(
+      "Source entry disappeared from directory {} during rename", source_dir_ino))),
+  };
+  try!(erase_dir_entry(fs, &mut source_dir_inode,
+    source_pos.offset, source_pos.prev_offset, source_pos.next_offset));
+
+  if source_inode.mode.file_type == FileType::Dir && source_dir_ino != target_dir_ino {
+    try!(reparent_dir(fs, source_inode.ino, source_dir_ino, target_dir_ino));
+  }
+
+  let mut source_inode = try!(get_inode(fs, source_inode.ino));
+  touch_changed(fs, &mut source_inode);
+  try!(update_inode(fs, &source_inode));
+  Ok(true)
+}
+
+fn exchange_entries(fs: &mut Filesystem,
+  source_dir_ino: u64, source_pos: EntryPos,
+  target_dir_ino: u64, target_pos: EntryPos) -> Result<()>
+{
+  let source_inode = try!(get_inode(fs, source_pos.ino));
+  let target_inode = try!(get_inode(fs, target_pos.ino));
+  let source_is_dir = source_inode.mode.file_type == FileType::Dir;
+  let target_is_dir = target_inode.mode.file_type == FileType::Dir;
+
+  if source_is_dir && try!(is_dir_ancestor(fs, source_inode.ino, target_dir_ino)) {
+    return Err(Error::of_kind(ErrorKind::InvalidInput, format!(
+      "Cannot move directory {} into its own subtree", source_inode.ino)))
+  } else if target_is_dir && try!(is_dir_ancestor(fs, target_inode.ino, source_dir_ino)) {
+    return Err(Error::of_kind(ErrorKind::InvalidInput, format!(
+      "Cannot move directory {} into its own subtree", target_inode.ino)))
+  }
+
+  let mut source_dir_inode = try!(get_inode(fs, source_dir_ino));
+  try!(replace_dir_entry(fs, &mut source_dir_inode, &source_pos, &target_inode));
+  let mut target_dir_inode = try!(get_inode(fs, target_dir_ino));
+  try!(replace_dir_entry(fs, &mut target_dir_inode, &target_pos, &source_inode));
+
+  if source_dir_ino != target_dir_ino {
+    if source_is_dir {
+      try!(reparent_dir(fs, source_inode.ino, source_dir_ino, target_dir_ino));
+    }
+    if target_is_dir {
+      try!(reparent_dir(fs, target_inode.ino, target_dir_ino, source_dir_ino));
+    }
+  }
+
+  for &ino in [source_inode.ino, target_inode.ino].iter() {
+    let mut inode = try!(get_inode(fs, ino));
+    touch_changed(fs, &mut inode);
+    try!(update_inode(fs, &inode));
+  }
+  Ok(())
+}
+
+fn reparent_dir(fs: &mut Filesystem, dir_ino: u64,
+  old_parent_ino: u64, new_parent_ino: u64) -> Result<()>
+{
+  let mut dir_inode = try!(get_inode(fs, dir_ino));
+  let dot_dot_pos = match try!(find_dir_entry(fs, &dir_inode, b"..")) {
+    Some(pos) => pos,
+    None => return Err(Error::new(format!(
+      "Directory {} has no '..' entry", dir_ino))),
+  };
+  let new_parent_inode = try!(get_inode(fs, new_parent_ino));
+  try!(replace_dir_entry(fs, &mut dir_inode, &dot_dot_pos, &new_parent_inode));
+
+  let mut old_parent_inode = try!(get_inode(fs, old_parent_ino));
+  old_parent_inode.links_count -= 1;
+  touch_changed(fs, &mut old_parent_inode);
+  try!(update_inode(fs, &old_parent_inode));
+
+  let mut new_parent_inode = try!(get_inode(fs, new_parent_ino));
+  new_parent_inode.links_count += 1;
+  touch_changed(fs, &mut new_parent_inode);
+  update_inode(fs, &new_parent_inode)
+}
+
+fn is_dir_ancestor(fs: &mut Filesystem, ancestor_ino: u64, dir_ino: u64) -> Result<bool> {
+  let mut ino = dir_ino;
+  loop {
+    if ino == ancestor_ino {
+      return Ok(true)
+    } else if ino == ROOT_INO {
+      return Ok(false)
+    }
+
+    let dir_inode = try!(get_inode(fs, ino));
+    ino = match try!(find_dir_entry(fs, &dir_inode, b"..")) {
+      Some(pos) if pos.ino != ino => pos.ino,
+      _ => return Err(Error::new(format!(
+        "Directory {} has an invalid '..' entry", ino))),
+    };
+  }
 }
 
-pub fn open_dir(fs: &mut Filesystem, ino: u64) -> Result<DirHandle> {
+pub fn open_dir(fs: &mut Filesystem, creds: &Credentials, ino: u64) -> Result<DirHandle> {
   let inode = try!(get_inode(fs, ino));
   if inode.mode.file_type == FileType::Dir {
+    try!(check_access(creds, &inode, ACCESS_READ));
     Ok(DirHandle { ino: ino, offset: 0 })
   } else {
-    return Err(Error::new(format!("inode is not a directory")))
+    return Err(Error::of_kind(ErrorKind::NotDir, format!("inode is not a directory")))
   }
 }
 
-pub fn read_dir(fs: &mut Filesystem, mut handle: DirHandle) 
+pub fn read_dir(fs: &mut Filesystem, handle: DirHandle) 
   -> Result<Option<(DirHandle, DirLine)>> 
 {
   let inode = try!(get_inode(fs, handle.ino));
   if handle.offset >= inode.size {
     return Ok(None)
   }
+  try!(touch_accessed(fs, handle.ino));
+  next_dir_line(fs, &inode, handle)
+}
 
-  loop {
-    let (entry, name, next_offset) = try!(read_dir_entry(fs, &inode, handle.offset));
-    let file_type = match entry.file_type {
-      Some(file_type) => file_type,
-      None => try!(get_inode(fs, entry.ino as u64)).mode.file_type,
-    };
-
+pub fn next_dir_line(fs: &Filesystem, inode: &Inode, mut handle: DirHandle)
+  -> Result<Option<(DirHandle, DirLine)>>
+{
+  while handle.offset < inode.size {
+    let (entry, name, next_offset) = try!(read_dir_entry(fs, inode, handle.offset));
     handle.offset = next_offset;
 
     if entry.ino != 0 {
+      let file_type = match entry.file_type {
+        Some(file_type) => file_type,
+        None => try!(peek_inode(fs, entry.ino as u64)).mode.file_type,
+      };
       return Ok(Some((handle, DirLine {
         ino: entry.ino as u64,
         file_type: file_type,
@@ -124,12 +387,56 @@ pub fn read_dir(fs: &mut Filesystem, mut handle: DirHandle)
       })))
     }
   }
+  Ok(None)
 }
 
 pub fn close_dir(_fs: &mut Filesystem, _handle: DirHandle) -> Result<()> {
   Ok(())
 }
 
+#[derive(Debug, Copy, Clone)]
+struct EntryPos {
+  ino: u64,
+  offset: u64,
+  prev_offset: u64,
+  next_offset: u64,
+}
+
+fn find_dir_entry(fs: &mut Filesystem, dir_inode: &Inode,
+  name: &[u8]) -> Result<Option<EntryPos>>
+{
+  let mut offset = 0;
+  let mut prev_offset = 0;
+  while offset < dir_inode.size {
+    let (entry, entry_name, next_offset) = try!(read_dir_entry(fs, dir_inode, offset));
+    if entry.ino != 0 && name == &entry_name[..] {
+      return Ok(Some(EntryPos {
+        ino: entry.ino as u64,
+        offset: offset,
+        prev_offset: prev_offset,
+        next_offset: next_offset,
+      }))
+    }
+    prev_offset = offset;
+    offset = next_offset;
+  }
+  Ok(None)
+}
+
+fn replace_dir_entry(fs: &mut Filesystem, dir_inode: &mut Inode,
+  pos: &EntryPos, new_inode: &Inode) -> Result<()>
+{
+  let (entry, name, _) = try!(read_dir_entry(fs, dir_inode, pos.offset));
+  forget_dentry(fs, dir_inode.ino, &name[..]);
+  let new_entry = DirEntry {
+    ino: new_inode.ino as u32,
+    file_type: entry.file_type.map(|_| new_inode.mode.file_type),
+    .. entry
+  };
+  try!(write_dir_entry(fs, dir_inode, pos.offset, &new_entry, None));
+  touch_dir_modified(fs, dir_inode)
+}
+
 #[derive(Debug)]
 struct FreeSpace {
   offset: u64,
@@ -141,6 +448,7 @@ pub fn add_dir_entry(fs: &mut Filesystem, dir_inode: &mut Inode,
   entry_inode: &mut Inode, name: &[u8]) -> Result<()>
 {
   assert_eq!(dir_inode.mode.file_type, FileType::Dir);
+  forget_dentry(fs, dir_inode.ino, name);
   let entry_size = dir_entry_size(name.len() as u64);
 
   let mut place_for_entry = None;
@@ -162,10 +470,12 @@ pub fn add_dir_entry(fs: &mut Filesystem, dir_inode: &mut Inode,
       };
       try!(write_dir_entry(fs, dir_inode, offset, &new_entry, None));
       entry_inode.links_count += 1;
+      touch_changed(fs, entry_inode);
+      try!(update_inode(fs, entry_inode));
 
       let mut old_inode = try!(get_inode(fs, entry.ino as u64));
       try!(unlink_inode(fs, &mut old_inode));
-      return Ok(());
+      return touch_dir_modified(fs, dir_inode);
     }
 
     let free_offset =
@@ -188,7 +498,9 @@ pub fn add_dir_entry(fs: &mut Filesystem, dir_inode: &mut Inode,
     offset = next_offset;
   }
 
-  insert_dir_entry(fs, dir_inode, entry_inode, name, place_for_entry, last_offset)
+  try!(insert_dir_entry(fs, dir_inode, entry_inode, name,
+    place_for_entry, last_offset));
+  touch_dir_modified(fs, dir_inode)
 }
 
 pub fn is_dir_empty(fs: &mut Filesystem, dir_inode: &Inode) -> Result<bool> {
@@ -240,12 +552,14 @@ pub fn init_dir(fs: &mut Filesystem, parent_inode: &mut Inode,
   try!(update_inode(fs, dir_inode));
 
   let (group_idx, _) = get_ino_group(fs, dir_inode.ino);
-  fs.groups[group_idx as usize].desc.used_dirs_count += 1;
-  fs.groups[group_idx as usize].dirty = true;
+  let group = group_mut(fs, group_idx);
+  group.desc.used_dirs_count += 1;
+  mark_group_dirty(fs, group_idx);
   Ok(())
 }
 
 pub fn deinit_dir(fs: &mut Filesystem, dir_inode: &mut Inode) -> Result<()> {
+  forget_dir_dentries(fs, dir_inode.ino);
   let mut dot_ino = None;
   let mut dot_dot_ino = None;
 
@@ -294,12 +608,143 @@ pub fn deinit_dir(fs: &mut Filesystem, dir_inode: &mut Inode) -> Result<()> {
   }
 
   let (group_idx, _) = get_ino_group(fs, dir_inode.ino);
-  fs.groups[group_idx as usize].desc.used_dirs_count -= 1;
-  fs.groups[group_idx as usize].dirty = true;
+  let group = group_mut(fs, group_idx);
+  group.desc.used_dirs_count -= 1;
+  mark_group_dirty(fs, group_idx);
 
   Ok(())
 }
 
+pub fn sort_dir(fs: &mut Filesystem, dir_ino: u64) -> Result<()> {
+  let mut dir_inode = try!(get_inode(fs, dir_ino));
+  if dir_inode.mode.file_type != FileType::Dir {
+    return Err(Error::of_kind(ErrorKind::NotDir,
+      format!("inode {} is not a directory", dir_ino)))
+  }
+  try!(repack_dir(fs, &mut dir_inode, true));
+  Ok(())
+}
+
+pub fn compact_dir(fs: &mut Filesystem, dir_ino: u64) -> Result<()> {
+  let mut dir_inode = try!(get_inode(fs, dir_ino));
+  if dir_inode.mode.file_type != FileType::Dir {
+    return Err(Error::of_kind(ErrorKind::NotDir,
+      format!("inode {} is not a directory", dir_ino)))
+  }
+  let used_blocks = try!(repack_dir(fs, &mut dir_inode, false));
+  try!(shrink_dir(fs, &mut dir_inode, used_blocks));
+  touch_dir_modified(fs, &mut dir_inode)
+}
+
+pub fn is_dir_sorted(fs: &Filesystem, dir_inode: &Inode) -> Result<bool> {
+  let mut last_name: Option<Vec<u8>> = None;
+  let mut offset = 0;
+  while offset < dir_inode.size {
+    let (entry, entry_name, next_offset) = try!(read_dir_entry(fs, dir_inode, offset));
+    if entry.ino != 0 && &entry_name[..] != b"." && &entry_name[..] != b".." {
+      if last_name.as_ref().map_or(false, |last_name| *last_name > entry_name) {
+        return Ok(false)
+      }
+      last_name = Some(entry_name);
+    }
+    offset = next_offset;
+  }
+  Ok(true)
+}
+
+fn shrink_dir(fs: &mut Filesystem, dir_inode: &mut Inode, used_blocks: u64) -> Result<()> {
+  let block_size = fs.block_size();
+  if dir_inode.size <= used_blocks * block_size {
+    return Ok(())
+  }
+  try!(truncate_inode_blocks(fs, dir_inode, used_blocks));
+  dir_inode.size = used_blocks * block_size;
+  update_inode(fs, dir_inode)
+}
+
+fn shrink_empty_tail(fs: &mut Filesystem, dir_inode: &mut Inode) -> Result<()> {
+  *dir_inode = try!(get_inode(fs, dir_inode.ino));
+  let block_size = fs.block_size();
+  let mut used_blocks = dir_inode.size / block_size;
+  while used_blocks > 1 {
+    let (entry, _, _) = try!(read_dir_entry(fs, dir_inode, (used_blocks - 1) * block_size));
+    if entry.ino != 0 || entry.rec_len as u64 != block_size {
+      break
+    }
+    used_blocks -= 1;
+  }
+  shrink_dir(fs, dir_inode, used_blocks)
+}
+
+fn repack_dir(fs: &mut Filesystem, dir_inode: &mut Inode, sort: bool) -> Result<u64> {
+  let block_size = fs.block_size();
+  let mut dot_entries = Vec::new();
+  let mut entries = Vec::new();
+
+  let mut offset = 0;
+  while offset < dir_inode.size {
+    let (entry, entry_name, next_offset) = try!(read_dir_entry(fs, dir_inode, offset));
+    if entry.ino != 0 {
+      if &entry_name[..] == b"." || &entry_name[..] == b".." {
+        dot_entries.push((entry, entry_name));
+      } else {
+        entries.push((entry, entry_name));
+      }
+    }
+    offset = next_offset;
+  }
+
+  if sort {
+    entries.sort_by(|a, b| a.1.cmp(&b.1));
+  }
+  dot_entries.sort_by(|a, b| a.1.len().cmp(&b.1.len()));
+
+  let mut buffer = Vec::new();
+  let mut offset = 0;
+  let mut last_offset = 0;
+  for &(entry, ref entry_name) in dot_entries.iter().chain(entries.iter()) {
+    let entry_size = align_4(dir_entry_size(entry_name.len() as u64));
+    if offset % block_size != 0 && space_in_block(fs, offset) < entry_size {
+      let rec_len = (offset / block_size + 1) * block_size - last_offset;
+      encode_u16(rec_len as u16, &mut buffer[last_offset as usize + 4..]);
+      offset = (offset / block_size + 1) * block_size;
+    }
+    if offset % block_size == 0 {
+      buffer.extend(make_buffer(block_size));
+    }
+
+    let new_entry = DirEntry { rec_len: entry_size as u16, .. entry };
+    try!(encode_dir_entry(&fs.superblock, &new_entry, &mut buffer[offset as usize..]));
+    let name_offset = (offset + dir_entry_size(0)) as usize;
+    buffer[name_offset..name_offset + entry_name.len()].copy_from_slice(&entry_name[..]);
+    last_offset = offset;
+    offset = offset + entry_size;
+  }
+
+  if buffer.is_empty() {
+    return Err(Error::new(format!(
+      "Directory {} has no '.' and '..' entries", dir_inode.ino)));
+  }
+  let rec_len = buffer.len() as u64 - last_offset;
+  encode_u16(rec_len as u16, &mut buffer[last_offset as usize + 4..]);
+
+  let used_size = buffer.len() as u64;
+  let empty_entry = DirEntry {
+    ino: 0,
+    rec_len: block_size as u16,
+    name_len: 0,
+    file_type: None,
+  };
+  while (buffer.len() as u64) < dir_inode.size {
+    let empty_offset = buffer.len();
+    buffer.extend(make_buffer(block_size));
+    try!(encode_dir_entry(&fs.superblock, &empty_entry, &mut buffer[empty_offset..]));
+  }
+
+  try!(write_inode_data(fs, dir_inode, 0, &buffer[..]));
+  Ok(used_size / block_size)
+}
+
 fn insert_dir_entry(fs: &mut Filesystem, dir_inode: &mut Inode,
   entry_inode: &mut Inode, name: &[u8],
   place_for_entry: Option<FreeSpace>, last_offset: u64) -> Result<()>
@@ -321,16 +766,27 @@ fn insert_dir_entry(fs: &mut Filesystem, dir_inode: &mut Inode,
     file_type: Some(entry_inode.mode.file_type),
   };
   try!(write_dir_entry(fs, dir_inode, free_space.offset, &new_entry, Some(name)));
+  if fs.options.reproducible.is_some() {
+    fs.unsorted_dirs.insert(dir_inode.ino);
+  }
   try!(write_dir_entry_rec_len(fs, dir_inode, free_space.prev_offset,
     (free_space.offset - free_space.prev_offset) as u16));
+  if dir_inode.size < free_space.next_offset {
+    dir_inode.size = free_space.next_offset;
+    try!(update_inode(fs, dir_inode));
+  }
 
   entry_inode.links_count += 1;
+  touch_changed(fs, entry_inode);
   update_inode(fs, entry_inode)
 }
 
 fn erase_dir_entry(fs: &mut Filesystem, dir_inode: &mut Inode,
   offset: u64, prev_offset: u64, next_offset: u64) -> Result<()>
 {
+  let (_, name, _) = try!(read_dir_entry(fs, dir_inode, offset));
+  forget_dentry(fs, dir_inode.ino, &name[..]);
+
   let new_entry = DirEntry {
     ino: 0,
     rec_len: (next_offset - offset) as u16,
@@ -343,11 +799,19 @@ fn erase_dir_entry(fs: &mut Filesystem, dir_inode: &mut Inode,
   if offset % fs.block_size() != 0 {
     try!(write_dir_entry_rec_len(fs, dir_inode, prev_offset,
       (next_offset - prev_offset) as u16));
+  } else if next_offset - offset == fs.block_size() {
+    try!(shrink_empty_tail(fs, dir_inode));
   }
-  Ok(())
+  touch_dir_modified(fs, dir_inode)
 }
 
-fn read_dir_entry(fs: &mut Filesystem, inode: &Inode, offset: u64) 
+fn touch_dir_modified(fs: &mut Filesystem, dir_inode: &mut Inode) -> Result<()> {
+  *dir_inode = try!(get_inode(fs, dir_inode.ino));
+  touch_modified(fs, dir_inode);
+  update_inode(fs, dir_inode)
+}
+
+fn read_dir_entry(fs: &Filesystem, inode: &Inode, offset: u64) 
   -> Result<(DirEntry, Vec<u8>, u64)>
 {
   let mut entry_buffer = make_buffer(dir_entry_size(0));
@@ -403,3 +867,172 @@ fn space_in_block(fs: &mut Filesystem, offset: u64) -> u64 {
 fn align_4(x: u64) -> u64 {
   (x + 0b11) & !0b11
 }
+
+#[cfg(test)]
+mod test {
+  use prelude::*;
+  use test_fs::{make_volume, mount, make_file, make_dir};
+
+  fn lookup(fs: &mut Filesystem, dir_ino: u64, name: &[u8]) -> Option<u64> {
+    lookup_in_dir(fs, &Credentials::root(), dir_ino, name).unwrap()
+  }
+
+  fn links(fs: &mut Filesystem, ino: u64) -> u16 {
+    get_inode(fs, ino).unwrap().links_count
+  }
+
+  fn is_ino_used(fs: &Filesystem, ino: u64) -> bool {
+    let (group_idx, local_idx) = get_ino_group(fs, ino);
+    get_bit_in_bitmap(&lock_group(fs, group_idx).inode_bitmap[..], local_idx)
+  }
+
+  fn rename(fs: &mut Filesystem, source_dir_ino: u64, source_name: &[u8],
+    target_dir_ino: u64, target_name: &[u8], flags: u32) -> Result<bool>
+  {
+    rename_in_dirs(fs, &Credentials::root(), source_dir_ino, source_name,
+      target_dir_ino, target_name, flags)
+  }
+
+  #[test]
+  fn rename_non_empty_dir() {
+    let volume = make_volume(1024, 2, 128);
+    let mut fs = mount(&volume);
+    let a_ino = make_dir(&mut fs, ROOT_INO, b"a").ino;
+    let b_ino = make_dir(&mut fs, ROOT_INO, b"b").ino;
+    let f_ino = make_file(&mut fs, a_ino, b"f").ino;
+    assert_eq!(links(&mut fs, ROOT_INO), 4);
+
+    assert!(rename(&mut fs, ROOT_INO, b"a", b_ino, b"c", 0).unwrap());
+    flush_fs(&mut fs).unwrap();
+
+    let mut fs = mount(&volume);
+    assert_eq!(lookup(&mut fs, ROOT_INO, b"a"), None);
+    assert_eq!(lookup(&mut fs, b_ino, b"c"), Some(a_ino));
+    assert_eq!(lookup(&mut fs, a_ino, b"f"), Some(f_ino));
+    assert_eq!(lookup(&mut fs, a_ino, b"."), Some(a_ino));
+    assert_eq!(lookup(&mut fs, a_ino, b".."), Some(b_ino));
+    assert_eq!(links(&mut fs, ROOT_INO), 3);
+    assert_eq!(links(&mut fs, b_ino), 3);
+    assert_eq!(links(&mut fs, a_ino), 2);
+  }
+
+  #[test]
+  fn rename_into_own_subtree() {
+    let mut fs = mount(&make_volume(1024, 1, 128));
+    let a_ino = make_dir(&mut fs, ROOT_INO, b"a").ino;
+    let c_ino = make_dir(&mut fs, a_ino, b"c").ino;
+    for &(dir_ino, flags) in [(a_ino, 0), (c_ino, 0), (c_ino, RENAME_NOREPLACE)].iter() {
+      let err = rename(&mut fs, ROOT_INO, b"a", dir_ino, b"x", flags).unwrap_err();
+      assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+    assert_eq!(lookup(&mut fs, ROOT_INO, b"a"), Some(a_ino));
+    assert_eq!(lookup(&mut fs, c_ino, b".."), Some(a_ino));
+  }
+
+  #[test]
+  fn rename_over_dir() {
+    let volume = make_volume(1024, 1, 128);
+    let mut fs = mount(&volume);
+    let a_ino = make_dir(&mut fs, ROOT_INO, b"a").ino;
+    let b_ino = make_dir(&mut fs, ROOT_INO, b"b").ino;
+    make_file(&mut fs, a_ino, b"f");
+    let err = rename(&mut fs, ROOT_INO, b"b", ROOT_INO, b"a", 0).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotEmpty);
+    let err = rename(&mut fs, ROOT_INO, b"a", ROOT_INO, b"b", RENAME_NOREPLACE).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+
+    assert!(rename(&mut fs, ROOT_INO, b"a", ROOT_INO, b"b", 0).unwrap());
+    flush_fs(&mut fs).unwrap();
+
+    let mut fs = mount(&volume);
+    assert_eq!(lookup(&mut fs, ROOT_INO, b"a"), None);
+    assert_eq!(lookup(&mut fs, ROOT_INO, b"b"), Some(a_ino));
+    assert!(!is_ino_used(&fs, b_ino));
+    assert_eq!(links(&mut fs, ROOT_INO), 3);
+  }
+
+  #[test]
+  fn rename_exchange() {
+    let volume = make_volume(1024, 2, 128);
+    let mut fs = mount(&volume);
+    let a_ino = make_dir(&mut fs, ROOT_INO, b"a").ino;
+    let b_ino = make_dir(&mut fs, ROOT_INO, b"b").ino;
+    let f_ino = make_file(&mut fs, b_ino, b"f").ino;
+    let err = rename(&mut fs, ROOT_INO, b"a", b_ino, b"g", RENAME_EXCHANGE).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+
+    assert!(rename(&mut fs, ROOT_INO, b"a", b_ino, b"f", RENAME_EXCHANGE).unwrap());
+    flush_fs(&mut fs).unwrap();
+
+    let mut fs = mount(&volume);
+    assert_eq!(lookup(&mut fs, ROOT_INO, b"a"), Some(f_ino));
+    assert_eq!(lookup(&mut fs, b_ino, b"f"), Some(a_ino));
+    assert_eq!(lookup(&mut fs, a_ino, b".."), Some(b_ino));
+    assert_eq!(links(&mut fs, ROOT_INO), 3);
+    assert_eq!(links(&mut fs, b_ino), 3);
+    assert_eq!(links(&mut fs, f_ino), 1);
+  }
+
+  fn names(fs: &mut Filesystem, dir_ino: u64) -> Vec<Vec<u8>> {
+    let mut names = Vec::new();
+    let mut handle = open_dir(fs, &Credentials::root(), dir_ino).unwrap();
+    while let Some((next_handle, line)) = read_dir(fs, handle).unwrap() {
+      names.push(line.name);
+      handle = next_handle;
+    }
+    names.sort();
+    names
+  }
+
+  #[test]
+  fn compact_and_shrink_dir() {
+    let volume = make_volume(1024, 1, 128);
+    let mut fs = mount(&volume);
+    let dir_ino = make_dir(&mut fs, ROOT_INO, b"d").ino;
+    let name = |i: usize| format!("entry-with-a-long-name-{:03}", i).into_bytes();
+    let file_ino = make_file(&mut fs, dir_ino, &name(0)[..]).ino;
+    for i in 1..100 {
+      make_hardlink_in_dir(&mut fs, &Credentials::root(), dir_ino, &name(i)[..], file_ino).unwrap();
+    }
+    let full_size = get_inode(&mut fs, dir_ino).unwrap().size;
+    assert!(full_size >= 4 * 1024);
+
+    for i in 0..100 {
+      if i % 10 != 0 {
+        assert!(unlink_in_dir(&mut fs, &Credentials::root(), dir_ino, &name(i)[..]).unwrap());
+      }
+    }
+    let free_blocks = statfs(&fs).free_blocks;
+    compact_dir(&mut fs, dir_ino).unwrap();
+    flush_fs(&mut fs).unwrap();
+
+    let mut fs = mount(&volume);
+    let dir_inode = get_inode(&mut fs, dir_ino).unwrap();
+    assert_eq!(dir_inode.size, 1024);
+    assert_eq!(statfs(&fs).free_blocks, free_blocks + full_size / 1024 - 1);
+    let mut expected: Vec<Vec<u8>> = (0..100).filter(|i| i % 10 == 0).map(|i| name(i)).collect();
+    expected.push(b".".to_vec());
+    expected.push(b"..".to_vec());
+    expected.sort();
+    assert_eq!(names(&mut fs, dir_ino), expected);
+    assert_eq!(lookup(&mut fs, dir_ino, &name(50)[..]), Some(file_ino));
+    assert_eq!(links(&mut fs, file_ino), 10);
+  }
+
+  #[test]
+  fn drop_empty_tail_blocks() {
+    let mut fs = mount(&make_volume(1024, 1, 128));
+    let dir_ino = make_dir(&mut fs, ROOT_INO, b"d").ino;
+    let name = |i: usize| format!("entry-with-a-long-name-{:03}", i).into_bytes();
+    let file_ino = make_file(&mut fs, dir_ino, &name(0)[..]).ino;
+    for i in 1..60 {
+      make_hardlink_in_dir(&mut fs, &Credentials::root(), dir_ino, &name(i)[..], file_ino).unwrap();
+    }
+    assert!(get_inode(&mut fs, dir_ino).unwrap().size > 1024);
+    for i in (1..60).rev() {
+      assert!(unlink_in_dir(&mut fs, &Credentials::root(), dir_ino, &name(i)[..]).unwrap());
+    }
+    assert_eq!(get_inode(&mut fs, dir_ino).unwrap().size, 1024);
+    assert_eq!(lookup(&mut fs, dir_ino, &name(0)[..]), Some(file_ino));
+  }
+}
@@ -1,7 +1,10 @@
+use std::{cmp};
 use prelude::*;
 
 pub fn encode_superblock(superblock: &Superblock, bytes: &mut [u8]) -> Result<()> {
+  encode_u32(superblock.inodes_count, &mut bytes[0..]);
   encode_u32(superblock.blocks_count, &mut bytes[4..]);
+  encode_u32(superblock.r_blocks_count, &mut bytes[8..]);
   encode_u32(superblock.free_blocks_count, &mut bytes[12..]);
   encode_u32(superblock.free_inodes_count, &mut bytes[16..]);
   encode_u32(superblock.first_data_block, &mut bytes[20..]);
@@ -11,6 +14,8 @@ pub fn encode_superblock(superblock: &Superblock, bytes: &mut [u8]) -> Result<()
   encode_u16(SUPERBLOCK_MAGIC, &mut bytes[56..]);
   encode_u16(superblock.state, &mut bytes[58..]);
   encode_u32(superblock.rev_level, &mut bytes[76..]);
+  encode_u16(superblock.def_resuid, &mut bytes[80..]);
+  encode_u16(superblock.def_resgid, &mut bytes[82..]);
 
   if superblock.rev_level >= 1 {
     encode_u32(superblock.first_ino, &mut bytes[84..]);
@@ -18,6 +23,12 @@ pub fn encode_superblock(superblock: &Superblock, bytes: &mut [u8]) -> Result<()
     encode_u32(superblock.feature_compat, &mut bytes[92..]);
     encode_u32(superblock.feature_incompat, &mut bytes[96..]);
     encode_u32(superblock.feature_ro_compat, &mut bytes[100..]);
+    bytes[104..120].copy_from_slice(&superblock.uuid[..]);
+    bytes[120..136].copy_from_slice(&superblock.volume_name[..]);
+    encode_u32(superblock.last_orphan, &mut bytes[232..]);
+    bytes[236..252].copy_from_slice(&superblock.hash_seed[..]);
+    encode_u32(superblock.usr_quota_inum, &mut bytes[576..]);
+    encode_u32(superblock.grp_quota_inum, &mut bytes[580..]);
   }
 
   Ok(())
@@ -47,29 +58,76 @@ pub fn encode_inode(superblock: &Superblock, inode: &Inode,
   encode_u16(((inode.attr.gid >> 16) & 0xffff) as u16, &mut bytes[122..]);
 
   encode_u32((inode.size & 0xffffffff) as u32, &mut bytes[4..]);
-  if (inode.size >> 32) != 0 && superblock.rev_level < 1 {
-    return Err(Error::new(
-      format!("Cannot encode file size exceeding 32 bits in rev {}",
-              superblock.rev_level)));
-  } else {
+  if inode_has_size_high(superblock, inode.mode.file_type) {
     encode_u32(((inode.size >> 32) & 0xffffffff) as u32, &mut bytes[108..]);
+  } else if (inode.size >> 32) != 0 {
+    return Err(Error::new(
+      format!("Cannot encode size {} of inode {} of type {:?} in rev {}",
+              inode.size, inode.ino, inode.mode.file_type, superblock.rev_level)));
   }
 
+  let blocks =
+    if (inode.flags & INODE_FLAG_HUGE_FILE) != 0 {
+      inode.size_512 >> (superblock.log_block_size + 1)
+    } else {
+      inode.size_512
+    };
+  if (superblock.feature_ro_compat & RO_COMPAT_HUGE_FILE) != 0 {
+    if (blocks >> 48) != 0 {
+      return Err(Error::new(
+        format!("Cannot encode block count {} of inode {} exceeding 48 bits",
+                blocks, inode.ino)));
+    }
+    encode_u16(((blocks >> 32) & 0xffff) as u16, &mut bytes[116..]);
+  } else if (blocks >> 32) != 0 {
+    return Err(Error::new(
+      format!("Cannot encode block count {} of inode {} exceeding 32 bits \
+              without the huge_file feature", blocks, inode.ino)));
+  }
+  encode_u32((blocks & 0xffffffff) as u32, &mut bytes[28..]);
+
   for i in 0..15 {
     encode_u32(inode.block[i], &mut bytes[40 + 4*i..]);
   }
 
-  encode_u32(inode.attr.atime, &mut bytes[8..]);
-  encode_u32(inode.attr.ctime, &mut bytes[12..]);
-  encode_u32(inode.attr.mtime, &mut bytes[16..]);
+  if bytes.len() >= 130 {
+    encode_u16(inode.extra_isize, &mut bytes[128..]);
+  }
+  {
+    let extra_end = cmp::min(128 + inode.extra_isize as usize, bytes.len());
+    let mut encode_time = |time: &Timestamp, base_offset: usize, extra_offset: usize| {
+      let has_extra = extra_offset + 4 <= extra_end;
+      let (base, extra) = encode_timestamp(time, has_extra);
+      encode_u32(base, &mut bytes[base_offset..]);
+      if has_extra {
+        encode_u32(extra, &mut bytes[extra_offset..]);
+      }
+    };
+    encode_time(&inode.attr.atime, 8, 140);
+    encode_time(&inode.attr.ctime, 12, 132);
+    encode_time(&inode.attr.mtime, 16, 136);
+    if 144 + 4 <= extra_end {
+      encode_time(&inode.attr.crtime, 144, 148);
+    }
+  }
   encode_u32(inode.attr.dtime, &mut bytes[20..]);
   encode_u16(inode.links_count, &mut bytes[26..]);
-  encode_u32(inode.size_512, &mut bytes[28..]);
   encode_u32(inode.flags, &mut bytes[32..]);
   encode_u32(inode.file_acl, &mut bytes[104..]);
   Ok(())
 }
 
+pub fn encode_timestamp(time: &Timestamp, has_extra: bool) -> (u32, u32) {
+  if has_extra {
+    let sec = cmp::max(cmp::min(time.sec, 0x37fffffff), -0x80000000);
+    let epoch = ((sec - sec as i32 as i64) >> 32) as u32 & 0b11;
+    (sec as u32, epoch | (time.nsec << 2))
+  } else {
+    let sec = cmp::max(cmp::min(time.sec, 0x7fffffff), -0x80000000);
+    (sec as u32, 0)
+  }
+}
+
 fn encode_inode_mode(mode: &Mode) -> u16 {
   encode_mode_file_type(mode.file_type) +
     if mode.suid { 0x0800 } else { 0 } +
@@ -120,6 +178,11 @@ pub fn encode_u32(value: u32, bytes: &mut [u8]) {
   bytes[3] = ((value >> 24) & 0xff) as u8;
 }
 
+pub fn encode_u64(value: u64, bytes: &mut [u8]) {
+  encode_u32((value & 0xffffffff) as u32, &mut bytes[0..]);
+  encode_u32((value >> 32) as u32, &mut bytes[4..]);
+}
+
 pub fn encode_u16(value: u16, bytes: &mut [u8]) {
   bytes[0] = (value & 0xff) as u8;
   bytes[1] = ((value >> 8) & 0xff) as u8;
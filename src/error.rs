@@ -2,15 +2,42 @@ use std::{convert, error, fmt, io, result};
 
 #[derive(Debug)]
 pub struct Error {
+  kind: ErrorKind,
   message: String,
-  cause: Option<Box<error::Error>>,
+  cause: Option<Box<error::Error + Send + Sync>>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ErrorKind {
+  Other,
+  Io,
+  NotFound,
+  AlreadyExists,
+  IsDir,
+  NotDir,
+  NotEmpty,
+  Busy,
+  InvalidInput,
+  NoSpace,
+  FileTooLarge,
+  NotPermitted,
+  PermissionDenied,
+  QuotaExceeded,
 }
 
 pub type Result<T> = result::Result<T, Error>;
 
 impl Error {
   pub fn new(message: String) -> Error {
-    Error { message: message, cause: None }
+    Error::of_kind(ErrorKind::Other, message)
+  }
+
+  pub fn of_kind(kind: ErrorKind, message: String) -> Error {
+    Error { kind: kind, message: message, cause: None }
+  }
+
+  pub fn kind(&self) -> ErrorKind {
+    self.kind
   }
 }
 
@@ -20,7 +47,7 @@ impl error::Error for Error {
   }
 
   fn cause(&self) -> Option<&error::Error> {
-    self.cause.as_ref().map(|e| &**e)
+    self.cause.as_ref().map(|e| &**e as &error::Error)
   }
 }
 
@@ -32,6 +59,10 @@ impl fmt::Display for Error {
 
 impl convert::From<io::Error> for Error {
   fn from(err: io::Error) -> Error {
-    Error { message: format!("IO error"), cause: Some(Box::new(err)) }
+    Error {
+      kind: ErrorKind::Io,
+      message: format!("IO error"),
+      cause: Some(Box::new(err)),
+    }
   }
 }
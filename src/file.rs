@@ -1,14 +1,44 @@
+use std::{cmp};
 use prelude::*;
 
 #[derive(Debug)]
 pub struct FileHandle {
   ino: u64,
+  access: u16,
+  next_offset: u64,
+  window: u64,
+  readahead: Option<Readahead>,
+  block_map: BlockMap,
 }
 
-pub fn open_file(fs: &mut Filesystem, ino: u64) -> Result<FileHandle> {
+#[derive(Debug)]
+struct Readahead {
+  offset: u64,
+  data: Vec<u8>,
+  data_version: u64,
+}
+
+impl FileHandle {
+  pub fn ino(&self) -> u64 {
+    self.ino
+  }
+}
+
+pub fn open_file(fs: &mut Filesystem, creds: &Credentials, ino: u64,
+  access: u16) -> Result<FileHandle>
+{
   let inode = try!(get_inode(fs, ino));
   if inode.mode.file_type == FileType::Regular {
-    Ok(FileHandle { ino: ino })
+    try!(check_access(creds, &inode, access & (ACCESS_READ | ACCESS_WRITE)));
+    *fs.open_counts.entry(ino).or_insert(0) += 1;
+    Ok(FileHandle {
+      ino: ino,
+      access: access,
+      next_offset: 0,
+      window: fs.options.readahead.min_window,
+      readahead: None,
+      block_map: BlockMap::new(ino),
+    })
   } else {
     Err(Error::new(format!("inode is not a regular file")))
   }
@@ -17,17 +47,177 @@ pub fn open_file(fs: &mut Filesystem, ino: u64) -> Result<FileHandle> {
 pub fn read_file(fs: &mut Filesystem, handle: &mut FileHandle,
     offset: u64, buffer: &mut [u8]) -> Result<u64> 
 {
+  try!(check_handle_access(handle, ACCESS_READ));
   let inode = try!(get_inode(fs, handle.ino));
-  read_inode_data(fs, &inode, offset, buffer)
+  let length = try!(read_ahead(fs, &inode, handle, offset, buffer));
+  try!(touch_accessed(fs, handle.ino));
+  Ok(length)
+}
+
+pub fn read_file_shared(fs: &Filesystem, handle: &mut FileHandle, inode: &Inode,
+    offset: u64, buffer: &mut [u8]) -> Result<u64>
+{
+  try!(check_handle_access(handle, ACCESS_READ));
+  read_ahead(fs, inode, handle, offset, buffer)
 }
 
-pub fn write_file(fs: &mut Filesystem, handle: &mut FileHandle,
+fn read_ahead(fs: &Filesystem, inode: &Inode, handle: &mut FileHandle,
+  offset: u64, buffer: &mut [u8]) -> Result<u64>
+{
+  let config = fs.options.readahead;
+  let length = cmp::min(buffer.len() as u64, inode.size.saturating_sub(offset));
+  let sequential = offset == handle.next_offset;
+
+  let cached = match handle.readahead {
+    Some(ref readahead) => readahead.data_version == fs.data_version() &&
+      readahead.offset <= offset &&
+      offset + length <= readahead.offset + readahead.data.len() as u64,
+    None => false,
+  };
+
+  if !cached && sequential && config.max_window > 0 {
+    let window = cmp::max(length, handle.window);
+    let mut data = make_buffer(window);
+    let data_length = try!(read_mapped_data(fs, &mut handle.block_map, inode, offset,
+      &mut data[..]));
+    data.truncate(data_length as usize);
+    handle.readahead = Some(Readahead {
+      offset: offset,
+      data: data,
+      data_version: fs.data_version(),
+    });
+    handle.window = cmp::min(handle.window * 2, config.max_window);
+  } else if !cached {
+    try!(read_mapped_data(fs, &mut handle.block_map, inode, offset,
+      &mut buffer[..length as usize]));
+    handle.readahead = None;
+    handle.window = config.min_window;
+    handle.next_offset = offset + length;
+    return Ok(length)
+  }
+
+  if let Some(ref readahead) = handle.readahead {
+    let begin = (offset - readahead.offset) as usize;
+    buffer[..length as usize].copy_from_slice(&readahead.data[begin..begin + length as usize]);
+  }
+  handle.next_offset = offset + length;
+  Ok(length)
+}
+
+pub fn write_file(fs: &mut Filesystem, creds: &Credentials, handle: &mut FileHandle,
     offset: u64, buffer: &[u8]) -> Result<u64>
 {
+  try!(check_handle_access(handle, ACCESS_WRITE));
   let mut inode = try!(get_inode(fs, handle.ino));
-  write_inode_data(fs, &mut inode, offset, buffer)
+  let block_map = &mut handle.block_map;
+  let length = try!(with_reserved_access(fs, creds, |fs|
+    write_mapped_data(fs, block_map, &mut inode, offset, buffer)));
+  if !creds.is_root() {
+    kill_privileges(&mut inode);
+  }
+  touch_modified(fs, &mut inode);
+  try!(update_inode(fs, &inode));
+  Ok(length)
+}
+
+pub fn write_file_shared<F>(fs: &Filesystem, creds: &Credentials,
+    handle: &mut FileHandle, inode: &mut Inode, offset: u64, buffer: &[u8],
+    reserve: F) -> Result<Option<u64>>
+  where F: FnOnce(u64) -> bool
+{
+  try!(check_handle_access(handle, ACCESS_WRITE));
+  let length = try!(write_mapped_data_shared(fs, &mut handle.block_map, inode,
+    offset, buffer, reserve));
+  if length.is_some() {
+    if !creds.is_root() {
+      kill_privileges(inode);
+    }
+    touch_modified(fs, inode);
+  }
+  Ok(length)
+}
+
+pub fn check_handle_access(handle: &FileHandle, access: u16) -> Result<()> {
+  if handle.access & access != access {
+    return Err(Error::of_kind(ErrorKind::PermissionDenied, format!(
+      "Handle of inode {} was not opened for access {:o}", handle.ino, access)))
+  }
+  Ok(())
 }
 
 pub fn close_file(fs: &mut Filesystem, handle: FileHandle) -> Result<()> {
+  let last_handle = match fs.open_counts.get_mut(&handle.ino) {
+    Some(count) => { *count -= 1; *count == 0 },
+    None => false,
+  };
+
+  if last_handle {
+    fs.open_counts.remove(&handle.ino);
+    let mut inode = try!(get_inode(fs, handle.ino));
+    if inode.links_count == 0 {
+      try!(remove_orphan(fs, &mut inode));
+      try!(remove_inode(fs, &mut inode));
+      try!(update_inode(fs, &inode));
+    }
+  }
   flush_ino(fs, handle.ino)
 }
+
+pub fn sync_file(fs: &mut Filesystem, ino: u64, data_only: bool) -> Result<()> {
+  try!(fs.volume.sync());
+  try!(flush_dirty_groups(fs));
+  try!(write_back_ino(fs, ino, data_only));
+  fs.volume.sync()
+}
+
+#[cfg(test)]
+mod test {
+  use prelude::*;
+  use shared::{SharedFilesystem};
+  use test_fs::{make_volume, make_fs, mount, mode, make_file};
+
+  // the handle carries the access granted at open, so taking away the write
+  // bits afterwards must not fail writes through a handle that is already open
+  #[test]
+  fn write_after_chmod_read_only() {
+    let mut fs = make_fs(1024);
+    let user = Credentials { uid: 1000, gid: 1000, groups: Vec::new() };
+    let ino = make_file(&mut fs, ROOT_INO, b"f").ino;
+    let attr = FileAttr { uid: 1000, gid: 1000, .. get_inode(&mut fs, ino).unwrap().attr };
+    set_inode_mode_attr(&mut fs, &Credentials::root(), ino,
+      mode(FileType::Regular, 0o644), attr).unwrap();
+
+    let mut handle = open_file(&mut fs, &user, ino, ACCESS_READ | ACCESS_WRITE).unwrap();
+    set_inode_mode_attr(&mut fs, &user, ino, mode(FileType::Regular, 0o444), attr).unwrap();
+    assert_eq!(open_file(&mut fs, &user, ino, ACCESS_WRITE).unwrap_err().kind(),
+      ErrorKind::PermissionDenied);
+    assert_eq!(write_file(&mut fs, &user, &mut handle, 0, b"hello").unwrap(), 5);
+
+    let shared = SharedFilesystem::new(fs);
+    assert_eq!(shared.write_file(&user, &mut handle, 5, b" world").unwrap(), 6);
+    let mut fs = shared.into_inner().unwrap();
+    let mut buffer = [0; 16];
+    assert_eq!(read_file(&mut fs, &mut handle, 0, &mut buffer).unwrap(), 11);
+    assert_eq!(&buffer[..11], b"hello world");
+    close_file(&mut fs, handle).unwrap();
+  }
+
+  #[test]
+  fn sync_writes_only_dirty_groups() {
+    let volume = make_volume(1024, 4, 128);
+    let mut fs = mount(&volume);
+    let ino = make_file(&mut fs, ROOT_INO, b"f").ino;
+    flush_fs(&mut fs).unwrap();
+    assert_eq!(dirty_group_idxs(&fs), Vec::<u64>::new());
+
+    let mut handle = open_file(&mut fs, &Credentials::root(), ino, ACCESS_WRITE).unwrap();
+    write_file(&mut fs, &Credentials::root(), &mut handle, 0, &[1; 3000]).unwrap();
+    assert_eq!(dirty_group_idxs(&fs), vec![0]);
+    sync_file(&mut fs, ino, false).unwrap();
+    assert_eq!(dirty_group_idxs(&fs), Vec::<u64>::new());
+
+    let free_blocks = lock_group(&fs, 0).desc.free_blocks_count;
+    assert_eq!(decode_u16(&volume.image()[2 * 1024 + 12..]) as u64, free_blocks as u64);
+    close_file(&mut fs, handle).unwrap();
+  }
+}
@@ -0,0 +1,71 @@
+use prelude::*;
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Fragmentation {
+  pub files: u64,
+  pub blocks: u64,
+  pub extents: u64,
+}
+
+impl Fragmentation {
+  pub fn score(&self) -> f64 {
+    if self.blocks <= self.files {
+      0.0
+    } else {
+      (self.extents - self.files) as f64 / (self.blocks - self.files) as f64
+    }
+  }
+
+  fn add(&mut self, other: &Fragmentation) {
+    self.files += other.files;
+    self.blocks += other.blocks;
+    self.extents += other.extents;
+  }
+}
+
+pub fn inode_fragmentation(fs: &mut Filesystem, ino: u64) -> Result<Fragmentation> {
+  let inode = try!(get_inode(fs, ino));
+  let mut frag = Fragmentation::default();
+  if !inode_has_blocks(fs, &inode) {
+    return Ok(frag)
+  }
+
+  let block_count = (inode.size + fs.block_size() - 1) / fs.block_size();
+  for extent in try!(map_range(fs, &inode, 0, block_count)) {
+    if extent.block.is_some() {
+      frag.blocks += extent.length;
+      frag.extents += 1;
+    }
+  }
+
+  if frag.blocks > 0 {
+    frag.files = 1;
+  }
+  Ok(frag)
+}
+
+pub fn fs_fragmentation(fs: &mut Filesystem) -> Result<Fragmentation> {
+  let mut frag = Fragmentation::default();
+  for ino in used_inos(fs) {
+    frag.add(&try!(inode_fragmentation(fs, ino)));
+  }
+  Ok(frag)
+}
+
+pub fn used_inos(fs: &Filesystem) -> Vec<u64> {
+  let mut inos = Vec::new();
+  let inodes_per_group = fs.superblock.inodes_per_group as u64;
+  for group_idx in 0..fs.group_count() {
+    let group = lock_group(fs, group_idx);
+    for local_idx in 0..inodes_per_group {
+      let byte = group.inode_bitmap[(local_idx / 8) as usize];
+      let ino = group.idx * inodes_per_group + local_idx + 1;
+      if (byte & (1 << (local_idx % 8))) != 0 &&
+        (ino == ROOT_INO || ino >= fs.superblock.first_ino as u64)
+      {
+        inos.push(ino);
+      }
+    }
+  }
+  inos
+}
@@ -1,5 +1,7 @@
-use std::{iter};
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::{env, iter};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use prelude::*;
 
 pub struct Filesystem {
@@ -7,11 +9,107 @@ pub struct Filesystem {
   pub superblock: Superblock,
   pub superblock_bytes: Vec<u8>,
   pub superblock_dirty: bool,
-  pub groups: Vec<Group>,
+  pub groups: Vec<Mutex<Group>>,
+  pub dirty_groups: Mutex<BTreeSet<u64>>,
   pub inode_cache: HashMap<u64, Inode>,
   pub dirty_inos: HashSet<u64>,
-  pub reused_inos: HashSet<u64>,
-  pub cache_queue: VecDeque<u64>,
+  pub inode_lru: Lru<u64>,
+  pub pinned_inos: HashMap<u64, u64>,
+  pub unsorted_dirs: HashSet<u64>,
+  pub dentry_cache: HashMap<(u64, Vec<u8>), Option<u64>>,
+  pub dentry_lru: Lru<(u64, Vec<u8>)>,
+  pub cache_stats: CacheStats,
+  pub data_version: AtomicU64,
+  pub block_map_version: AtomicU64,
+  pub reservations: HashMap<u64, Reservation>,
+  pub open_counts: HashMap<u64, u64>,
+  pub user_quota: Option<QuotaFile>,
+  pub group_quota: Option<QuotaFile>,
+  pub use_reserved: bool,
+  pub ignore_quota: bool,
+  pub options: MountOptions,
+}
+
+#[derive(Clone)]
+pub struct MountOptions {
+  pub atime_mode: AtimeMode,
+  pub clock: Arc<Clock>,
+  pub reproducible: Option<Reproducible>,
+  pub reservation_blocks: u64,
+  pub quota: bool,
+  pub cache: CacheConfig,
+  pub readahead: ReadaheadConfig,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct CacheConfig {
+  pub inode_count: usize,
+  pub inode_memory: Option<usize>,
+  pub dentry_count: usize,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ReadaheadConfig {
+  pub min_window: u64,
+  pub max_window: u64,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct CacheStats {
+  pub inode_hits: u64,
+  pub inode_misses: u64,
+  pub inode_evictions: u64,
+  pub dentry_hits: u64,
+  pub dentry_misses: u64,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Reproducible {
+  pub epoch: Timestamp,
+  pub uuid: [u8; 16],
+  pub hash_seed: [u8; 16],
+}
+
+impl Reproducible {
+  pub fn from_epoch(epoch: Timestamp) -> Reproducible {
+    Reproducible {
+      epoch: epoch,
+      uuid: derive_uuid(epoch.sec as u64, 0x75756964),
+      hash_seed: derive_uuid(epoch.sec as u64, 0x68617368),
+    }
+  }
+
+  pub fn from_env() -> Option<Reproducible> {
+    env::var("SOURCE_DATE_EPOCH").ok()
+      .and_then(|epoch| epoch.trim().parse::<i64>().ok())
+      .map(|sec| Reproducible::from_epoch(Timestamp { sec: sec, nsec: 0 }))
+  }
+}
+
+fn derive_uuid(seed: u64, salt: u64) -> [u8; 16] {
+  let mut uuid = [0; 16];
+  let mut hash: u64 = 0xcbf29ce484222325 ^ salt;
+  for i in 0..16 {
+    hash ^= (seed >> (8 * (i % 8))) & 0xff;
+    hash = hash.wrapping_mul(0x100000001b3);
+    uuid[i] = (hash >> 32) as u8;
+  }
+  uuid[6] = (uuid[6] & 0x0f) | 0x40;
+  uuid[8] = (uuid[8] & 0x3f) | 0x80;
+  uuid
+}
+
+#[derive(Debug, Clone)]
+pub struct StatFs {
+  pub block_size: u64,
+  pub blocks: u64,
+  pub free_blocks: u64,
+  pub available_blocks: u64,
+  pub inodes: u64,
+  pub free_inodes: u64,
+  pub name_len: u64,
+  pub fsid: u64,
+  pub volume_name: Vec<u8>,
 }
 
 pub struct Group {
@@ -19,7 +117,15 @@ pub struct Group {
   pub desc: GroupDesc,
   pub block_bitmap: Vec<u8>,
   pub inode_bitmap: Vec<u8>,
-  pub dirty: bool,
+  pub block_hint: u64,
+  pub inode_hint: u64,
+  pub largest_free_run: Option<u64>,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Reservation {
+  pub start: u64,
+  pub end: u64,
 }
 
 pub const ROOT_INO: u64 = 2;
@@ -29,50 +135,197 @@ impl Filesystem {
     1024 << self.superblock.log_block_size 
   }
 
+  pub fn now(&self) -> Timestamp {
+    self.options.clock.now()
+  }
+
   pub fn group_count(&self) -> u64 {
     let a = self.superblock.blocks_count as u64;
     let b = self.superblock.blocks_per_group as u64;
     (a + b - 1) / b
   }
+
+  pub fn data_version(&self) -> u64 {
+    self.data_version.load(Ordering::SeqCst)
+  }
+
+  pub fn bump_data_version(&self) {
+    self.data_version.fetch_add(1, Ordering::SeqCst);
+  }
+
+  pub fn block_map_version(&self) -> u64 {
+    self.block_map_version.load(Ordering::SeqCst)
+  }
+
+  pub fn bump_block_map_version(&self) {
+    self.block_map_version.fetch_add(1, Ordering::SeqCst);
+  }
+}
+
+impl Default for MountOptions {
+  fn default() -> MountOptions {
+    MountOptions {
+      atime_mode: AtimeMode::Relative,
+      clock: Arc::new(SystemClock),
+      reproducible: None,
+      reservation_blocks: 8,
+      quota: false,
+      cache: CacheConfig::default(),
+      readahead: ReadaheadConfig::default(),
+    }
+  }
 }
 
-pub fn mount_fs(mut volume: Box<Volume>) -> Result<Filesystem> {
+impl Default for ReadaheadConfig {
+  fn default() -> ReadaheadConfig {
+    ReadaheadConfig {
+      min_window: 16 * 1024,
+      max_window: 512 * 1024,
+    }
+  }
+}
+
+impl Default for CacheConfig {
+  fn default() -> CacheConfig {
+    CacheConfig {
+      inode_count: 1024,
+      inode_memory: None,
+      dentry_count: 4096,
+    }
+  }
+}
+
+pub fn mount_fs(volume: Box<Volume>) -> Result<Filesystem> {
+  mount_fs_with_options(volume, MountOptions::default())
+}
+
+pub fn mount_fs_with_options(volume: Box<Volume>,
+  mut options: MountOptions) -> Result<Filesystem>
+{
   let mut superblock_bytes = make_buffer(1024);
   try!(volume.read(1024, &mut superblock_bytes[..]));
-  let superblock = try!(decode_superblock(&superblock_bytes[..], true));
+  let mut superblock = try!(decode_superblock(&superblock_bytes[..], true));
+
+  let mut superblock_dirty = false;
+  if let Some(reproducible) = options.reproducible {
+    options.clock = Arc::new(FixedClock(reproducible.epoch));
+    if superblock.rev_level >= 1 {
+      superblock.uuid = reproducible.uuid;
+      superblock.hash_seed = reproducible.hash_seed;
+      superblock_dirty = true;
+    }
+  }
 
   let mut fs = Filesystem {
     volume: volume,
     superblock: superblock,
     superblock_bytes: superblock_bytes,
-    superblock_dirty: false,
+    superblock_dirty: superblock_dirty,
     groups: Vec::new(),
+    dirty_groups: Mutex::new(BTreeSet::new()),
     inode_cache: HashMap::new(),
     dirty_inos: HashSet::new(),
-    reused_inos: HashSet::new(),
-    cache_queue: VecDeque::new(),
+    inode_lru: Lru::new(),
+    pinned_inos: HashMap::new(),
+    unsorted_dirs: HashSet::new(),
+    dentry_cache: HashMap::new(),
+    dentry_lru: Lru::new(),
+    cache_stats: CacheStats::default(),
+    data_version: AtomicU64::new(0),
+    block_map_version: AtomicU64::new(0),
+    reservations: HashMap::new(),
+    open_counts: HashMap::new(),
+    user_quota: None,
+    group_quota: None,
+    use_reserved: false,
+    ignore_quota: false,
+    options: options,
   };
 
   for group_idx in 0..fs.group_count() {
     let group = try!(read_group(&mut fs, group_idx));
-    fs.groups.push(group);
+    fs.groups.push(Mutex::new(group));
+  }
+  if fs.options.reproducible.is_some() {
+    try!(zero_free_space(&mut fs));
   }
 
+  try!(load_quotas(&mut fs));
+  try!(process_orphans(&mut fs));
   try!(flush_superblock(&mut fs, false));
   Ok(fs)
 }
 
 pub fn flush_fs(fs: &mut Filesystem) -> Result<()> {
+  if fs.options.reproducible.is_some() {
+    try!(sort_unsorted_dirs(fs));
+  }
+  try!(with_reserved_access(fs, &Credentials::root(), flush_quotas));
+  try!(fs.volume.sync());
+
   let dirty_inos = fs.dirty_inos.clone();
   for dirty_ino in dirty_inos {
     try!(flush_ino(fs, dirty_ino));
   }
 
-  for group_idx in 0..fs.group_count() {
-    try!(flush_group(fs, group_idx));
+  try!(flush_dirty_groups(fs));
+  try!(fs.volume.sync());
+
+  try!(flush_superblock(fs, true));
+  fs.volume.sync()
+}
+
+pub fn cache_stats(fs: &Filesystem) -> CacheStats {
+  fs.cache_stats
+}
+
+pub fn statfs(fs: &Filesystem) -> StatFs {
+  let superblock = &fs.superblock;
+  let free_blocks = superblock.free_blocks_count as u64;
+  let name_end = superblock.volume_name.iter()
+    .position(|&b| b == 0).unwrap_or(superblock.volume_name.len());
+  StatFs {
+    block_size: fs.block_size(),
+    blocks: superblock.blocks_count as u64,
+    free_blocks: free_blocks,
+    available_blocks: free_blocks.saturating_sub(superblock.r_blocks_count as u64),
+    inodes: superblock.inodes_count as u64,
+    free_inodes: superblock.free_inodes_count as u64,
+    name_len: 255,
+    fsid: decode_u64(&superblock.uuid[0..]) ^ decode_u64(&superblock.uuid[8..]),
+    volume_name: superblock.volume_name[..name_end].to_vec(),
+  }
+}
+
+pub fn set_ro_compat_feature(fs: &mut Filesystem, feature: u32) -> Result<()> {
+  if (fs.superblock.feature_ro_compat & feature) != 0 {
+    return Ok(())
+  } else if fs.superblock.rev_level < 1 {
+    return Err(Error::new(format!(
+      "Cannot enable ro_compat feature 0x{:x} in rev {}",
+      feature, fs.superblock.rev_level)));
   }
 
-  flush_superblock(fs, true)
+  fs.superblock.feature_ro_compat |= feature;
+  fs.superblock_dirty = true;
+  Ok(())
+}
+
+// kept apart from dirty_inos, which forgets every directory that eviction
+// writes back before the flush
+fn sort_unsorted_dirs(fs: &mut Filesystem) -> Result<()> {
+  let mut dir_inos: Vec<u64> = fs.unsorted_dirs.iter().cloned().collect();
+  dir_inos.sort();
+  for dir_ino in dir_inos {
+    let inode = try!(get_inode(fs, dir_ino));
+    if inode.mode.file_type == FileType::Dir && inode.links_count > 0 &&
+      !try!(is_dir_sorted(fs, &inode))
+    {
+      try!(sort_dir(fs, dir_ino));
+    }
+    fs.unsorted_dirs.remove(&dir_ino);
+  }
+  Ok(())
 }
 
 fn flush_superblock(fs: &mut Filesystem, clean: bool) -> Result<()> {
@@ -91,3 +344,36 @@ fn flush_superblock(fs: &mut Filesystem, clean: bool) -> Result<()> {
 pub fn make_buffer(size: u64) -> Vec<u8> {
   iter::repeat(0).take(size as usize).collect()
 }
+
+#[cfg(test)]
+mod test {
+  use prelude::*;
+  use test_fs::{make_volume, mount_with_options, make_file, make_dir};
+
+  fn build_tree(inode_count: usize) -> Vec<u8> {
+    let volume = make_volume(1024, 2, 128);
+    let options = MountOptions {
+      reproducible: Some(Reproducible::from_epoch(Timestamp { sec: 1_000_000, nsec: 0 })),
+      cache: CacheConfig { inode_count: inode_count, .. CacheConfig::default() },
+      .. MountOptions::default()
+    };
+    let mut fs = mount_with_options(&volume, options);
+    let dir_ino = make_dir(&mut fs, ROOT_INO, b"d").ino;
+    for &name in [b"c", b"a", b"b"].iter() {
+      make_file(&mut fs, dir_ino, name);
+      make_file(&mut fs, ROOT_INO, name);
+    }
+    flush_fs(&mut fs).unwrap();
+
+    for &ino in [ROOT_INO, dir_ino].iter() {
+      let inode = get_inode(&mut fs, ino).unwrap();
+      assert!(is_dir_sorted(&fs, &inode).unwrap());
+    }
+    volume.image()
+  }
+
+  #[test]
+  fn reproducible_image_ignores_cache_pressure() {
+    assert!(build_tree(1) == build_tree(1024));
+  }
+}
@@ -1,3 +1,4 @@
+use std::sync::{LockResult, MutexGuard};
 use prelude::*;
 
 pub fn read_group(fs: &mut Filesystem, group_idx: u64) -> Result<Group> {
@@ -17,7 +18,9 @@ pub fn read_group(fs: &mut Filesystem, group_idx: u64) -> Result<Group> {
     desc: desc,
     block_bitmap: block_bitmap,
     inode_bitmap: inode_bitmap,
-    dirty: false 
+    block_hint: 0,
+    inode_hint: 0,
+    largest_free_run: None,
   })
 }
 
@@ -31,17 +34,17 @@ fn read_group_desc(fs: &mut Filesystem, table_block: u64,
 }
 
 fn write_group(fs: &mut Filesystem, group_idx: u64) -> Result<()> {
-  let group_desc = fs.groups[group_idx as usize].desc;
+  let group_desc = group_mut(fs, group_idx).desc;
   let table_block = fs.superblock.first_data_block as u64 + 1;
   try!(write_group_desc(fs, table_block, group_idx, &group_desc));
 
-  let block_bitmap_offset = group_desc.block_bitmap as u64 * fs.block_size();
-  try!(fs.volume.write(block_bitmap_offset,
-    &fs.groups[group_idx as usize].block_bitmap[..]));
-
-  let inode_bitmap_offset = group_desc.inode_bitmap as u64 * fs.block_size();
-  try!(fs.volume.write(inode_bitmap_offset,
-    &fs.groups[group_idx as usize].inode_bitmap[..]));
+  let block_size = fs.block_size();
+  let Filesystem { ref mut volume, ref mut groups, .. } = *fs;
+  let group = unpoison(groups[group_idx as usize].get_mut());
+  try!(volume.write(group_desc.block_bitmap as u64 * block_size,
+    &group.block_bitmap[..]));
+  try!(volume.write(group_desc.inode_bitmap as u64 * block_size,
+    &group.inode_bitmap[..]));
 
   Ok(())
 }
@@ -56,14 +59,36 @@ fn write_group_desc(fs: &mut Filesystem, table_block: u64,
   fs.volume.write(offset, &desc_buf[..])
 }
 
-pub fn flush_group(fs: &mut Filesystem, group_idx: u64) -> Result<()> {
-  if fs.groups[group_idx as usize].dirty {
+pub fn mark_group_dirty(fs: &Filesystem, group_idx: u64) {
+  unpoison(fs.dirty_groups.lock()).insert(group_idx);
+}
+
+pub fn dirty_group_idxs(fs: &Filesystem) -> Vec<u64> {
+  unpoison(fs.dirty_groups.lock()).iter().cloned().collect()
+}
+
+pub fn flush_dirty_groups(fs: &mut Filesystem) -> Result<()> {
+  for group_idx in dirty_group_idxs(fs) {
     try!(write_group(fs, group_idx));
-    fs.groups[group_idx as usize].dirty = false;
+    unpoison(fs.dirty_groups.get_mut()).remove(&group_idx);
   }
   Ok(())
 }
 
+// poisoning is ignored, a panic in the middle of an update would leave an
+// unlocked group just as inconsistent
+pub fn lock_group(fs: &Filesystem, group_idx: u64) -> MutexGuard<'_, Group> {
+  unpoison(fs.groups[group_idx as usize].lock())
+}
+
+pub fn group_mut(fs: &mut Filesystem, group_idx: u64) -> &mut Group {
+  unpoison(fs.groups[group_idx as usize].get_mut())
+}
+
+fn unpoison<T>(result: LockResult<T>) -> T {
+  result.unwrap_or_else(|err| err.into_inner())
+}
+
 pub fn get_ino_group(fs: &Filesystem, ino: u64) -> (u64, u64) {
   let group_size = fs.superblock.inodes_per_group as u64;
   ((ino - 1) / group_size, (ino - 1) % group_size)
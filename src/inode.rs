@@ -1,97 +1,212 @@
+use std::{cmp, mem};
 use prelude::*;
 
+const RELATIME_INTERVAL: i64 = 24 * 60 * 60;
+
 pub fn get_inode(fs: &mut Filesystem, ino: u64) -> Result<Inode> {
   if let Some(inode) = fs.inode_cache.get(&ino) {
-    fs.reused_inos.insert(ino);
+    fs.inode_lru.touch(ino);
+    fs.cache_stats.inode_hits += 1;
     return Ok(inode.clone())
   }
 
+  fs.cache_stats.inode_misses += 1;
   let inode = try!(read_inode(fs, ino));
-  fs.inode_cache.insert(ino, inode.clone());
-  fs.cache_queue.push_back(ino);
-  try!(refit_inode_cache(fs));
+  try!(cache_inode(fs, &inode));
   Ok(inode)
 }
 
-pub fn update_inode(fs: &mut Filesystem, inode: &Inode) -> Result<()> {
-  use std::collections::hash_map::Entry;
+pub fn cache_inode(fs: &mut Filesystem, inode: &Inode) -> Result<()> {
+  if fs.inode_cache.contains_key(&inode.ino) {
+    return Ok(())
+  }
+  fs.inode_cache.insert(inode.ino, inode.clone());
+  fs.inode_lru.touch(inode.ino);
+  refit_inode_cache(fs)
+}
 
+pub fn peek_inode(fs: &Filesystem, ino: u64) -> Result<Inode> {
+  match fs.inode_cache.get(&ino) {
+    Some(inode) => Ok(inode.clone()),
+    None => read_inode(fs, ino),
+  }
+}
+
+pub fn update_inode(fs: &mut Filesystem, inode: &Inode) -> Result<()> {
   fs.dirty_inos.insert(inode.ino);
-  match fs.inode_cache.entry(inode.ino) {
-    Entry::Occupied(mut occupied) => {
-      occupied.insert(inode.clone());
-      fs.reused_inos.insert(inode.ino);
-      return Ok(())
-    },
-    Entry::Vacant(vacant) => {
-      vacant.insert(inode.clone());
-      fs.cache_queue.push_back(inode.ino);
-    },
+  let cached = fs.inode_cache.insert(inode.ino, inode.clone()).is_some();
+  fs.inode_lru.touch(inode.ino);
+  if cached {
+    Ok(())
+  } else {
+    refit_inode_cache(fs)
   }
+}
+
+pub fn pin_inode(fs: &mut Filesystem, ino: u64) -> Result<()> {
+  try!(get_inode(fs, ino));
+  *fs.pinned_inos.entry(ino).or_insert(0) += 1;
+  Ok(())
+}
 
+pub fn unpin_inode(fs: &mut Filesystem, ino: u64) -> Result<()> {
+  let unpinned = match fs.pinned_inos.get_mut(&ino) {
+    Some(count) => { *count -= 1; *count == 0 },
+    None => return Err(Error::of_kind(ErrorKind::InvalidInput,
+      format!("Inode {} is not pinned", ino))),
+  };
+  if unpinned {
+    fs.pinned_inos.remove(&ino);
+  }
   refit_inode_cache(fs)
 }
 
-fn refit_inode_cache(fs: &mut Filesystem) -> Result<()> {
-  while fs.inode_cache.len() > 10 {
-    let mut flushed = false;
-
-    while let Some(used_ino) = fs.cache_queue.pop_front() {
-      if !fs.reused_inos.remove(&used_ino) {
-        try!(flush_ino(fs, used_ino));
-        flushed = true;
-        break;
-      } else {
-        fs.cache_queue.push_back(used_ino);
-      }
-    }
+pub fn evict_inode(fs: &mut Filesystem, ino: u64) -> Result<()> {
+  if fs.pinned_inos.contains_key(&ino) {
+    return Err(Error::of_kind(ErrorKind::Busy,
+      format!("Inode {} is pinned in the cache", ino)))
+  }
+  flush_ino(fs, ino)
+}
 
-    if !flushed {
-      let random_ino = *fs.inode_cache.iter().next().unwrap().0;
-      try!(flush_ino(fs, random_ino));
+pub fn inode_cache_limit(fs: &Filesystem) -> usize {
+  let config = &fs.options.cache;
+  let limit = match config.inode_memory {
+    Some(memory) => cmp::min(config.inode_count, memory / mem::size_of::<Inode>()),
+    None => config.inode_count,
+  };
+  cmp::max(limit, 1)
+}
+
+fn refit_inode_cache(fs: &mut Filesystem) -> Result<()> {
+  let limit = inode_cache_limit(fs);
+  while fs.inode_cache.len() > limit {
+    let victim = {
+      let pinned_inos = &fs.pinned_inos;
+      fs.inode_lru.oldest(|ino| !pinned_inos.contains_key(ino))
+    };
+    match victim {
+      Some(ino) => {
+        try!(flush_ino(fs, ino));
+        fs.cache_stats.inode_evictions += 1;
+      },
+      None => break,
     }
   }
   Ok(())
 }
 
 pub fn flush_ino(fs: &mut Filesystem, ino: u64) -> Result<()> {
-  if let Some(inode) = fs.inode_cache.remove(&ino) {
-    fs.reused_inos.remove(&ino);
-    if fs.dirty_inos.remove(&ino) {
-      return write_inode(fs, &inode);
+  discard_reservation(fs, ino);
+  if fs.dirty_inos.contains(&ino) {
+    if let Some(inode) = fs.inode_cache.get(&ino).cloned() {
+      try!(write_inode(fs, &inode));
     }
+    fs.dirty_inos.remove(&ino);
+  }
+  if !fs.pinned_inos.contains_key(&ino) {
+    fs.inode_cache.remove(&ino);
+    fs.inode_lru.remove(&ino);
   }
   Ok(())
 }
 
+pub fn write_back_ino(fs: &mut Filesystem, ino: u64, data_only: bool) -> Result<()> {
+  if !fs.dirty_inos.contains(&ino) {
+    return Ok(())
+  }
+  let inode = match fs.inode_cache.get(&ino) {
+    Some(inode) => inode.clone(),
+    None => return Ok(()),
+  };
+
+  if data_only {
+    let stored_inode = try!(read_inode(fs, ino));
+    if stored_inode.size == inode.size && stored_inode.size_512 == inode.size_512 &&
+      stored_inode.block == inode.block
+    {
+      return Ok(())
+    }
+  }
+
+  try!(write_inode(fs, &inode));
+  fs.dirty_inos.remove(&ino);
+  Ok(())
+}
 
-pub fn set_inode_mode_attr(fs: &mut Filesystem, ino: u64,
-  mode: Mode, attr: FileAttr) -> Result<()>
+pub fn set_inode_mode_attr(fs: &mut Filesystem, creds: &Credentials, ino: u64,
+  mut mode: Mode, mut attr: FileAttr) -> Result<()>
 {
   let mut inode = try!(get_inode(fs, ino));
+  let atime_now = attr.atime.nsec == UTIME_NOW;
+  let mtime_now = attr.mtime.nsec == UTIME_NOW;
+  let now = fs.now();
+  if atime_now { attr.atime = now; }
+  if mtime_now { attr.mtime = now; }
+
+  let uid_changed = attr.uid != inode.attr.uid;
+  let gid_changed = attr.gid != inode.attr.gid;
+  if uid_changed && !creds.is_root() {
+    return Err(Error::of_kind(ErrorKind::NotPermitted, format!(
+      "Uid {} cannot change the owner of inode {}", creds.uid, ino)))
+  } else if gid_changed {
+    try!(check_owner(creds, &inode));
+    if !creds.is_root() && !creds.in_group(attr.gid) {
+      return Err(Error::of_kind(ErrorKind::NotPermitted, format!(
+        "Uid {} is not a member of group {}", creds.uid, attr.gid)))
+    }
+  }
+
+  let mode_changed = mode.suid != inode.mode.suid || mode.sgid != inode.mode.sgid ||
+    mode.sticky != inode.mode.sticky || mode.access_rights != inode.mode.access_rights;
+  let times_set = (!atime_now && attr.atime != inode.attr.atime) ||
+    (!mtime_now && attr.mtime != inode.attr.mtime) || attr.crtime != inode.attr.crtime;
+  if mode_changed || times_set {
+    try!(check_owner(creds, &inode));
+  } else if (atime_now || mtime_now) && creds.uid != inode.attr.uid {
+    try!(check_access(creds, &inode, ACCESS_WRITE));
+  }
+  if mode.sgid && !creds.is_root() && !creds.in_group(attr.gid) {
+    mode.sgid = false;
+  }
+
+  if uid_changed || gid_changed {
+    try!(with_reserved_access(fs, creds, |fs|
+      transfer_quota(fs, &inode, attr.uid, attr.gid)));
+  }
+
   inode.mode = mode;
   inode.attr = attr;
+  if uid_changed || gid_changed {
+    kill_privileges(&mut inode);
+  }
+  touch_changed(fs, &mut inode);
   update_inode(fs, &mut inode)
 }
 
-pub fn truncate_inode_size(fs: &mut Filesystem, ino: u64, new_size: u64) -> Result<()> {
+pub fn truncate_inode_size(fs: &mut Filesystem, creds: &Credentials,
+  ino: u64, new_size: u64) -> Result<()>
+{
   let mut inode = try!(get_inode(fs, ino));
   if inode.mode.file_type != FileType::Regular {
     return Err(Error::new(format!(
       "Cannot truncate inode {} of type {:?}", ino, inode.mode.file_type)));
   }
+  try!(check_access(creds, &inode, ACCESS_WRITE));
 
-  if new_size == 0 {
-    dealloc_inode_blocks(fs, &mut inode)
-  } else if inode.size < new_size {
-    return Err(Error::new(format!(
+  if inode.size < new_size {
+    return Err(Error::of_kind(ErrorKind::InvalidInput, format!(
       "Cannot truncate inode {} with size {} to size {}", ino, inode.size, new_size)));
-  } else {
-    let first_unused_block = (new_size + fs.block_size() - 1) / fs.block_size();
-    try!(truncate_inode_blocks(fs, &mut inode, first_unused_block));
-    inode.size = new_size;
-    update_inode(fs, &mut inode)
   }
+
+  let first_unused_block = (new_size + fs.block_size() - 1) / fs.block_size();
+  try!(truncate_inode_blocks(fs, &mut inode, first_unused_block));
+  inode.size = new_size;
+  if !creds.is_root() {
+    kill_privileges(&mut inode);
+  }
+  touch_modified(fs, &mut inode);
+  update_inode(fs, &mut inode)
 }
 
 pub fn inode_mode_from_linux_mode(mode: u16) -> Result<Mode> {
@@ -101,7 +216,7 @@ pub fn inode_mode_from_linux_mode(mode: u16) -> Result<Mode> {
 pub fn unlink_inode(fs: &mut Filesystem, inode: &mut Inode) -> Result<()> {
   if inode.mode.file_type == FileType::Dir {
     if !try!(is_dir_empty(fs, inode)) {
-      return Err(Error::new(
+      return Err(Error::of_kind(ErrorKind::NotEmpty, 
           format!("Cannot unlink non-empty directory inode {}", inode.ino)));
     }
 
@@ -114,13 +229,18 @@ pub fn unlink_inode(fs: &mut Filesystem, inode: &mut Inode) -> Result<()> {
   }
 
   inode.links_count -= 1;
+  touch_changed(fs, inode);
   if inode.links_count == 0 {
-    try!(remove_inode(fs, inode))
+    if fs.open_counts.contains_key(&inode.ino) {
+      try!(add_orphan(fs, inode));
+    } else {
+      try!(remove_inode(fs, inode));
+    }
   }
   update_inode(fs, inode)
 }
 
-pub fn read_inode(fs: &mut Filesystem, ino: u64) -> Result<Inode> {
+pub fn read_inode(fs: &Filesystem, ino: u64) -> Result<Inode> {
   let (offset, inode_size) = try!(locate_inode(fs, ino));
   let mut inode_buf = make_buffer(inode_size);
   try!(fs.volume.read(offset, &mut inode_buf[..]));
@@ -135,27 +255,54 @@ pub fn write_inode(fs: &mut Filesystem, inode: &Inode) -> Result<()> {
   fs.volume.write(offset, &inode_buf[..])
 }
 
-fn locate_inode(fs: &mut Filesystem, ino: u64) -> Result<(u64, u64)> {
+pub fn locate_inode(fs: &Filesystem, ino: u64) -> Result<(u64, u64)> {
   let (group_idx, local_idx) = get_ino_group(fs, ino);
   let inode_size = fs.superblock.inode_size as u64;
-  let inode_table = fs.groups[group_idx as usize].desc.inode_table as u64;
+  let inode_table = lock_group(fs, group_idx).desc.inode_table as u64;
   let offset = inode_table * fs.block_size() + local_idx * inode_size;
   Ok((offset, inode_size))
 }
 
-pub fn init_inode(fs: &mut Filesystem, dir_inode: &mut Inode,
+pub fn init_inode(fs: &mut Filesystem, creds: &Credentials, dir_inode: &mut Inode,
   ino: u64, mode: Mode, attr: FileAttr) -> Result<Inode> 
 {
+  if fs.options.reproducible.is_some() {
+    let (offset, inode_size) = try!(locate_inode(fs, ino));
+    try!(fs.volume.write(offset, &make_buffer(inode_size)[..]));
+  }
+
+  let mut mode = mode;
+  let mut attr = attr;
+  if dir_inode.mode.sgid {
+    attr.gid = dir_inode.attr.gid;
+    if mode.file_type == FileType::Dir {
+      mode.sgid = true;
+    } else if !creds.is_root() && !creds.in_group(attr.gid) {
+      mode.sgid = false;
+    }
+  }
+
+  let now = fs.now();
   let mut inode = Inode {
     ino: ino,
     mode: mode,
-    attr: attr,
+    attr: FileAttr {
+      atime: now, ctime: now, mtime: now, crtime: now,
+      dtime: 0,
+      .. attr
+    },
     size: 0, size_512: 0,
     links_count: 0, flags: 0,
     block: [0; 15],
     file_acl: 0,
+    extra_isize: cmp::min(INODE_EXTRA_ISIZE as u64,
+      fs.superblock.inode_size as u64 - 128) as u16,
   };
 
+  if let Err(err) = charge_quota(fs, &inode, 0, 1) {
+    try!(dealloc_inode(fs, ino));
+    return Err(err)
+  }
   if mode.file_type == FileType::Dir {
     try!(init_dir(fs, dir_inode, &mut inode));
   }
@@ -163,8 +310,145 @@ pub fn init_inode(fs: &mut Filesystem, dir_inode: &mut Inode,
   Ok(inode)
 }
 
-fn remove_inode(fs: &mut Filesystem, inode: &mut Inode) -> Result<()> {
+pub fn remove_inode(fs: &mut Filesystem, inode: &mut Inode) -> Result<()> {
   try!(dealloc_inode_blocks(fs, inode));
-  inode.attr.dtime = 1451303454;
+  if inode.mode.file_type == FileType::Dir {
+    forget_dir_dentries(fs, inode.ino);
+  }
+  release_quota(fs, inode, 0, 1);
+  inode.attr.dtime = cmp::max(fs.now().sec, 0) as u32;
   dealloc_inode(fs, inode.ino)
 }
+
+pub fn touch_accessed(fs: &mut Filesystem, ino: u64) -> Result<()> {
+  let now = fs.now();
+  let mut inode = try!(get_inode(fs, ino));
+  let update = match fs.options.atime_mode {
+    AtimeMode::Strict => true,
+    AtimeMode::Relative =>
+      inode.attr.atime <= inode.attr.mtime ||
+      inode.attr.atime <= inode.attr.ctime ||
+      inode.attr.atime.sec + RELATIME_INTERVAL <= now.sec,
+    AtimeMode::Never => false,
+  };
+
+  if update {
+    inode.attr.atime = now;
+    try!(update_inode(fs, &inode));
+  }
+  Ok(())
+}
+
+pub fn touch_modified(fs: &Filesystem, inode: &mut Inode) {
+  let now = fs.now();
+  inode.attr.mtime = now;
+  inode.attr.ctime = now;
+}
+
+pub fn touch_changed(fs: &Filesystem, inode: &mut Inode) {
+  inode.attr.ctime = fs.now();
+}
+
+#[cfg(test)]
+mod test {
+  use prelude::*;
+  use test_fs::{make_volume, mount, mount_with_options, make_file};
+
+  fn store_times(inode_size: u64, attr: FileAttr) -> FileAttr {
+    let volume = make_volume(1024, 1, inode_size);
+    let mut fs = mount(&volume);
+    let mut inode = make_file(&mut fs, ROOT_INO, b"f");
+    inode.attr = attr;
+    update_inode(&mut fs, &inode).unwrap();
+    flush_fs(&mut fs).unwrap();
+
+    let mut fs = mount(&volume);
+    get_inode(&mut fs, inode.ino).unwrap().attr
+  }
+
+  fn sample_attr() -> FileAttr {
+    FileAttr {
+      atime: Timestamp { sec: 1_500_000_000, nsec: 123_456_789 },
+      ctime: Timestamp { sec: -5, nsec: 1 },
+      mtime: Timestamp { sec: (1 << 33) + 7, nsec: 999_999_999 },
+      crtime: Timestamp { sec: 1_000_000_000, nsec: 42 },
+      .. FileAttr::default()
+    }
+  }
+
+  #[test]
+  fn times_in_large_inodes() {
+    let attr = sample_attr();
+    let stored = store_times(256, attr);
+    assert_eq!(stored.atime, attr.atime);
+    assert_eq!(stored.ctime, attr.ctime);
+    assert_eq!(stored.mtime, attr.mtime);
+    assert_eq!(stored.crtime, attr.crtime);
+  }
+
+  #[test]
+  fn times_in_small_inodes() {
+    let stored = store_times(128, sample_attr());
+    assert_eq!(stored.atime, Timestamp { sec: 1_500_000_000, nsec: 0 });
+    assert_eq!(stored.ctime, Timestamp { sec: -5, nsec: 0 });
+    assert_eq!(stored.mtime, Timestamp { sec: 0x7fffffff, nsec: 0 });
+    assert_eq!(stored.crtime, Timestamp::default());
+  }
+
+  fn is_cached(fs: &Filesystem, ino: u64) -> bool {
+    fs.inode_cache.contains_key(&ino)
+  }
+
+  #[test]
+  fn inode_cache_evicts_least_recent() {
+    let volume = make_volume(1024, 1, 128);
+    let mut fs = mount(&volume);
+    let inos: Vec<u64> = [b"a", b"b", b"c", b"d"].iter()
+      .map(|name| make_file(&mut fs, ROOT_INO, &name[..]).ino).collect();
+    flush_fs(&mut fs).unwrap();
+
+    let options = MountOptions {
+      cache: CacheConfig { inode_count: 3, .. CacheConfig::default() },
+      .. MountOptions::default()
+    };
+    let mut fs = mount_with_options(&volume, options);
+    let cached: Vec<u64> = fs.inode_cache.keys().cloned().collect();
+    for ino in cached {
+      evict_inode(&mut fs, ino).unwrap();
+    }
+    let (a, b, c, d) = (inos[0], inos[1], inos[2], inos[3]);
+    let stats = cache_stats(&fs);
+
+    for &ino in [a, b, c, a].iter() {
+      get_inode(&mut fs, ino).unwrap();
+    }
+    get_inode(&mut fs, d).unwrap();
+    assert!(is_cached(&fs, a) && is_cached(&fs, c) && is_cached(&fs, d));
+    assert!(!is_cached(&fs, b));
+
+    // c is the oldest now, but pinned, so a goes instead
+    pin_inode(&mut fs, c).unwrap();
+    get_inode(&mut fs, b).unwrap();
+    assert!(is_cached(&fs, b) && is_cached(&fs, c) && is_cached(&fs, d));
+    assert!(!is_cached(&fs, a));
+    assert_eq!(evict_inode(&mut fs, c).unwrap_err().kind(), ErrorKind::Busy);
+    unpin_inode(&mut fs, c).unwrap();
+    assert_eq!(unpin_inode(&mut fs, c).unwrap_err().kind(), ErrorKind::InvalidInput);
+
+    let new_stats = cache_stats(&fs);
+    assert_eq!(new_stats.inode_hits - stats.inode_hits, 2);
+    assert_eq!(new_stats.inode_misses - stats.inode_misses, 5);
+    assert_eq!(new_stats.inode_evictions - stats.inode_evictions, 2);
+
+    // a dirty inode is written back when it is pushed out
+    let mut inode = get_inode(&mut fs, d).unwrap();
+    inode.attr.uid = 1234;
+    update_inode(&mut fs, &inode).unwrap();
+    get_inode(&mut fs, a).unwrap();
+    get_inode(&mut fs, b).unwrap();
+    get_inode(&mut fs, c).unwrap();
+    assert!(!is_cached(&fs, d));
+    assert_eq!(read_inode(&fs, d).unwrap().attr.uid, 1234);
+    assert_eq!(get_inode(&mut fs, d).unwrap().attr.uid, 1234);
+  }
+}
@@ -1,79 +1,268 @@
 use std::{cmp};
 use prelude::*;
 
-pub fn read_inode_data(fs: &mut Filesystem, inode: &Inode, 
+const LARGE_FILE_SIZE: u64 = 0x7fffffff;
+
+pub fn read_inode_data(fs: &Filesystem, inode: &Inode, 
   offset: u64, buffer: &mut [u8]) -> Result<u64> 
+{
+  read_mapped_data(fs, &mut BlockMap::new(inode.ino), inode, offset, buffer)
+}
+
+pub fn read_mapped_data(fs: &Filesystem, map: &mut BlockMap, inode: &Inode,
+  offset: u64, buffer: &mut [u8]) -> Result<u64>
 {
   let block_size = fs.block_size();
-  let max_length = cmp::min(buffer.len() as u64, inode.size - offset);
-  let mut chunk_begin = 0;
-  while chunk_begin < max_length {
-    let chunk_block = (offset + chunk_begin) / block_size;
-    let chunk_offset = (offset + chunk_begin) % block_size;
-    let chunk_length = cmp::min(max_length - chunk_begin,
-        block_size - chunk_offset);
-    try!(read_inode_block(fs, inode, chunk_block, chunk_offset,
-          &mut (buffer[chunk_begin as usize..])[0..chunk_length as usize]));
-    chunk_begin = chunk_begin + chunk_length;
-  }
-  Ok(chunk_begin)
+  let length = cmp::min(buffer.len() as u64, inode.size.saturating_sub(offset));
+  if length == 0 {
+    return Ok(0)
+  }
+
+  let buffer = &mut buffer[..length as usize];
+  let first_block = offset / block_size;
+  let end_block = (offset + length + block_size - 1) / block_size;
+  for extent in try!(map_range_with(fs, map, inode, first_block, end_block - first_block)) {
+    try!(read_block_run(fs, &extent, offset, buffer));
+  }
+  Ok(length)
 }
 
-pub fn read_inode_block(fs: &mut Filesystem, inode: &Inode, inode_block: u64,
+fn read_block_run(fs: &Filesystem, extent: &BlockExtent,
   offset: u64, buffer: &mut [u8]) -> Result<()>
 {
-  assert!(offset + buffer.len() as u64 <= fs.block_size());
-  let real_block = match try!(get_inode_block(fs, inode, inode_block)) {
-    Some(block) => block,
-    None => return Err(Error::new(
-          format!("File block {} is out of the allocated range", inode_block))),
-  };
-  let block_offset = real_block * fs.block_size() + offset;
-  fs.volume.read(block_offset, buffer)
+  let block_size = fs.block_size();
+  let extent_begin = extent.inode_block * block_size;
+  let begin = cmp::max(extent_begin, offset);
+  let end = cmp::min(extent_begin + extent.length * block_size, offset + buffer.len() as u64);
+  let chunk = &mut buffer[(begin - offset) as usize..(end - offset) as usize];
+  match extent.block {
+    Some(block) => fs.volume.read(block * block_size + begin - extent_begin, chunk),
+    None => {
+      for byte in chunk.iter_mut() {
+        *byte = 0;
+      }
+      Ok(())
+    },
+  }
 }
 
 pub fn write_inode_data(fs: &mut Filesystem, inode: &mut Inode,
   offset: u64, buffer: &[u8]) -> Result<u64>
 {
+  let mut map = BlockMap::new(inode.ino);
+  write_mapped_data(fs, &mut map, inode, offset, buffer)
+}
+
+pub fn write_mapped_data(fs: &mut Filesystem, map: &mut BlockMap, inode: &mut Inode,
+  offset: u64, buffer: &[u8]) -> Result<u64>
+{
+  let end = match offset.checked_add(buffer.len() as u64) {
+    Some(end) => end,
+    None => return Err(Error::of_kind(ErrorKind::InvalidInput, format!(
+      "Write of {} bytes at offset {} to inode {} overflows",
+      buffer.len(), offset, inode.ino))),
+  };
+
+  fs.bump_data_version();
   let block_size = fs.block_size();
-  let mut chunk_begin = 0;
-  while chunk_begin < buffer.len() as u64 {
-    let chunk_block = (offset + chunk_begin) / block_size;
-    let chunk_offset = (offset + chunk_begin) % block_size;
-    let chunk_length = cmp::min(buffer.len() as u64 - chunk_begin,
-        block_size - chunk_offset);
-    try!(write_inode_block(fs, inode, chunk_block, chunk_offset,
-          &(buffer[chunk_begin as usize..])[0..chunk_length as usize]));
-    chunk_begin = chunk_begin + chunk_length;
-  }
-
-  if inode.size < offset + chunk_begin {
-    inode.size = offset + chunk_begin;
+  if end > 0 {
+    if let BlockPos::OutOfRange = inode_block_to_pos(fs, (end - 1) / block_size) {
+      return Err(Error::of_kind(ErrorKind::FileTooLarge, format!(
+        "Cannot write to inode {} beyond the maximal file size (end {})",
+        inode.ino, end)));
+    }
+  }
+  if end > LARGE_FILE_SIZE {
+    try!(set_ro_compat_feature(fs, RO_COMPAT_LARGE_FILE));
+  }
+
+  let mut run = BlockRun { next: 0, count: 0 };
+  let written = write_inode_runs(fs, map, inode, offset, buffer, &mut run);
+  try!(release_block_run(fs, &mut run));
+  let written = try!(written);
+
+  if inode.size < offset + written {
+    inode.size = offset + written;
     try!(update_inode(fs, inode));
   }
 
-  Ok(chunk_begin)
+  Ok(written)
 }
 
-fn write_inode_block(fs: &mut Filesystem, inode: &mut Inode, inode_block: u64,
-  offset: u64, buffer: &[u8]) -> Result<()>
+// writes without the exclusive filesystem, which only works when every block
+// the data needs fits into the existing block tree. returns None before
+// touching anything if the caller has to use write_mapped_data instead
+pub fn write_mapped_data_shared<F>(fs: &Filesystem, map: &mut BlockMap,
+  inode: &mut Inode, offset: u64, buffer: &[u8], reserve: F) -> Result<Option<u64>>
+  where F: FnOnce(u64) -> bool
 {
-  assert!(offset + buffer.len() as u64 <= fs.block_size());
-  let real_block = match try!(get_inode_block(fs, inode, inode_block)) {
-    Some(block) => block,
-    None => {
-      let block = try!(alloc_inode_block(fs, inode));
-      try!(set_inode_block(fs, inode, inode_block, block));
-      block
-    }
+  let end = match offset.checked_add(buffer.len() as u64) {
+    Some(end) => end,
+    None => return Ok(None),
   };
-  let block_offset = real_block * fs.block_size() + offset;
-  fs.volume.write(block_offset, buffer)
+  if buffer.is_empty() {
+    return Ok(Some(0))
+  } else if fs.options.quota || fs.options.reproducible.is_some() ||
+    !fs.volume.can_write_shared() ||
+    (end > LARGE_FILE_SIZE &&
+      (fs.superblock.feature_ro_compat & RO_COMPAT_LARGE_FILE) == 0)
+  {
+    return Ok(None)
+  }
+
+  let block_size = fs.block_size();
+  let first_block = offset / block_size;
+  let end_block = (end + block_size - 1) / block_size;
+  if let BlockPos::OutOfRange = inode_block_to_pos(fs, end_block - 1) {
+    return Ok(None)
+  }
+
+  let extents = try!(map_range_with(fs, map, inode, first_block, end_block - first_block));
+  let mut holes = Vec::new();
+  for extent in extents.iter().filter(|extent| extent.block.is_none()) {
+    for inode_block in extent.inode_block..extent.inode_block + extent.length {
+      match try!(find_block_slot(fs, map, inode, inode_block)) {
+        BlockSlot::Missing => return Ok(None),
+        slot => holes.push((inode_block, slot)),
+      }
+    }
+  }
+
+  let hole_size_512 = holes.len() as u64 * (block_size / 512);
+  if (inode.size_512 + hole_size_512 > 0xffffffff &&
+      (fs.superblock.feature_ro_compat & RO_COMPAT_HUGE_FILE) == 0) ||
+    (!holes.is_empty() && !reserve(holes.len() as u64))
+  {
+    return Ok(None)
+  }
+
+  fs.bump_data_version();
+  for extent in extents.iter() {
+    if let Some(block) = extent.block {
+      let extent_begin = extent.inode_block * block_size;
+      let begin = cmp::max(extent_begin, offset);
+      let end = cmp::min(extent_begin + extent.length * block_size, end);
+      try!(fs.volume.write_shared(block * block_size + begin - extent_begin,
+        &buffer[(begin - offset) as usize..(end - offset) as usize]));
+    }
+  }
+
+  for &(inode_block, slot) in holes.iter() {
+    let goal = try!(inode_block_goal(fs, map, inode, inode_block));
+    let block = match alloc_block_shared(fs, inode.ino, goal) {
+      Some(block) => block,
+      None => return Err(Error::of_kind(ErrorKind::NoSpace,
+        format!("No free blocks remain for files"))),
+    };
+
+    let block_begin = inode_block * block_size;
+    let begin = cmp::max(block_begin, offset);
+    let end = cmp::min(block_begin + block_size, end);
+    let mut data = make_buffer(block_size);
+    data[(begin - block_begin) as usize..(end - block_begin) as usize]
+      .copy_from_slice(&buffer[(begin - offset) as usize..(end - offset) as usize]);
+    let linked = fs.volume.write_shared(block * block_size, &data[..])
+      .and_then(|_| match slot {
+        BlockSlot::Inode(idx) => { inode.block[idx] = block as u32; Ok(()) },
+        BlockSlot::Indirect(indirect_block, entry) =>
+          set_map_entry_shared(fs, map, 1, indirect_block, entry, block),
+        BlockSlot::Missing => unreachable!(),
+      });
+    if let Err(err) = linked {
+      dealloc_block_shared(fs, block);
+      return Err(err)
+    }
+    inode.size_512 += block_size / 512;
+  }
+
+  if inode.size < end {
+    inode.size = end;
+  }
+  Ok(Some(buffer.len() as u64))
+}
+
+#[derive(Debug, Copy, Clone)]
+struct BlockRun {
+  next: u64,
+  count: u64,
+}
+
+fn write_inode_runs(fs: &mut Filesystem, map: &mut BlockMap, inode: &mut Inode,
+  offset: u64, buffer: &[u8], run: &mut BlockRun) -> Result<u64>
+{
+  if buffer.is_empty() {
+    return Ok(0)
+  }
+
+  let block_size = fs.block_size();
+  let first_block = offset / block_size;
+  let end_block = (offset + buffer.len() as u64 + block_size - 1) / block_size;
+  let extents = try!(map_range_with(fs, map, inode, first_block, end_block - first_block));
+  for extent in extents {
+    let extent_begin = extent.inode_block * block_size;
+    let begin = cmp::max(extent_begin, offset);
+    let end = cmp::min(extent_begin + extent.length * block_size,
+      offset + buffer.len() as u64);
+    let chunk = &buffer[(begin - offset) as usize..(end - offset) as usize];
+    match extent.block {
+      Some(block) =>
+        try!(fs.volume.write(block * block_size + begin - extent_begin, chunk)),
+      None =>
+        try!(write_hole_run(fs, map, inode, &extent, begin - extent_begin, chunk,
+          run, end_block)),
+    }
+  }
+  Ok(buffer.len() as u64)
 }
 
+fn write_hole_run(fs: &mut Filesystem, map: &mut BlockMap, inode: &mut Inode,
+  extent: &BlockExtent, skip: u64, chunk: &[u8],
+  run: &mut BlockRun, end_block: u64) -> Result<()>
+{
+  let block_size = fs.block_size();
+  let mut data = make_buffer(extent.length * block_size);
+  data[skip as usize..skip as usize + chunk.len()].copy_from_slice(chunk);
+
+  let mut first = 0;
+  let mut first_block = 0;
+  for i in 0..extent.length {
+    let inode_block = extent.inode_block + i;
+    let block = try!(alloc_inode_block(fs, map, inode, inode_block,
+      run, end_block - inode_block));
+    try!(set_inode_block(fs, map, inode, inode_block, block));
+    if i == 0 {
+      first_block = block;
+    } else if block != first_block + (i - first) {
+      try!(fs.volume.write(first_block * block_size,
+        &data[(first * block_size) as usize..(i * block_size) as usize]));
+      first = i;
+      first_block = block;
+    }
+  }
+  fs.volume.write(first_block * block_size, &data[(first * block_size) as usize..])
+}
+
+fn release_block_run(fs: &mut Filesystem, run: &mut BlockRun) -> Result<()> {
+  while run.count > 0 {
+    try!(dealloc_block(fs, run.next));
+    run.next += 1;
+    run.count -= 1;
+  }
+  Ok(())
+}
+
+
+pub fn inode_has_blocks(fs: &Filesystem, inode: &Inode) -> bool {
+  match inode.mode.file_type {
+    FileType::Regular | FileType::Dir => true,
+    FileType::Symlink => !is_fast_symlink(fs, inode),
+    FileType::CharDev | FileType::BlockDev | FileType::Fifo | FileType::Socket => false,
+  }
+}
 
 pub fn dealloc_inode_blocks(fs: &mut Filesystem, inode: &mut Inode) -> Result<()> {
-  if !is_fast_symlink(fs, inode) {
+  fs.bump_data_version();
+  if inode_has_blocks(fs, inode) {
     for i in 0..12 {
       let block = inode.block[i] as u64;
       try!(dealloc_inode_block(fs, inode, block));
@@ -90,6 +279,7 @@ pub fn dealloc_inode_blocks(fs: &mut Filesystem, inode: &mut Inode) -> Result<()
 pub fn truncate_inode_blocks(fs: &mut Filesystem, inode: &mut Inode,
   first_block: u64) -> Result<()>
 {
+  fs.bump_data_version();
   let (block1, block2, block3) =
     (inode.block[12] as u64, inode.block[13] as u64, inode.block[14] as u64);
   match inode_block_to_pos(fs, first_block) {
@@ -113,18 +303,19 @@ pub fn truncate_inode_blocks(fs: &mut Filesystem, inode: &mut Inode,
       inode.block[14] = 0;
     },
     BlockPos::Level2(level1, level0) => {
-      let block1 = try!(read_indirect(fs, block2, level1));
+      let block1 = try!(read_indirect_or_zero(fs, block2, level1));
       try!(truncate_indirect_block(fs, inode, block1, level0, 1));
-      try!(truncate_indirect_block(fs, inode, block2, level1, 2));
+      try!(truncate_indirect_block(fs, inode, block2, level1 + kept(level0), 2));
       try!(dealloc_indirect_block(fs, inode, block3, 3));
       inode.block[14] = 0;
     },
     BlockPos::Level3(level2, level1, level0) => {
-      let block2 = try!(read_indirect(fs, block3, level2));
-      let block1 = try!(read_indirect(fs, block2, level1));
+      let block2 = try!(read_indirect_or_zero(fs, block3, level2));
+      let block1 = try!(read_indirect_or_zero(fs, block2, level1));
       try!(truncate_indirect_block(fs, inode, block1, level0, 1));
-      try!(truncate_indirect_block(fs, inode, block2, level1, 2));
-      try!(truncate_indirect_block(fs, inode, block3, level2, 3));
+      try!(truncate_indirect_block(fs, inode, block2, level1 + kept(level0), 2));
+      try!(truncate_indirect_block(fs, inode, block3,
+        level2 + kept(level1 + level0), 3));
     },
     BlockPos::OutOfRange => (),
   }
@@ -134,6 +325,9 @@ pub fn truncate_inode_blocks(fs: &mut Filesystem, inode: &mut Inode,
 fn truncate_indirect_block(fs: &mut Filesystem, inode: &mut Inode,
   block: u64, entry: u64, level: usize) -> Result<()>
 {
+  if block == 0 {
+    return Ok(())
+  }
   for i in entry..fs.block_size() / 4 {
     let entry_block = try!(read_indirect(fs, block, i));
     if entry_block == 0 {
@@ -150,57 +344,68 @@ fn truncate_indirect_block(fs: &mut Filesystem, inode: &mut Inode,
   Ok(())
 }
 
-#[derive(Copy, Clone, Debug)]
-enum BlockPos {
-  Level0(u64),
-  Level1(u64),
-  Level2(u64, u64),
-  Level3(u64, u64, u64),
-  OutOfRange,
+fn read_indirect_or_zero(fs: &Filesystem, indirect_block: u64, entry: u64) -> Result<u64> {
+  if indirect_block == 0 { Ok(0) } else { read_indirect(fs, indirect_block, entry) }
 }
 
-fn read_indirect(fs: &mut Filesystem, indirect_block: u64, entry: u64) -> Result<u64> {
-  let mut buffer = [0; 4];
-  let entry_offset = indirect_block * fs.block_size() + entry * 4;
-  assert!(entry < fs.block_size() / 4);
-  try!(fs.volume.read(entry_offset, &mut buffer[..]));
-  Ok(decode_u32(&buffer[..]) as u64)
+fn kept(first_entry: u64) -> u64 {
+  if first_entry > 0 { 1 } else { 0 }
 }
 
-fn write_indirect(fs: &mut Filesystem, indirect_block: u64,
-  entry: u64, link: u64) -> Result<()> 
-{
-  let mut buffer = [0; 4];
-  let entry_offset = indirect_block * fs.block_size() + entry * 4;
-  assert!(entry < fs.block_size() / 4);
-  encode_u32(link as u32, &mut buffer[..]);
-  fs.volume.write(entry_offset, &buffer[..])
+fn add_inode_size_512(fs: &mut Filesystem, inode: &mut Inode) -> Result<()> {
+  let block_size = fs.block_size();
+  try!(charge_quota(fs, inode, block_size, 0));
+  inode.size_512 += block_size / 512;
+  if inode.size_512 > 0xffffffff {
+    try!(set_ro_compat_feature(fs, RO_COMPAT_HUGE_FILE));
+  }
+  update_inode(fs, inode)
 }
 
+fn sub_inode_size_512(fs: &mut Filesystem, inode: &mut Inode) {
+  let block_size = fs.block_size();
+  inode.size_512 -= block_size / 512;
+  release_quota(fs, inode, block_size, 0);
+}
 
-fn alloc_inode_block(fs: &mut Filesystem, inode: &mut Inode) -> Result<u64> {
-  let (inode_group_idx, _) = get_ino_group(fs, inode.ino);
-  match try!(alloc_block(fs, inode_group_idx)) {
-    Some(block) => {
-      inode.size_512 += (fs.block_size() / 512) as u32;
-      try!(update_inode(fs, inode));
-      Ok(block)
-    },
-    None => Err(Error::new(format!("No free blocks remain for files"))),
+fn alloc_inode_block(fs: &mut Filesystem, map: &mut BlockMap, inode: &mut Inode,
+  inode_block: u64, run: &mut BlockRun, run_length: u64) -> Result<u64>
+{
+  try!(add_inode_size_512(fs, inode));
+  if run.count == 0 {
+    let goal = try!(inode_block_goal(fs, map, inode, inode_block));
+    match try!(alloc_blocks(fs, inode.ino, goal, run_length)) {
+      Some((first, length)) if length > 0 =>
+        *run = BlockRun { next: first, count: length },
+      _ => {
+        sub_inode_size_512(fs, inode);
+        try!(update_inode(fs, inode));
+        return Err(Error::of_kind(ErrorKind::NoSpace,
+          format!("No free blocks remain for files")))
+      },
+    }
   }
+
+  let block = run.next;
+  run.next += 1;
+  run.count -= 1;
+  Ok(block)
 }
 
-fn alloc_indirect_block(fs: &mut Filesystem, inode: &mut Inode) -> Result<u64> {
-  let (inode_group_idx, _) = get_ino_group(fs, inode.ino);
-  let block = match try!(alloc_block(fs, inode_group_idx)) {
-    Some(block) => block,
-    None => return Err(Error::new(
-        format!("No free blocks remain for indirections"))),
+fn alloc_indirect_block(fs: &mut Filesystem, inode: &mut Inode,
+  goal: u64) -> Result<u64>
+{
+  try!(add_inode_size_512(fs, inode));
+  let block = match try!(alloc_blocks(fs, inode.ino, goal, 1)) {
+    Some((block, 1)) => block,
+    _ => {
+      sub_inode_size_512(fs, inode);
+      try!(update_inode(fs, inode));
+      return Err(Error::of_kind(ErrorKind::NoSpace, 
+        format!("No free blocks remain for indirections")))
+    },
   };
 
-  inode.size_512 += (fs.block_size() / 512) as u32;
-  try!(update_inode(fs, inode));
-
   let zeros = make_buffer(fs.block_size());
   let offset = block * fs.block_size();
   try!(fs.volume.write(offset, &zeros[..]));
@@ -213,7 +418,7 @@ fn dealloc_inode_block(fs: &mut Filesystem, inode: &mut Inode,
   if block == 0 {
     return Ok(())
   }
-  inode.size_512 -= (fs.block_size() / 512) as u32;
+  sub_inode_size_512(fs, inode);
   dealloc_block(fs, block)
 }
 
@@ -234,52 +439,28 @@ fn dealloc_indirect_block(fs: &mut Filesystem, inode: &mut Inode,
       try!(dealloc_inode_block(fs, inode, block));
     }
   }
-  inode.size_512 -= (fs.block_size() / 512) as u32;
+  sub_inode_size_512(fs, inode);
+  fs.bump_block_map_version();
   dealloc_block(fs, indirect_block)
 }
 
-fn get_inode_block(fs: &mut Filesystem, inode: &Inode,
-  inode_block: u64) -> Result<Option<u64>> 
+fn inode_block_goal(fs: &Filesystem, map: &mut BlockMap, inode: &Inode,
+  inode_block: u64) -> Result<u64>
 {
-  Ok(Some(match inode_block_to_pos(fs, inode_block) {
-    BlockPos::Level0(level0) => {
-      let block0 = inode.block[level0 as usize] as u64;
-      if block0 == 0 { return Ok(None) } else { block0 }
-    },
-    BlockPos::Level1(level0) => {
-      let block1 = inode.block[12] as u64;
-      if block1 == 0 { return Ok(None) }
-      let block0 = try!(read_indirect(fs, block1, level0));
-      if block0 == 0 { return Ok(None) } else { block0 }
-    },
-    BlockPos::Level2(level1, level0) => {
-      let block2 = inode.block[13] as u64;
-      if block2 == 0 { return Ok(None) }
-      let block1 = try!(read_indirect(fs, block2, level1));
-      if block1 == 0 { return Ok(None) }
-      let block0 = try!(read_indirect(fs, block1, level0));
-      if block0 == 0 { return Ok(None) } else { block0 }
-    },
-    BlockPos::Level3(level2, level1, level0) => {
-      let block3 = inode.block[14] as u64;
-      if block3 == 0 { return Ok(None) }
-      let block2 = try!(read_indirect(fs, block3, level2));
-      if block2 == 0 { return Ok(None) }
-      let block1 = try!(read_indirect(fs, block2, level1));
-      if block1 == 0 { return Ok(None) }
-      let block0 = try!(read_indirect(fs, block1, level0));
-      if block0 == 0 { return Ok(None) } else { block0 }
-    },
-    BlockPos::OutOfRange =>
-      return Err(Error::new(
-        format!("File block {} is out of range for reading", inode_block))),
-  }))
+  if inode_block > 0 {
+    if let Some(prev_block) = try!(map_block(fs, map, inode, inode_block - 1)) {
+      return Ok(prev_block + 1)
+    }
+  }
+  let (group_idx, _) = get_ino_group(fs, inode.ino);
+  Ok(group_idx * fs.superblock.blocks_per_group as u64 +
+     fs.superblock.first_data_block as u64)
 }
 
-fn set_inode_block(fs: &mut Filesystem, inode: &mut Inode,
+fn set_inode_block(fs: &mut Filesystem, map: &mut BlockMap, inode: &mut Inode,
   inode_block: u64, block: u64) -> Result<()> 
 {
-  if let Some(prev_block) = try!(get_inode_block(fs, inode, inode_block)) {
+  if let Some(prev_block) = try!(map_block(fs, map, inode, inode_block)) {
     panic!("inode {}, file block {}: tried to overwrite block {} with {}",
             inode.ino, inode_block, prev_block, block);
   }
@@ -288,19 +469,19 @@ fn set_inode_block(fs: &mut Filesystem, inode: &mut Inode,
     idx: u64| -> Result<_> 
   {
     if inode.block[idx as usize] == 0 {
-      inode.block[idx as usize] = try!(alloc_indirect_block(fs, inode)) as u32;
+      inode.block[idx as usize] = try!(alloc_indirect_block(fs, inode, block)) as u32;
       try!(update_inode(fs, inode));
     }
     Ok(inode.block[idx as usize] as u64)
   };
 
-  let block_indirect = |fs: &mut Filesystem, inode: &mut Inode,
-    indirect: u64, entry: u64| -> Result<_> 
+  let block_indirect = |fs: &mut Filesystem, map: &mut BlockMap, inode: &mut Inode,
+    level: usize, indirect: u64, entry: u64| -> Result<_> 
   {
-    let old_block = try!(read_indirect(fs, indirect, entry));
+    let old_block = try!(map_entry(fs, map, level, indirect, entry));
     if old_block == 0 {
-      let new_block = try!(alloc_indirect_block(fs, inode));
-      try!(write_indirect(fs, indirect, entry, new_block));
+      let new_block = try!(alloc_indirect_block(fs, inode, block));
+      try!(set_map_entry(fs, map, level, indirect, entry, new_block));
       Ok(new_block)
     } else {
       Ok(old_block)
@@ -314,18 +495,18 @@ fn set_inode_block(fs: &mut Filesystem, inode: &mut Inode,
     },
     BlockPos::Level1(level0) => {
       let block1 = try!(inode_indirect(fs, inode, 12));
-      try!(write_indirect(fs, block1, level0, block));
+      try!(set_map_entry(fs, map, 1, block1, level0, block));
     },
     BlockPos::Level2(level1, level0) => {
       let block2 = try!(inode_indirect(fs, inode, 13));
-      let block1 = try!(block_indirect(fs, inode, block2, level1));
-      try!(write_indirect(fs, block1, level0, block));
+      let block1 = try!(block_indirect(fs, map, inode, 2, block2, level1));
+      try!(set_map_entry(fs, map, 1, block1, level0, block));
     },
     BlockPos::Level3(level2, level1, level0) => {
       let block3 = try!(inode_indirect(fs, inode, 14));
-      let block2 = try!(block_indirect(fs, inode, block3, level2));
-      let block1 = try!(block_indirect(fs, inode, block2, level1));
-      try!(write_indirect(fs, block1, level0, block));
+      let block2 = try!(block_indirect(fs, map, inode, 3, block3, level2));
+      let block1 = try!(block_indirect(fs, map, inode, 2, block2, level1));
+      try!(set_map_entry(fs, map, 1, block1, level0, block));
     },
     BlockPos::OutOfRange =>
       return Err(Error::new(
@@ -335,23 +516,131 @@ fn set_inode_block(fs: &mut Filesystem, inode: &mut Inode,
   Ok(())
 }
 
-fn inode_block_to_pos(fs: &Filesystem, inode_block: u64) -> BlockPos {
-  let indirect_1_size: u64 = fs.block_size() / 4;
-  let indirect_2_size = indirect_1_size * indirect_1_size;
-  let indirect_3_size = indirect_1_size * indirect_2_size;
-  if inode_block < 12 {
-    BlockPos::Level0(inode_block)
-  } else if inode_block < 12 + indirect_1_size {
-    BlockPos::Level1(inode_block - 12)
-  } else if inode_block < 12 + indirect_1_size + indirect_2_size {
-    let base = inode_block - 12 - indirect_1_size;
-    BlockPos::Level2(base / indirect_1_size, base % indirect_1_size)
-  } else if inode_block < 12 + indirect_1_size + indirect_2_size + indirect_3_size {
-    let base = inode_block - 12 - indirect_1_size - indirect_2_size;
-    BlockPos::Level3(base / indirect_2_size,
-      (base % indirect_2_size) / indirect_1_size,
-      (base % indirect_2_size) % indirect_1_size)
-  } else {
-    BlockPos::OutOfRange
+#[cfg(test)]
+mod test {
+  use prelude::*;
+  use test_fs::{make_fs};
+
+  fn make_file(fs: &mut Filesystem) -> Inode {
+    let inode = Inode {
+      ino: 12,
+      mode: Mode {
+        file_type: FileType::Regular,
+        suid: false, sgid: false, sticky: false,
+        access_rights: 0o644,
+      },
+      attr: FileAttr::default(),
+      size: 0, size_512: 0,
+      links_count: 1, flags: 0,
+      block: [0; 15],
+      file_acl: 0,
+      extra_isize: 0,
+    };
+    update_inode(fs, &inode).unwrap();
+    inode
+  }
+
+  fn boundaries(block_size: u64) -> (u64, u64, u64, u64) {
+    let n = block_size / 4;
+    (12, 12 + n, 12 + n + n * n, 12 + n + n * n + n * n * n)
+  }
+
+  fn check_block_pos(block_size: u64) {
+    let fs = make_fs(block_size);
+    let n = block_size / 4;
+    let (level1, level2, level3, end) = boundaries(block_size);
+    assert_eq!(inode_block_to_pos(&fs, level1 - 1), BlockPos::Level0(11));
+    assert_eq!(inode_block_to_pos(&fs, level1), BlockPos::Level1(0));
+    assert_eq!(inode_block_to_pos(&fs, level2 - 1), BlockPos::Level1(n - 1));
+    assert_eq!(inode_block_to_pos(&fs, level2), BlockPos::Level2(0, 0));
+    assert_eq!(inode_block_to_pos(&fs, level3 - 1), BlockPos::Level2(n - 1, n - 1));
+    assert_eq!(inode_block_to_pos(&fs, level3), BlockPos::Level3(0, 0, 0));
+    assert_eq!(inode_block_to_pos(&fs, end - 1), BlockPos::Level3(n - 1, n - 1, n - 1));
+    assert_eq!(inode_block_to_pos(&fs, end), BlockPos::OutOfRange);
+  }
+
+  fn check_write_boundaries(block_size: u64) {
+    let mut fs = make_fs(block_size);
+    let mut inode = make_file(&mut fs);
+    let (level1, level2, level3, end) = boundaries(block_size);
+
+    let mut written = Vec::new();
+    for (i, &first) in [level1, level2, level3, end - 1].iter().enumerate() {
+      let offset = first * block_size - 2;
+      let data = [i as u8 + 1; 4];
+      let len = if first == end - 1 { 2 + block_size as usize } else { 4 };
+      let data: Vec<u8> = data.iter().cloned().cycle().take(len).collect();
+      assert_eq!(write_inode_data(&mut fs, &mut inode, offset, &data[..]).unwrap(), len as u64);
+      written.push((offset, data));
+    }
+    assert_eq!(inode.size, end * block_size);
+    assert!((fs.superblock.feature_ro_compat & RO_COMPAT_LARGE_FILE) != 0);
+    flush_ino(&mut fs, inode.ino).unwrap();
+    assert_eq!(get_inode(&mut fs, inode.ino).unwrap().size, end * block_size);
+
+    for &(offset, ref data) in written.iter() {
+      let mut buffer = make_buffer(data.len() as u64 + 4);
+      let length = read_inode_data(&fs, &inode, offset - 2, &mut buffer[..]).unwrap();
+      assert_eq!(length, buffer.len().min((inode.size - offset + 2) as usize) as u64);
+      assert_eq!(&buffer[..2], &[0, 0]);
+      assert_eq!(&buffer[2..2 + data.len()], &data[..]);
+    }
+
+    for &first in [level1, level2, level3].iter() {
+      let extents = map_range(&fs, &inode, first - 1, 2).unwrap();
+      assert_eq!(extents.iter().map(|e| e.length).sum::<u64>(), 2);
+      assert!(extents.iter().all(|e| e.block.is_some()));
+    }
+    let holes = map_range(&fs, &inode, level1 + 1, level2 - level1 - 2).unwrap();
+    assert_eq!(holes, vec![BlockExtent {
+      inode_block: level1 + 1, block: None, length: level2 - level1 - 2 }]);
+
+    let err = write_inode_data(&mut fs, &mut inode, end * block_size, b"x").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::FileTooLarge);
+    let err = write_inode_data(&mut fs, &mut inode, end * block_size - 1, b"xy").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::FileTooLarge);
+    let err = write_inode_data(&mut fs, &mut inode, u64::max_value() - 1, b"xy").unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert_eq!(inode.size, end * block_size);
+  }
+
+  #[test]
+  fn size_high_by_type() {
+    let mut fs = make_fs(1024);
+    let mut inode = make_file(&mut fs);
+    let mut bytes = make_buffer(128);
+    inode.size = (5 << 32) + 3;
+    for &file_type in [FileType::Regular, FileType::Symlink, FileType::Fifo].iter() {
+      inode.mode.file_type = file_type;
+      encode_inode(&fs.superblock, &inode, &mut bytes[..]).unwrap();
+      assert_eq!(decode_inode(&fs.superblock, inode.ino, &bytes[..]).unwrap().size, inode.size);
+    }
+
+    inode.mode.file_type = FileType::Dir;
+    assert!(encode_inode(&fs.superblock, &inode, &mut bytes[..]).is_err());
+    assert_eq!(decode_inode(&fs.superblock, inode.ino, &bytes[..]).unwrap().size, 3);
+    fs.superblock.feature_incompat |= INCOMPAT_LARGE_DIR;
+    encode_inode(&fs.superblock, &inode, &mut bytes[..]).unwrap();
+    assert_eq!(decode_inode(&fs.superblock, inode.ino, &bytes[..]).unwrap().size, inode.size);
+  }
+
+  #[test]
+  fn block_pos_1k() {
+    check_block_pos(1024);
+  }
+
+  #[test]
+  fn block_pos_4k() {
+    check_block_pos(4096);
+  }
+
+  #[test]
+  fn write_boundaries_1k() {
+    check_write_boundaries(1024);
+  }
+
+  #[test]
+  fn write_boundaries_4k() {
+    check_write_boundaries(4096);
   }
 }
@@ -1,27 +1,59 @@
+extern crate libc;
+
 pub use defs::*;
-pub use error::{Error, Result};
+pub use error::{Error, ErrorKind, Result};
 pub use volume::{Volume, FileVolume};
-pub use fs::{Filesystem, ROOT_INO, mount_fs, flush_fs};
+pub use alloc::{alloc_extent, free_extents, largest_free_run};
+pub use block_map::{BlockMap, BlockExtent, FileExtents, MetadataBlock, map_range,
+  map_range_with, file_extents};
+pub use clock::{Clock, SystemClock, FixedClock};
+pub use fs::{Filesystem, MountOptions, CacheConfig, CacheStats, ReadaheadConfig,
+  Reproducible, ROOT_INO, mount_fs, mount_fs_with_options, flush_fs, cache_stats, StatFs, statfs};
 pub use inode::{get_inode, set_inode_mode_attr, truncate_inode_size,
-  inode_mode_from_linux_mode };
-pub use make_in_dir::{make_inode_in_dir, make_symlink_in_dir, make_hardlink_in_dir};
-pub use dir::{DirHandle, lookup_in_dir, remove_from_dir, move_between_dirs,
-  open_dir, read_dir, close_dir};
-pub use file::{FileHandle, open_file, read_file, write_file, close_file};
+  inode_mode_from_linux_mode, pin_inode, unpin_inode, evict_inode};
+pub use make_in_dir::{make_inode_in_dir, make_device_in_dir, make_symlink_in_dir,
+  make_hardlink_in_dir};
+pub use dir::{DirHandle, DirLine, lookup_in_dir, remove_from_dir, unlink_in_dir,
+  rmdir_in_dir, move_between_dirs, rename_in_dirs, open_dir, read_dir, close_dir,
+  sort_dir, compact_dir};
+pub use file::{FileHandle, open_file, read_file, write_file, close_file, sync_file};
+pub use frag::{Fragmentation, inode_fragmentation, fs_fragmentation};
+pub use defrag::{DefragOptions, DefragReport, defrag_file, defrag_fs};
 pub use link::{read_link};
+pub use shared::{SharedFilesystem};
+pub use quota::{Quota, QuotaKind, QuotaLimits, get_quota, quota_ids, set_quota_limits,
+  set_quota_grace};
+pub use perm::{Credentials, ACCESS_READ, ACCESS_WRITE, ACCESS_EXEC, check_access};
+pub use async_fs::{AsyncVolume, VolumeFuture, AsyncFilesystem, mount_fs_async,
+  lookup_in_dir_async, open_file_async, read_file_async, write_file_async,
+  close_file_async, open_dir_async, read_dir_async, flush_fs_async};
 
 mod alloc;
+mod async_fs;
+mod bitmap;
+mod block_map;
+mod clock;
+mod dcache;
 mod decode;
+mod defrag;
 mod defs;
 mod dir;
 mod encode;
 mod error;
 mod file;
+mod frag;
 mod fs;
 mod group;
 mod inode;
 mod inode_data;
 mod make_in_dir;
 mod link;
+mod lru;
+mod orphan;
+mod perm;
 mod prelude;
+mod quota;
+mod shared;
+#[cfg(test)]
+mod test_fs;
 mod volume;
@@ -4,9 +4,11 @@ use prelude::*;
 pub fn read_link(fs: &mut Filesystem, ino: u64) -> Result<Vec<u8>> {
   let inode = try!(get_inode(fs, ino));
   if inode.mode.file_type == FileType::Symlink {
-    read_link_data(fs, &inode)
+    let data = try!(read_link_data(fs, &inode));
+    try!(touch_accessed(fs, ino));
+    Ok(data)
   } else {
-    Err(Error::new(format!("inode is not a symlink")))
+    Err(Error::of_kind(ErrorKind::InvalidInput, format!("inode is not a symlink")))
   }
 }
 
@@ -22,7 +24,7 @@ pub fn is_fast_symlink(fs: &Filesystem, inode: &Inode) -> bool {
   }
 }
 
-pub fn read_link_data(fs: &mut Filesystem, inode: &Inode) -> Result<Vec<u8>> {
+pub fn read_link_data(fs: &Filesystem, inode: &Inode) -> Result<Vec<u8>> {
   let mut buffer = make_buffer(inode.size + 4);
 
   let length = 
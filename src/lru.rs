@@ -0,0 +1,43 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash};
+
+pub struct Lru<K> {
+  ticks: HashMap<K, u64>,
+  order: BTreeMap<u64, K>,
+  next_tick: u64,
+}
+
+impl<K: Hash + Eq + Clone> Lru<K> {
+  pub fn new() -> Lru<K> {
+    Lru { ticks: HashMap::new(), order: BTreeMap::new(), next_tick: 0 }
+  }
+
+  pub fn touch(&mut self, key: K) {
+    let tick = self.next_tick;
+    self.next_tick += 1;
+    if let Some(old_tick) = self.ticks.insert(key.clone(), tick) {
+      self.order.remove(&old_tick);
+    }
+    self.order.insert(tick, key);
+  }
+
+  pub fn remove(&mut self, key: &K) -> bool {
+    match self.ticks.remove(key) {
+      Some(tick) => { self.order.remove(&tick); true },
+      None => false,
+    }
+  }
+
+  pub fn oldest<F: Fn(&K) -> bool>(&self, evictable: F) -> Option<K> {
+    self.order.values().find(|key| evictable(key)).cloned()
+  }
+
+  pub fn len(&self) -> usize {
+    self.ticks.len()
+  }
+
+  pub fn clear(&mut self) {
+    self.ticks.clear();
+    self.order.clear();
+  }
+}
@@ -1,26 +1,54 @@
 use prelude::*;
 
-pub fn make_inode_in_dir(fs: &mut Filesystem, dir_ino: u64,
+pub fn make_inode_in_dir(fs: &mut Filesystem, creds: &Credentials, dir_ino: u64,
   name: &[u8], mode: Mode, attr: FileAttr) -> Result<Inode>
 {
   let mut dir_inode = try!(get_inode(fs, dir_ino));
   if dir_inode.mode.file_type != FileType::Dir {
-    return Err(Error::new(format!(
+    return Err(Error::of_kind(ErrorKind::NotDir, format!(
       "Inode {} is not a directory", dir_ino)));
   }
+  try!(check_access(creds, &dir_inode, ACCESS_WRITE | ACCESS_EXEC));
 
-  let dir_group = get_ino_group(fs, dir_ino).0;
-  let new_ino = match try!(alloc_inode(fs, dir_group)) {
-    None => return Err(Error::new(format!("No free inodes left"))),
+  let new_ino = if mode.file_type == FileType::Dir {
+    try!(alloc_dir_inode(fs, dir_ino, name))
+  } else {
+    let dir_group = get_ino_group(fs, dir_ino).0;
+    try!(alloc_inode(fs, dir_group))
+  };
+
+  let new_ino = match new_ino {
+    None => return Err(Error::of_kind(ErrorKind::NoSpace,
+      format!("No free inodes left"))),
     Some(ino) => ino,
   };
 
-  let mut new_inode = try!(init_inode(fs, &mut dir_inode, new_ino, mode, attr));
-  try!(add_dir_entry(fs, &mut dir_inode, &mut new_inode, name));
-  Ok(new_inode)
+  with_reserved_access(fs, creds, |fs| {
+    let mut new_inode = try!(init_inode(fs, creds, &mut dir_inode, new_ino, mode, attr));
+    try!(add_dir_entry(fs, &mut dir_inode, &mut new_inode, name));
+    Ok(new_inode)
+  })
+}
+
+pub fn make_device_in_dir(fs: &mut Filesystem, creds: &Credentials, dir_ino: u64,
+  name: &[u8], mode: Mode, rdev: u32, attr: FileAttr) -> Result<Inode>
+{
+  match mode.file_type {
+    FileType::CharDev | FileType::BlockDev => (),
+    FileType::Fifo | FileType::Socket if rdev == 0 => (),
+    file_type => return Err(Error::of_kind(ErrorKind::InvalidInput, format!(
+      "Cannot make device {:x} with file type {:?}", rdev, file_type))),
+  }
+
+  let mut inode = try!(make_inode_in_dir(fs, creds, dir_ino, name, mode, attr));
+  if rdev != 0 {
+    inode.set_rdev(rdev);
+    try!(update_inode(fs, &inode));
+  }
+  Ok(inode)
 }
 
-pub fn make_symlink_in_dir(fs: &mut Filesystem, dir_ino: u64,
+pub fn make_symlink_in_dir(fs: &mut Filesystem, creds: &Credentials, dir_ino: u64,
   name: &[u8], link: &[u8], attr: FileAttr) -> Result<Inode>
 {
   let mode = Mode {
@@ -28,24 +56,29 @@ pub fn make_symlink_in_dir(fs: &mut Filesystem, dir_ino: u64,
     suid: false, sgid: false, sticky: false,
     access_rights: 0o777,
   };
-  let mut inode = try!(make_inode_in_dir(fs, dir_ino, name, mode, attr));
-  try!(write_link_data(fs, &mut inode, link));
+  let mut inode = try!(make_inode_in_dir(fs, creds, dir_ino, name, mode, attr));
+  try!(with_reserved_access(fs, creds, |fs|
+    write_link_data(fs, &mut inode, link)));
   Ok(inode)
 }
 
-pub fn make_hardlink_in_dir(fs: &mut Filesystem, dir_ino: u64,
+pub fn make_hardlink_in_dir(fs: &mut Filesystem, creds: &Credentials, dir_ino: u64,
   name: &[u8], link_ino: u64) -> Result<Inode>
 {
   let mut dir_inode = try!(get_inode(fs, dir_ino));
   let mut link_inode = try!(get_inode(fs, link_ino));
 
   if dir_inode.mode.file_type != FileType::Dir {
-    return Err(Error::new(format!("Inode {} is not a directory", dir_ino)));
+    return Err(Error::of_kind(ErrorKind::NotDir,
+      format!("Inode {} is not a directory", dir_ino)));
   } else if link_inode.mode.file_type == FileType::Dir {
-    return Err(Error::new(format!("Inode {} is a directory", link_ino)));
+    return Err(Error::of_kind(ErrorKind::NotPermitted,
+      format!("Inode {} is a directory", link_ino)));
   }
+  try!(check_access(creds, &dir_inode, ACCESS_WRITE | ACCESS_EXEC));
 
-  try!(add_dir_entry(fs, &mut dir_inode, &mut link_inode, name));
+  try!(with_reserved_access(fs, creds, |fs|
+    add_dir_entry(fs, &mut dir_inode, &mut link_inode, name)));
   Ok(link_inode)
 }
 
@@ -0,0 +1,99 @@
+use prelude::*;
+
+// rev 0 superblocks have no s_last_orphan, so there the head of the chain only
+// lives in memory and a crash leaves the unlinked inodes for e2fsck to free
+pub fn add_orphan(fs: &mut Filesystem, inode: &mut Inode) -> Result<()> {
+  inode.attr.dtime = fs.superblock.last_orphan;
+  fs.superblock.last_orphan = inode.ino as u32;
+  fs.superblock_dirty = true;
+  update_inode(fs, inode)
+}
+
+pub fn remove_orphan(fs: &mut Filesystem, inode: &mut Inode) -> Result<()> {
+  let next_ino = inode.attr.dtime;
+  inode.attr.dtime = 0;
+  try!(update_inode(fs, inode));
+
+  if fs.superblock.last_orphan as u64 == inode.ino {
+    fs.superblock.last_orphan = next_ino;
+    fs.superblock_dirty = true;
+    return Ok(())
+  }
+
+  let mut prev_ino = fs.superblock.last_orphan as u64;
+  while prev_ino != 0 {
+    try!(check_orphan_ino(fs, prev_ino));
+    let mut prev_inode = try!(get_inode(fs, prev_ino));
+    if prev_inode.attr.dtime as u64 == inode.ino {
+      prev_inode.attr.dtime = next_ino;
+      return update_inode(fs, &prev_inode);
+    }
+    prev_ino = prev_inode.attr.dtime as u64;
+  }
+
+  Err(Error::new(format!("Inode {} is not in the orphan list", inode.ino)))
+}
+
+pub fn process_orphans(fs: &mut Filesystem) -> Result<()> {
+  while fs.superblock.last_orphan != 0 {
+    let ino = fs.superblock.last_orphan as u64;
+    try!(check_orphan_ino(fs, ino));
+    let mut inode = try!(get_inode(fs, ino));
+    fs.superblock.last_orphan = inode.attr.dtime;
+    fs.superblock_dirty = true;
+
+    inode.attr.dtime = 0;
+    if inode.links_count == 0 {
+      try!(remove_inode(fs, &mut inode));
+    }
+    try!(update_inode(fs, &inode));
+  }
+  Ok(())
+}
+
+fn check_orphan_ino(fs: &Filesystem, ino: u64) -> Result<()> {
+  let inodes_count = fs.superblock.inodes_per_group as u64 * fs.group_count();
+  if ino < fs.superblock.first_ino as u64 || ino > inodes_count {
+    return Err(Error::new(format!("Orphan list contains invalid inode {}", ino)))
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use prelude::*;
+  use test_fs::{make_volume, mount, make_file};
+
+  #[test]
+  fn orphans_freed_after_crash() {
+    let volume = make_volume(1024, 1, 128);
+    let mut fs = mount(&volume);
+    let root = Credentials::root();
+    let free_before = statfs(&fs);
+
+    let ino = make_file(&mut fs, ROOT_INO, b"log").ino;
+    let mut handle = open_file(&mut fs, &root, ino, ACCESS_READ | ACCESS_WRITE).unwrap();
+    write_file(&mut fs, &root, &mut handle, 0, &[7; 20 * 1024][..]).unwrap();
+    assert!(unlink_in_dir(&mut fs, &root, ROOT_INO, b"log").unwrap());
+    assert_eq!(fs.superblock.last_orphan as u64, ino);
+
+    let mut buffer = [0; 4];
+    assert_eq!(read_file(&mut fs, &mut handle, 20 * 1024 - 4, &mut buffer[..]).unwrap(), 4);
+    assert_eq!(buffer, [7; 4]);
+    flush_fs(&mut fs).unwrap();
+    assert_eq!(decode_u32(&volume.image()[1024 + 232..]) as u64, ino);
+    drop(fs);
+
+    let mut fs = mount(&volume);
+    assert_eq!(fs.superblock.last_orphan, 0);
+    let free_after = statfs(&fs);
+    assert_eq!(free_after.free_blocks, free_before.free_blocks);
+    assert_eq!(free_after.free_inodes, free_before.free_inodes);
+    let (group_idx, local_idx) = get_ino_group(&fs, ino);
+    assert!(!get_bit_in_bitmap(&lock_group(&fs, group_idx).inode_bitmap[..], local_idx));
+    assert!(get_inode(&mut fs, ino).unwrap().attr.dtime != 0);
+
+    flush_fs(&mut fs).unwrap();
+    assert_eq!(decode_u32(&volume.image()[1024 + 232..]), 0);
+  }
+}
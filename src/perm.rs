@@ -0,0 +1,78 @@
+use prelude::*;
+
+pub const ACCESS_READ: u16 = 4;
+pub const ACCESS_WRITE: u16 = 2;
+pub const ACCESS_EXEC: u16 = 1;
+
+#[derive(Debug, Clone)]
+pub struct Credentials {
+  pub uid: u32,
+  pub gid: u32,
+  pub groups: Vec<u32>,
+}
+
+impl Credentials {
+  pub fn root() -> Credentials {
+    Credentials { uid: 0, gid: 0, groups: Vec::new() }
+  }
+
+  pub fn is_root(&self) -> bool {
+    self.uid == 0
+  }
+
+  pub fn in_group(&self, gid: u32) -> bool {
+    self.gid == gid || self.groups.contains(&gid)
+  }
+}
+
+pub fn check_access(creds: &Credentials, inode: &Inode, access: u16) -> Result<()> {
+  if creds.is_root() {
+    if access & ACCESS_EXEC == 0 || inode.mode.file_type == FileType::Dir ||
+      inode.mode.access_rights & 0o111 != 0
+    {
+      return Ok(())
+    }
+  } else {
+    let rights = if creds.uid == inode.attr.uid {
+      inode.mode.access_rights >> 6
+    } else if creds.in_group(inode.attr.gid) {
+      inode.mode.access_rights >> 3
+    } else {
+      inode.mode.access_rights
+    };
+    if rights & access == access {
+      return Ok(())
+    }
+  }
+
+  Err(Error::of_kind(ErrorKind::PermissionDenied, format!(
+    "Access {:o} to inode {} denied for uid {}", access, inode.ino, creds.uid)))
+}
+
+pub fn check_sticky(creds: &Credentials, dir_inode: &Inode, inode: &Inode) -> Result<()> {
+  if dir_inode.mode.sticky && !creds.is_root() &&
+    creds.uid != dir_inode.attr.uid && creds.uid != inode.attr.uid
+  {
+    return Err(Error::of_kind(ErrorKind::NotPermitted, format!(
+      "Cannot remove inode {} from sticky directory {} as uid {}",
+      inode.ino, dir_inode.ino, creds.uid)))
+  }
+  Ok(())
+}
+
+pub fn check_owner(creds: &Credentials, inode: &Inode) -> Result<()> {
+  if !creds.is_root() && creds.uid != inode.attr.uid {
+    return Err(Error::of_kind(ErrorKind::NotPermitted, format!(
+      "Uid {} does not own inode {}", creds.uid, inode.ino)))
+  }
+  Ok(())
+}
+
+pub fn kill_privileges(inode: &mut Inode) {
+  if inode.mode.file_type != FileType::Dir {
+    inode.mode.suid = false;
+    if inode.mode.access_rights & 0o010 != 0 {
+      inode.mode.sgid = false;
+    }
+  }
+}
@@ -1,14 +1,23 @@
 pub use alloc::*;
+pub use bitmap::*;
+pub use block_map::*;
+pub use clock::*;
+pub use dcache::*;
 pub use decode::*;
 pub use defs::*;
 pub use dir::*;
 pub use encode::*;
-pub use error::{Error, Result};
+pub use error::{Error, ErrorKind, Result};
 pub use file::*;
+pub use frag::*;
 pub use fs::*;
 pub use group::*;
 pub use inode::*;
 pub use inode_data::*;
 pub use link::*;
+pub use lru::*;
 pub use make_in_dir::*;
+pub use orphan::*;
+pub use perm::*;
+pub use quota::*;
 pub use volume::{Volume};
@@ -0,0 +1,583 @@
+use std::collections::{HashMap, HashSet};
+use prelude::*;
+
+const QUOTA_BLOCK_SIZE: usize = 1024;
+const QUOTA_TREE_DEPTH: usize = 4;
+const QUOTA_TREE_ROOT: usize = 1;
+const QUOTA_DATA_HEADER_SIZE: usize = 16;
+const QUOTA_INFO_OFFSET: usize = 8;
+const USER_QUOTA_MAGIC: u32 = 0xd9c01f11;
+const GROUP_QUOTA_MAGIC: u32 = 0xd9c01927;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum QuotaKind {
+  User,
+  Group,
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct Quota {
+  pub space_used: u64,
+  pub inodes_used: u64,
+  pub space_soft_limit: u64,
+  pub space_hard_limit: u64,
+  pub inode_soft_limit: u64,
+  pub inode_hard_limit: u64,
+  pub space_grace_end: i64,
+  pub inode_grace_end: i64,
+}
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct QuotaLimits {
+  pub space_soft_limit: u64,
+  pub space_hard_limit: u64,
+  pub inode_soft_limit: u64,
+  pub inode_hard_limit: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct QuotaFile {
+  pub ino: u64,
+  pub version: u32,
+  pub space_grace: u32,
+  pub inode_grace: u32,
+  pub flags: u32,
+  pub quotas: HashMap<u32, Quota>,
+  pub dirty: bool,
+}
+
+pub fn get_quota(fs: &mut Filesystem, kind: QuotaKind, id: u32) -> Result<Quota> {
+  let file = try!(enabled_quota_file(fs, kind));
+  Ok(file.quotas.get(&id).cloned().unwrap_or_default())
+}
+
+pub fn quota_ids(fs: &mut Filesystem, kind: QuotaKind) -> Result<Vec<u32>> {
+  let file = try!(enabled_quota_file(fs, kind));
+  let mut ids: Vec<u32> = file.quotas.keys().cloned().collect();
+  ids.sort();
+  Ok(ids)
+}
+
+pub fn set_quota_limits(fs: &mut Filesystem, kind: QuotaKind, id: u32,
+  limits: QuotaLimits) -> Result<()>
+{
+  let now = fs.now().sec;
+  let file = try!(enabled_quota_file(fs, kind));
+  let (space_grace, inode_grace) = (file.space_grace, file.inode_grace);
+  let quota = file.quotas.entry(id).or_insert(Quota::default());
+  quota.space_soft_limit = limits.space_soft_limit;
+  quota.space_hard_limit = limits.space_hard_limit;
+  quota.inode_soft_limit = limits.inode_soft_limit;
+  quota.inode_hard_limit = limits.inode_hard_limit;
+  update_grace(quota, now, space_grace, inode_grace);
+  file.dirty = true;
+  Ok(())
+}
+
+pub fn set_quota_grace(fs: &mut Filesystem, kind: QuotaKind,
+  space_grace: u32, inode_grace: u32) -> Result<()>
+{
+  let file = try!(enabled_quota_file(fs, kind));
+  file.space_grace = space_grace;
+  file.inode_grace = inode_grace;
+  file.dirty = true;
+  Ok(())
+}
+
+pub fn charge_quota(fs: &mut Filesystem, inode: &Inode,
+  space: u64, inodes: u64) -> Result<()>
+{
+  if is_quota_ino(fs, inode.ino) {
+    return Ok(())
+  }
+
+  let now = fs.now().sec;
+  let ids = [(QuotaKind::User, inode.attr.uid), (QuotaKind::Group, inode.attr.gid)];
+  let enforce = !fs.ignore_quota;
+  for &(kind, id) in ids.iter() {
+    if let Some(ref file) = *quota_file(fs, kind) {
+      if enforce {
+        try!(check_quota(file, kind, id, space, inodes, now));
+      }
+    }
+  }
+  for &(kind, id) in ids.iter() {
+    if let Some(ref mut file) = *quota_file(fs, kind) {
+      add_usage(file, id, space, inodes, now);
+    }
+  }
+  Ok(())
+}
+
+pub fn release_quota(fs: &mut Filesystem, inode: &Inode, space: u64, inodes: u64) {
+  if is_quota_ino(fs, inode.ino) {
+    return
+  }
+
+  let ids = [(QuotaKind::User, inode.attr.uid), (QuotaKind::Group, inode.attr.gid)];
+  for &(kind, id) in ids.iter() {
+    if let Some(ref mut file) = *quota_file(fs, kind) {
+      sub_usage(file, id, space, inodes);
+    }
+  }
+}
+
+pub fn transfer_quota(fs: &mut Filesystem, inode: &Inode,
+  new_uid: u32, new_gid: u32) -> Result<()>
+{
+  if is_quota_ino(fs, inode.ino) {
+    return Ok(())
+  }
+
+  let now = fs.now().sec;
+  let space = inode.size_512 * 512;
+  let moves = [
+    (QuotaKind::User, inode.attr.uid, new_uid),
+    (QuotaKind::Group, inode.attr.gid, new_gid),
+  ];
+  let enforce = !fs.ignore_quota;
+  for &(kind, old_id, new_id) in moves.iter() {
+    if old_id == new_id || !enforce {
+      continue
+    }
+    if let Some(ref file) = *quota_file(fs, kind) {
+      try!(check_quota(file, kind, new_id, space, 1, now));
+    }
+  }
+  for &(kind, old_id, new_id) in moves.iter() {
+    if old_id == new_id {
+      continue
+    }
+    if let Some(ref mut file) = *quota_file(fs, kind) {
+      sub_usage(file, old_id, space, 1);
+      add_usage(file, new_id, space, 1, now);
+    }
+  }
+  Ok(())
+}
+
+pub fn is_quota_ino(fs: &Filesystem, ino: u64) -> bool {
+  fs.user_quota.as_ref().map_or(false, |file| file.ino == ino) ||
+    fs.group_quota.as_ref().map_or(false, |file| file.ino == ino)
+}
+
+pub fn load_quotas(fs: &mut Filesystem) -> Result<()> {
+  for &kind in [QuotaKind::User, QuotaKind::Group].iter() {
+    if let Some(ino) = try!(find_quota_ino(fs, kind)) {
+      let file = try!(read_quota_file(fs, kind, ino));
+      *quota_file(fs, kind) = Some(file);
+    }
+  }
+  Ok(())
+}
+
+pub fn flush_quotas(fs: &mut Filesystem) -> Result<()> {
+  for &kind in [QuotaKind::User, QuotaKind::Group].iter() {
+    let file = match *quota_file(fs, kind) {
+      Some(ref file) if file.dirty => file.clone(),
+      _ => continue,
+    };
+    try!(write_quota_file(fs, kind, &file));
+    if let Some(ref mut file) = *quota_file(fs, kind) {
+      file.dirty = false;
+    }
+  }
+  Ok(())
+}
+
+fn quota_file(fs: &mut Filesystem, kind: QuotaKind) -> &mut Option<QuotaFile> {
+  match kind {
+    QuotaKind::User => &mut fs.user_quota,
+    QuotaKind::Group => &mut fs.group_quota,
+  }
+}
+
+fn enabled_quota_file(fs: &mut Filesystem, kind: QuotaKind) -> Result<&mut QuotaFile> {
+  match *quota_file(fs, kind) {
+    Some(ref mut file) => Ok(file),
+    None => Err(Error::of_kind(ErrorKind::NotFound,
+      format!("{:?} quota is not enabled", kind))),
+  }
+}
+
+fn check_quota(file: &QuotaFile, kind: QuotaKind, id: u32,
+  space: u64, inodes: u64, now: i64) -> Result<()>
+{
+  let quota = file.quotas.get(&id).cloned().unwrap_or_default();
+  let space_exceeded = space > 0 && exceeds_limit(quota.space_used + space,
+    quota.space_soft_limit, quota.space_hard_limit, quota.space_grace_end, now);
+  let inodes_exceeded = inodes > 0 && exceeds_limit(quota.inodes_used + inodes,
+    quota.inode_soft_limit, quota.inode_hard_limit, quota.inode_grace_end, now);
+
+  if space_exceeded || inodes_exceeded {
+    return Err(Error::of_kind(ErrorKind::QuotaExceeded, format!(
+      "{:?} quota of id {} exceeded", kind, id)))
+  }
+  Ok(())
+}
+
+fn exceeds_limit(used: u64, soft_limit: u64, hard_limit: u64,
+  grace_end: i64, now: i64) -> bool
+{
+  (hard_limit != 0 && used > hard_limit) ||
+    (soft_limit != 0 && used > soft_limit && grace_end != 0 && now >= grace_end)
+}
+
+fn add_usage(file: &mut QuotaFile, id: u32, space: u64, inodes: u64, now: i64) {
+  let (space_grace, inode_grace) = (file.space_grace, file.inode_grace);
+  let quota = file.quotas.entry(id).or_insert(Quota::default());
+  quota.space_used += space;
+  quota.inodes_used += inodes;
+  update_grace(quota, now, space_grace, inode_grace);
+  file.dirty = true;
+}
+
+fn sub_usage(file: &mut QuotaFile, id: u32, space: u64, inodes: u64) {
+  if let Some(quota) = file.quotas.get_mut(&id) {
+    quota.space_used = quota.space_used.saturating_sub(space);
+    quota.inodes_used = quota.inodes_used.saturating_sub(inodes);
+    if quota.space_soft_limit == 0 || quota.space_used <= quota.space_soft_limit {
+      quota.space_grace_end = 0;
+    }
+    if quota.inode_soft_limit == 0 || quota.inodes_used <= quota.inode_soft_limit {
+      quota.inode_grace_end = 0;
+    }
+    file.dirty = true;
+  }
+}
+
+fn update_grace(quota: &mut Quota, now: i64, space_grace: u32, inode_grace: u32) {
+  if quota.space_soft_limit != 0 && quota.space_used > quota.space_soft_limit {
+    if quota.space_grace_end == 0 {
+      quota.space_grace_end = now + space_grace as i64;
+    }
+  } else {
+    quota.space_grace_end = 0;
+  }
+
+  if quota.inode_soft_limit != 0 && quota.inodes_used > quota.inode_soft_limit {
+    if quota.inode_grace_end == 0 {
+      quota.inode_grace_end = now + inode_grace as i64;
+    }
+  } else {
+    quota.inode_grace_end = 0;
+  }
+}
+
+fn find_quota_ino(fs: &mut Filesystem, kind: QuotaKind) -> Result<Option<u64>> {
+  if fs.superblock.feature_ro_compat & RO_COMPAT_QUOTA != 0 {
+    let ino = match kind {
+      QuotaKind::User => fs.superblock.usr_quota_inum,
+      QuotaKind::Group => fs.superblock.grp_quota_inum,
+    };
+    return Ok(if ino != 0 { Some(ino as u64) } else { None })
+  }
+
+  if fs.options.quota {
+    let name: &[u8] = match kind {
+      QuotaKind::User => b"aquota.user",
+      QuotaKind::Group => b"aquota.group",
+    };
+    return lookup_in_dir(fs, &Credentials::root(), ROOT_INO, name)
+  }
+  Ok(None)
+}
+
+fn quota_magic(kind: QuotaKind) -> u32 {
+  match kind {
+    QuotaKind::User => USER_QUOTA_MAGIC,
+    QuotaKind::Group => GROUP_QUOTA_MAGIC,
+  }
+}
+
+fn quota_entry_size(version: u32) -> usize {
+  if version == 0 { 48 } else { 72 }
+}
+
+fn read_quota_file(fs: &mut Filesystem, kind: QuotaKind, ino: u64) -> Result<QuotaFile> {
+  let inode = try!(get_inode(fs, ino));
+  let mut bytes = make_buffer(inode.size);
+  try!(read_inode_data(fs, &inode, 0, &mut bytes[..]));
+
+  if bytes.len() < 2 * QUOTA_BLOCK_SIZE {
+    return Err(Error::new(format!("Quota file {} is too short", ino)))
+  }
+  let magic = decode_u32(&bytes[0..]);
+  let version = decode_u32(&bytes[4..]);
+  if magic != quota_magic(kind) {
+    return Err(Error::new(format!(
+      "Bad magic 0x{:x} of {:?} quota file {}", magic, kind, ino)))
+  } else if version > 1 {
+    return Err(Error::new(format!(
+      "Unsupported version {} of quota file {}", version, ino)))
+  }
+
+  let info = &bytes[QUOTA_INFO_OFFSET..];
+  let mut file = QuotaFile {
+    ino: ino,
+    version: version,
+    space_grace: decode_u32(&info[0..]),
+    inode_grace: decode_u32(&info[4..]),
+    flags: decode_u32(&info[8..]),
+    quotas: HashMap::new(),
+    dirty: false,
+  };
+
+  let mut data_blocks = HashSet::new();
+  try!(read_quota_tree(&bytes[..], QUOTA_TREE_ROOT, 0, &mut data_blocks));
+  let entry_size = quota_entry_size(version);
+  for &block in data_blocks.iter() {
+    let data = &bytes[block * QUOTA_BLOCK_SIZE..(block + 1) * QUOTA_BLOCK_SIZE];
+    let mut offset = QUOTA_DATA_HEADER_SIZE;
+    while offset + entry_size <= QUOTA_BLOCK_SIZE {
+      let entry = &data[offset..offset + entry_size];
+      if entry.iter().any(|&b| b != 0) {
+        let (id, quota) = decode_quota_entry(version, entry);
+        file.quotas.insert(id, quota);
+      }
+      offset += entry_size;
+    }
+  }
+  Ok(file)
+}
+
+fn read_quota_tree(bytes: &[u8], block: usize, depth: usize,
+  data_blocks: &mut HashSet<usize>) -> Result<()>
+{
+  if (block + 1) * QUOTA_BLOCK_SIZE > bytes.len() {
+    return Err(Error::new(format!("Quota tree block {} is out of the file", block)))
+  }
+
+  for i in 0..QUOTA_BLOCK_SIZE / 4 {
+    let child = decode_u32(&bytes[block * QUOTA_BLOCK_SIZE + i * 4..]) as usize;
+    if child == 0 {
+      continue
+    } else if depth + 1 < QUOTA_TREE_DEPTH {
+      try!(read_quota_tree(bytes, child, depth + 1, data_blocks));
+    } else if (child + 1) * QUOTA_BLOCK_SIZE <= bytes.len() {
+      data_blocks.insert(child);
+    } else {
+      return Err(Error::new(format!("Quota data block {} is out of the file", child)))
+    }
+  }
+  Ok(())
+}
+
+fn decode_quota_entry(version: u32, entry: &[u8]) -> (u32, Quota) {
+  let id = decode_u32(&entry[0..]);
+  let mut quota = if version == 0 {
+    Quota {
+      inode_hard_limit: decode_u32(&entry[4..]) as u64,
+      inode_soft_limit: decode_u32(&entry[8..]) as u64,
+      inodes_used: decode_u32(&entry[12..]) as u64,
+      space_hard_limit: decode_u32(&entry[16..]) as u64 * QUOTA_BLOCK_SIZE as u64,
+      space_soft_limit: decode_u32(&entry[20..]) as u64 * QUOTA_BLOCK_SIZE as u64,
+      space_used: decode_u64(&entry[24..]),
+      space_grace_end: decode_u64(&entry[32..]) as i64,
+      inode_grace_end: decode_u64(&entry[40..]) as i64,
+    }
+  } else {
+    Quota {
+      inode_hard_limit: decode_u64(&entry[8..]),
+      inode_soft_limit: decode_u64(&entry[16..]),
+      inodes_used: decode_u64(&entry[24..]),
+      space_hard_limit: decode_u64(&entry[32..]) * QUOTA_BLOCK_SIZE as u64,
+      space_soft_limit: decode_u64(&entry[40..]) * QUOTA_BLOCK_SIZE as u64,
+      space_used: decode_u64(&entry[48..]),
+      space_grace_end: decode_u64(&entry[56..]) as i64,
+      inode_grace_end: decode_u64(&entry[64..]) as i64,
+    }
+  };
+
+  // an otherwise empty entry is marked with an inode grace time of 1 so that
+  // it is not mistaken for an unused slot
+  let unmarked = Quota { inode_grace_end: 0, .. quota };
+  if quota.inode_grace_end == 1 && unmarked == Quota::default() {
+    quota.inode_grace_end = 0;
+  }
+  (id, quota)
+}
+
+fn encode_quota_entry(version: u32, id: u32, quota: &Quota, entry: &mut [u8]) {
+  let block_size = QUOTA_BLOCK_SIZE as u64;
+  let space_hard_limit = (quota.space_hard_limit + block_size - 1) / block_size;
+  let space_soft_limit = (quota.space_soft_limit + block_size - 1) / block_size;
+  encode_u32(id, &mut entry[0..]);
+  if version == 0 {
+    let clamp = |value: u64| if value > 0xffffffff { 0xffffffff } else { value as u32 };
+    encode_u32(clamp(quota.inode_hard_limit), &mut entry[4..]);
+    encode_u32(clamp(quota.inode_soft_limit), &mut entry[8..]);
+    encode_u32(clamp(quota.inodes_used), &mut entry[12..]);
+    encode_u32(clamp(space_hard_limit), &mut entry[16..]);
+    encode_u32(clamp(space_soft_limit), &mut entry[20..]);
+    encode_u64(quota.space_used, &mut entry[24..]);
+    encode_u64(quota.space_grace_end as u64, &mut entry[32..]);
+    encode_u64(quota.inode_grace_end as u64, &mut entry[40..]);
+  } else {
+    encode_u64(quota.inode_hard_limit, &mut entry[8..]);
+    encode_u64(quota.inode_soft_limit, &mut entry[16..]);
+    encode_u64(quota.inodes_used, &mut entry[24..]);
+    encode_u64(space_hard_limit, &mut entry[32..]);
+    encode_u64(space_soft_limit, &mut entry[40..]);
+    encode_u64(quota.space_used, &mut entry[48..]);
+    encode_u64(quota.space_grace_end as u64, &mut entry[56..]);
+    encode_u64(quota.inode_grace_end as u64, &mut entry[64..]);
+  }
+}
+
+fn write_quota_file(fs: &mut Filesystem, kind: QuotaKind, file: &QuotaFile) -> Result<()> {
+  let mut blocks = vec![vec![0; QUOTA_BLOCK_SIZE], vec![0; QUOTA_BLOCK_SIZE]];
+  let entry_size = quota_entry_size(file.version);
+  let entries_per_block = (QUOTA_BLOCK_SIZE - QUOTA_DATA_HEADER_SIZE) / entry_size;
+
+  let mut ids: Vec<u32> = file.quotas.iter()
+    .filter(|&(_, quota)| *quota != Quota::default())
+    .map(|(&id, _)| id).collect();
+  ids.sort();
+
+  let mut data_block = 0;
+  let mut data_entries = entries_per_block;
+  for &id in ids.iter() {
+    if data_entries == entries_per_block {
+      data_block = blocks.len();
+      data_entries = 0;
+      blocks.push(vec![0; QUOTA_BLOCK_SIZE]);
+    }
+    let offset = QUOTA_DATA_HEADER_SIZE + data_entries * entry_size;
+    encode_quota_entry(file.version, id, &file.quotas[&id],
+      &mut blocks[data_block][offset..offset + entry_size]);
+    data_entries += 1;
+    encode_u16(data_entries as u16, &mut blocks[data_block][8..]);
+
+    let mut tree_block = QUOTA_TREE_ROOT;
+    for depth in 0..QUOTA_TREE_DEPTH {
+      let ref_offset = ((id >> (8 * (QUOTA_TREE_DEPTH - 1 - depth))) & 0xff) as usize * 4;
+      if depth + 1 == QUOTA_TREE_DEPTH {
+        encode_u32(data_block as u32, &mut blocks[tree_block][ref_offset..]);
+      } else {
+        let mut child = decode_u32(&blocks[tree_block][ref_offset..]) as usize;
+        if child == 0 {
+          child = blocks.len();
+          blocks.push(vec![0; QUOTA_BLOCK_SIZE]);
+          encode_u32(child as u32, &mut blocks[tree_block][ref_offset..]);
+        }
+        tree_block = child;
+      }
+    }
+  }
+
+  let free_entry = if data_entries < entries_per_block { data_block } else { 0 };
+  let block_count = blocks.len();
+  {
+    let header = &mut blocks[0];
+    encode_u32(quota_magic(kind), &mut header[0..]);
+    encode_u32(file.version, &mut header[4..]);
+    let info = &mut header[QUOTA_INFO_OFFSET..];
+    encode_u32(file.space_grace, &mut info[0..]);
+    encode_u32(file.inode_grace, &mut info[4..]);
+    encode_u32(file.flags, &mut info[8..]);
+    encode_u32(block_count as u32, &mut info[12..]);
+    encode_u32(0, &mut info[16..]);
+    encode_u32(free_entry as u32, &mut info[20..]);
+  }
+
+  let bytes: Vec<u8> = blocks.concat();
+  let mut inode = try!(get_inode(fs, file.ino));
+  try!(write_inode_data(fs, &mut inode, 0, &bytes[..]));
+  if inode.size > bytes.len() as u64 {
+    let used_blocks = (bytes.len() as u64 + fs.block_size() - 1) / fs.block_size();
+    try!(truncate_inode_blocks(fs, &mut inode, used_blocks));
+    inode.size = bytes.len() as u64;
+    try!(update_inode(fs, &inode));
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use prelude::*;
+  use super::{QUOTA_BLOCK_SIZE, QUOTA_INFO_OFFSET, USER_QUOTA_MAGIC};
+  use test_fs::{MemVolume, make_volume, mount, mount_with_options, mode, make_file, make_dir};
+
+  fn mount_with_quota(volume: &MemVolume) -> Filesystem {
+    mount_with_options(volume, MountOptions { quota: true, .. MountOptions::default() })
+  }
+
+  fn make_quota_volume() -> MemVolume {
+    let volume = make_volume(1024, 1, 128);
+    let mut fs = mount(&volume);
+    let mut inode = make_file(&mut fs, ROOT_INO, b"aquota.user");
+    let mut header = vec![0; 2 * QUOTA_BLOCK_SIZE];
+    encode_u32(USER_QUOTA_MAGIC, &mut header[0..]);
+    encode_u32(1, &mut header[4..]);
+    encode_u32(2, &mut header[QUOTA_INFO_OFFSET + 12..]);
+    write_inode_data(&mut fs, &mut inode, 0, &header[..]).unwrap();
+    update_inode(&mut fs, &inode).unwrap();
+    flush_fs(&mut fs).unwrap();
+    volume
+  }
+
+  #[test]
+  fn limits_survive_remount() {
+    let volume = make_quota_volume();
+    let mut fs = mount_with_quota(&volume);
+    assert_eq!(quota_ids(&mut fs, QuotaKind::User).unwrap(), Vec::<u32>::new());
+    assert_eq!(get_quota(&mut fs, QuotaKind::Group, 0).unwrap_err().kind(),
+      ErrorKind::NotFound);
+
+    // more ids than fit in one data block, spread over different tree branches
+    let ids: Vec<u32> = (0..40).map(|i| (i % 3) << 24 | (i % 5) << 12 | i).collect();
+    for &id in ids.iter() {
+      set_quota_limits(&mut fs, QuotaKind::User, id, QuotaLimits {
+        space_soft_limit: id as u64 * 1024,
+        space_hard_limit: id as u64 * 2048,
+        inode_soft_limit: 10,
+        inode_hard_limit: id as u64 + 20,
+      }).unwrap();
+    }
+    flush_fs(&mut fs).unwrap();
+
+    let mut fs = mount_with_quota(&volume);
+    let mut sorted_ids = ids.clone();
+    sorted_ids.sort();
+    assert_eq!(quota_ids(&mut fs, QuotaKind::User).unwrap(), sorted_ids);
+    for &id in ids.iter() {
+      let quota = get_quota(&mut fs, QuotaKind::User, id).unwrap();
+      assert_eq!(quota.space_soft_limit, id as u64 * 1024);
+      assert_eq!(quota.space_hard_limit, id as u64 * 2048);
+      assert_eq!(quota.inode_soft_limit, 10);
+      assert_eq!(quota.inode_hard_limit, id as u64 + 20);
+    }
+  }
+
+  #[test]
+  fn hard_limit_enforced_after_remount() {
+    let volume = make_quota_volume();
+    let mut fs = mount_with_quota(&volume);
+    let user = Credentials { uid: 1000, gid: 1000, groups: Vec::new() };
+    let dir = make_dir(&mut fs, ROOT_INO, b"home");
+    let attr = FileAttr { uid: 1000, gid: 1000, .. dir.attr };
+    set_inode_mode_attr(&mut fs, &Credentials::root(), dir.ino,
+      mode(FileType::Dir, 0o755), attr).unwrap();
+    set_quota_limits(&mut fs, QuotaKind::User, 1000, QuotaLimits {
+      inode_hard_limit: 2, .. QuotaLimits::default()
+    }).unwrap();
+    flush_fs(&mut fs).unwrap();
+
+    let mut fs = mount_with_quota(&volume);
+    assert_eq!(get_quota(&mut fs, QuotaKind::User, 1000).unwrap().inodes_used, 1);
+    make_inode_in_dir(&mut fs, &user, dir.ino, b"a",
+      mode(FileType::Regular, 0o644), attr).unwrap();
+    let free_inodes = statfs(&fs).free_inodes;
+    let err = make_inode_in_dir(&mut fs, &user, dir.ino, b"b",
+      mode(FileType::Regular, 0o644), attr).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::QuotaExceeded);
+    assert_eq!(statfs(&fs).free_inodes, free_inodes);
+    assert_eq!(get_quota(&mut fs, QuotaKind::User, 1000).unwrap().inodes_used, 2);
+
+    // root is not held to the limit, but is still charged
+    make_inode_in_dir(&mut fs, &Credentials::root(), dir.ino, b"b",
+      mode(FileType::Regular, 0o644), attr).unwrap();
+    assert_eq!(get_quota(&mut fs, QuotaKind::User, 1000).unwrap().inodes_used, 3);
+  }
+}
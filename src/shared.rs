@@ -0,0 +1,263 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{LockResult, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use prelude::*;
+
+const INODE_LOCK_STRIPES: usize = 64;
+
+type DentryMap = HashMap<(u64, Vec<u8>), Option<u64>>;
+
+// lock order: fs, then an inode stripe, then a group, then the dirty group
+// set and the caches.
+// everything that only holds fs for reading keeps its inode changes in
+// `inodes` and its allocations in `pending_blocks` until the next write()
+pub struct SharedFilesystem {
+  fs: RwLock<Filesystem>,
+  inodes: RwLock<HashMap<u64, SharedInode>>,
+  dentries: RwLock<DentryMap>,
+  inode_locks: Vec<RwLock<()>>,
+  pending_blocks: AtomicU64,
+  accessed_inos: Mutex<HashSet<u64>>,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct SharedInode {
+  inode: Inode,
+  dirty: bool,
+}
+
+impl SharedFilesystem {
+  pub fn new(fs: Filesystem) -> SharedFilesystem {
+    SharedFilesystem {
+      fs: RwLock::new(fs),
+      inodes: RwLock::new(HashMap::new()),
+      dentries: RwLock::new(HashMap::new()),
+      inode_locks: (0..INODE_LOCK_STRIPES).map(|_| RwLock::new(())).collect(),
+      pending_blocks: AtomicU64::new(0),
+      accessed_inos: Mutex::new(HashSet::new()),
+    }
+  }
+
+  pub fn into_inner(self) -> Result<Filesystem> {
+    drop(try!(self.write()));
+    lock(self.fs.into_inner())
+  }
+
+  pub fn write(&self) -> Result<RwLockWriteGuard<'_, Filesystem>> {
+    let mut fs = try!(lock(self.fs.write()));
+    try!(self.drain(&mut fs));
+    Ok(fs)
+  }
+
+  pub fn get_inode(&self, ino: u64) -> Result<Inode> {
+    let fs = try!(self.read());
+    self.peek(&fs, ino)
+  }
+
+  pub fn lookup_in_dir(&self, creds: &Credentials, dir_ino: u64,
+    name: &[u8]) -> Result<Option<u64>>
+  {
+    let fs = try!(self.read());
+    let dir_inode = try!(self.peek(&fs, dir_ino));
+    if dir_inode.mode.file_type != FileType::Dir {
+      return Err(Error::of_kind(ErrorKind::NotDir,
+        format!("inode {} is not a directory", dir_ino)))
+    }
+    try!(check_access(creds, &dir_inode, ACCESS_EXEC));
+
+    let key = (dir_ino, name.to_vec());
+    if let Some(&entry) = try!(lock(self.dentries.read())).get(&key) {
+      return Ok(entry)
+    }
+    let entry = match cached_dentry(&fs, dir_ino, name) {
+      Some(entry) => return Ok(entry),
+      None => try!(find_in_dir(&fs, &dir_inode, name)),
+    };
+
+    let mut dentries = try!(lock(self.dentries.write()));
+    if dentries.len() >= fs.options.cache.dentry_count {
+      dentries.clear();
+    }
+    dentries.insert(key, entry);
+    Ok(entry)
+  }
+
+  pub fn read_file(&self, handle: &mut FileHandle, offset: u64,
+    buffer: &mut [u8]) -> Result<u64>
+  {
+    let ino = handle.ino();
+    let length = {
+      let fs = try!(self.read());
+      let _inode_lock = try!(lock(self.inode_lock(ino).read()));
+      let inode = try!(self.peek(&fs, ino));
+      try!(read_file_shared(&fs, handle, &inode, offset, buffer))
+    };
+    try!(self.mark_accessed(ino));
+    Ok(length)
+  }
+
+  pub fn write_file(&self, creds: &Credentials, handle: &mut FileHandle,
+    offset: u64, buffer: &[u8]) -> Result<u64>
+  {
+    {
+      let fs = try!(self.read());
+      let _inode_lock = try!(lock(self.inode_lock(handle.ino()).write()));
+      let mut inode = try!(self.peek(&fs, handle.ino()));
+      let size_512 = inode.size_512;
+      let use_reserved = may_use_reserved(&fs, creds);
+      let mut reserved = 0;
+      let result = write_file_shared(&fs, creds, handle, &mut inode, offset, buffer,
+        |count| {
+          let ok = self.reserve_blocks(&fs, use_reserved, count);
+          if ok { reserved = count; }
+          ok
+        });
+
+      let used = (inode.size_512 - size_512) / (fs.block_size() / 512);
+      self.pending_blocks.fetch_sub(reserved - used, Ordering::SeqCst);
+      match result {
+        Ok(Some(length)) => {
+          try!(self.store(inode));
+          return Ok(length)
+        },
+        Ok(None) => {},
+        Err(err) => {
+          if used > 0 {
+            try!(self.store(inode));
+          }
+          return Err(err)
+        },
+      }
+    }
+
+    let mut fs = try!(self.write());
+    write_file(&mut fs, creds, handle, offset, buffer)
+  }
+
+  pub fn read_dir(&self, handle: DirHandle) -> Result<Option<(DirHandle, DirLine)>> {
+    let line = {
+      let fs = try!(self.read());
+      let inode = try!(self.peek(&fs, handle.ino()));
+      try!(next_dir_line(&fs, &inode, handle))
+    };
+    try!(self.mark_accessed(handle.ino()));
+    Ok(line)
+  }
+
+  pub fn read_link(&self, ino: u64) -> Result<Vec<u8>> {
+    let data = {
+      let fs = try!(self.read());
+      let inode = try!(self.peek(&fs, ino));
+      if inode.mode.file_type != FileType::Symlink {
+        return Err(Error::of_kind(ErrorKind::InvalidInput,
+          format!("inode is not a symlink")))
+      }
+      try!(read_link_data(&fs, &inode))
+    };
+    try!(self.mark_accessed(ino));
+    Ok(data)
+  }
+
+  pub fn statfs(&self) -> Result<StatFs> {
+    let fs = try!(self.read());
+    let pending_blocks = self.pending_blocks.load(Ordering::SeqCst);
+    let mut stat = statfs(&fs);
+    stat.free_blocks = stat.free_blocks.saturating_sub(pending_blocks);
+    stat.available_blocks = stat.available_blocks.saturating_sub(pending_blocks);
+    Ok(stat)
+  }
+
+  pub fn flush(&self) -> Result<()> {
+    let mut fs = try!(self.write());
+    flush_fs(&mut fs)
+  }
+
+  fn read(&self) -> Result<RwLockReadGuard<'_, Filesystem>> {
+    lock(self.fs.read())
+  }
+
+  fn inode_lock(&self, ino: u64) -> &RwLock<()> {
+    &self.inode_locks[ino as usize % INODE_LOCK_STRIPES]
+  }
+
+  fn peek(&self, fs: &Filesystem, ino: u64) -> Result<Inode> {
+    if let Some(shared) = try!(lock(self.inodes.read())).get(&ino) {
+      return Ok(shared.inode)
+    }
+
+    let inode = try!(peek_inode(fs, ino));
+    let mut inodes = try!(lock(self.inodes.write()));
+    if inodes.len() >= inode_cache_limit(fs) {
+      inodes.retain(|_, shared| shared.dirty);
+    }
+    Ok(inodes.entry(ino).or_insert(SharedInode { inode: inode, dirty: false }).inode)
+  }
+
+  fn store(&self, inode: Inode) -> Result<()> {
+    let mut inodes = try!(lock(self.inodes.write()));
+    inodes.insert(inode.ino, SharedInode { inode: inode, dirty: true });
+    Ok(())
+  }
+
+  fn reserve_blocks(&self, fs: &Filesystem, use_reserved: bool, count: u64) -> bool {
+    let free_blocks = fs.superblock.free_blocks_count as u64;
+    let free_blocks = if use_reserved {
+      free_blocks
+    } else {
+      free_blocks.saturating_sub(fs.superblock.r_blocks_count as u64)
+    };
+
+    let mut pending_blocks = self.pending_blocks.load(Ordering::SeqCst);
+    loop {
+      if pending_blocks + count > free_blocks {
+        return false
+      }
+      match self.pending_blocks.compare_exchange(pending_blocks, pending_blocks + count,
+          Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => return true,
+        Err(current) => pending_blocks = current,
+      }
+    }
+  }
+
+  fn drain(&self, fs: &mut Filesystem) -> Result<()> {
+    let pending_blocks = self.pending_blocks.swap(0, Ordering::SeqCst);
+    if pending_blocks > 0 {
+      fs.superblock.free_blocks_count -= pending_blocks as u32;
+      fs.superblock_dirty = true;
+    }
+
+    // an inode that fails to go back must not take the others with it
+    let inodes: Vec<SharedInode> = try!(lock(self.inodes.write()))
+      .drain().map(|(_, shared)| shared).collect();
+    let mut result = Ok(());
+    for shared in inodes {
+      result = result.and(if shared.dirty {
+        update_inode(fs, &shared.inode)
+      } else {
+        cache_inode(fs, &shared.inode)
+      });
+    }
+
+    let dentries: Vec<_> = try!(lock(self.dentries.write())).drain().collect();
+    for ((dir_ino, name), entry) in dentries {
+      cache_dentry(fs, dir_ino, &name[..], entry);
+    }
+
+    let accessed_inos: Vec<u64> = try!(lock(self.accessed_inos.lock())).drain().collect();
+    for ino in accessed_inos {
+      result = result.and(touch_accessed(fs, ino));
+    }
+    result
+  }
+
+  fn mark_accessed(&self, ino: u64) -> Result<()> {
+    let mut accessed_inos = try!(lock(self.accessed_inos.lock()));
+    accessed_inos.insert(ino);
+    Ok(())
+  }
+}
+
+fn lock<T>(result: LockResult<T>) -> Result<T> {
+  result.map_err(|_| Error::new(format!("Filesystem lock is poisoned")))
+}
@@ -0,0 +1,141 @@
+use std::{cmp};
+use std::sync::{Arc, Mutex};
+use prelude::*;
+
+pub const BLOCKS_PER_GROUP: u64 = 256;
+pub const INODES_PER_GROUP: u64 = 16;
+
+#[derive(Clone)]
+pub struct MemVolume(Arc<Mutex<Vec<u8>>>);
+
+impl MemVolume {
+  pub fn image(&self) -> Vec<u8> {
+    self.0.lock().unwrap().clone()
+  }
+}
+
+impl Volume for MemVolume {
+  fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<()> {
+    let image = self.0.lock().unwrap();
+    let begin = offset as usize;
+    buffer.copy_from_slice(&image[begin..begin + buffer.len()]);
+    Ok(())
+  }
+
+  fn write(&mut self, offset: u64, buffer: &[u8]) -> Result<()> {
+    let mut image = self.0.lock().unwrap();
+    let begin = offset as usize;
+    image[begin..begin + buffer.len()].copy_from_slice(buffer);
+    Ok(())
+  }
+}
+
+// every group starts with room for a superblock copy and one descriptor block,
+// followed by its bitmaps and inode table. the root directory is the only
+// inode in use
+pub fn make_volume(block_size: u64, group_count: u64, inode_size: u64) -> MemVolume {
+  let first_data_block = if block_size == 1024 { 1 } else { 0 };
+  let blocks_count = group_count * BLOCKS_PER_GROUP;
+  let inodes_count = group_count * INODES_PER_GROUP;
+  let table_blocks = (INODES_PER_GROUP * inode_size + block_size - 1) / block_size;
+  let group_used_blocks = 4 + table_blocks;
+  let mut image = make_buffer(blocks_count * block_size);
+
+  let block = |idx: u64| (idx * block_size) as usize;
+  let mut free_blocks_count = 0;
+  for group_idx in 0..group_count {
+    let first_block = first_data_block + group_idx * BLOCKS_PER_GROUP;
+    let group_blocks = cmp::min(blocks_count - first_block, BLOCKS_PER_GROUP);
+    let free_blocks = group_blocks - group_used_blocks;
+    let free_inodes = if group_idx == 0 { INODES_PER_GROUP - 11 } else { INODES_PER_GROUP };
+    free_blocks_count += free_blocks;
+
+    {
+      let desc = &mut image[block(first_data_block + 1) + group_idx as usize * 32..];
+      encode_u32(first_block as u32 + 2, &mut desc[0..]);
+      encode_u32(first_block as u32 + 3, &mut desc[4..]);
+      encode_u32(first_block as u32 + 4, &mut desc[8..]);
+      encode_u16(free_blocks as u16, &mut desc[12..]);
+      encode_u16(free_inodes as u16, &mut desc[14..]);
+    }
+
+    let block_bitmap = block(first_block + 2);
+    for bit in 0..block_size * 8 {
+      if bit < group_used_blocks || bit >= group_blocks {
+        image[block_bitmap + bit as usize / 8] |= 1 << (bit % 8);
+      }
+    }
+    let inode_bitmap = block(first_block + 3);
+    for bit in 0..block_size * 8 {
+      if (group_idx == 0 && bit < 11) || bit >= INODES_PER_GROUP {
+        image[inode_bitmap + bit as usize / 8] |= 1 << (bit % 8);
+      }
+    }
+  }
+
+  {
+    let sb = &mut image[1024..2048];
+    encode_u32(inodes_count as u32, &mut sb[0..]);
+    encode_u32(blocks_count as u32, &mut sb[4..]);
+    encode_u32(free_blocks_count as u32, &mut sb[12..]);
+    encode_u32(inodes_count as u32 - 11, &mut sb[16..]);
+    encode_u32(first_data_block as u32, &mut sb[20..]);
+    encode_u32((block_size / 1024).trailing_zeros(), &mut sb[24..]);
+    encode_u32(BLOCKS_PER_GROUP as u32, &mut sb[32..]);
+    encode_u32(INODES_PER_GROUP as u32, &mut sb[40..]);
+    encode_u16(SUPERBLOCK_MAGIC, &mut sb[56..]);
+    encode_u16(1, &mut sb[58..]);
+    encode_u32(1, &mut sb[76..]);
+    encode_u32(11, &mut sb[84..]);
+    encode_u16(inode_size as u16, &mut sb[88..]);
+  }
+
+  let volume = MemVolume(Arc::new(Mutex::new(image)));
+  let mut fs = mount(&volume);
+  let mut root = Inode {
+    ino: ROOT_INO,
+    mode: mode(FileType::Dir, 0o755),
+    attr: FileAttr::default(),
+    size: 0, size_512: 0,
+    links_count: 0, flags: 0,
+    block: [0; 15],
+    file_acl: 0,
+    extra_isize: cmp::min(INODE_EXTRA_ISIZE as u64, inode_size - 128) as u16,
+  };
+  let mut parent = root;
+  init_dir(&mut fs, &mut parent, &mut root).unwrap();
+  root.links_count = 2;
+  update_inode(&mut fs, &root).unwrap();
+  flush_fs(&mut fs).unwrap();
+  volume
+}
+
+pub fn make_fs(block_size: u64) -> Filesystem {
+  mount(&make_volume(block_size, 1, 128))
+}
+
+pub fn mount(volume: &MemVolume) -> Filesystem {
+  mount_with_options(volume, MountOptions::default())
+}
+
+pub fn mount_with_options(volume: &MemVolume, options: MountOptions) -> Filesystem {
+  mount_fs_with_options(Box::new(volume.clone()), options).unwrap()
+}
+
+pub fn mode(file_type: FileType, access_rights: u16) -> Mode {
+  Mode {
+    file_type: file_type,
+    suid: false, sgid: false, sticky: false,
+    access_rights: access_rights,
+  }
+}
+
+pub fn make_file(fs: &mut Filesystem, dir_ino: u64, name: &[u8]) -> Inode {
+  make_inode_in_dir(fs, &Credentials::root(), dir_ino, name,
+    mode(FileType::Regular, 0o644), FileAttr::default()).unwrap()
+}
+
+pub fn make_dir(fs: &mut Filesystem, dir_ino: u64, name: &[u8]) -> Inode {
+  make_inode_in_dir(fs, &Credentials::root(), dir_ino, name,
+    mode(FileType::Dir, 0o755), FileAttr::default()).unwrap()
+}
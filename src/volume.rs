@@ -1,35 +1,274 @@
-use std::{io, fs};
-use std::io::{Read, Write, Seek};
-use error::{Result};
+use std::{cmp, fs, io, path};
+use std::io::{Seek, SeekFrom};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd};
+use libc;
+use error::{Error, ErrorKind, Result};
 
-pub trait Volume {
-  fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<()>;
+pub trait Volume: Send + Sync {
+  fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<()>;
   fn write(&mut self, offset: u64, buffer: &[u8]) -> Result<()>;
+
+  fn read_vectored_at(&self, offset: u64, buffers: &mut [&mut [u8]]) -> Result<()> {
+    let mut offset = offset;
+    for buffer in buffers.iter_mut() {
+      try!(self.read(offset, buffer));
+      offset += buffer.len() as u64;
+    }
+    Ok(())
+  }
+
+  fn write_vectored_at(&mut self, offset: u64, buffers: &[&[u8]]) -> Result<()> {
+    let mut offset = offset;
+    for buffer in buffers.iter() {
+      try!(self.write(offset, buffer));
+      offset += buffer.len() as u64;
+    }
+    Ok(())
+  }
+
+  fn sync(&mut self) -> Result<()> {
+    Ok(())
+  }
+
+  fn can_write_shared(&self) -> bool {
+    false
+  }
+
+  fn write_shared(&self, offset: u64, _buffer: &[u8]) -> Result<()> {
+    Err(Error::new(format!("Volume cannot write at offset {} while shared", offset)))
+  }
+
+  fn size(&self) -> Result<u64> {
+    Err(Error::new(format!("Volume size is unknown")))
+  }
+
+  fn alignment(&self) -> u64 {
+    1
+  }
 }
 
-pub struct FileVolume(pub fs::File);
+pub struct FileVolume {
+  file: fs::File,
+  alignment: u64,
+}
 
-impl Volume for FileVolume {
-  #[cfg(feature = "read_exact")]
-  fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<()> {
-    try!(self.0.seek(io::SeekFrom::Start(offset)));
-    try!(self.0.read_exact(buffer));
+impl FileVolume {
+  pub fn new(file: fs::File) -> FileVolume {
+    FileVolume { file: file, alignment: 1 }
+  }
+
+  pub fn open_direct<P: AsRef<path::Path>>(path: P, alignment: u64) -> Result<FileVolume> {
+    if alignment == 0 || !alignment.is_power_of_two() {
+      return Err(Error::of_kind(ErrorKind::InvalidInput,
+        format!("Alignment {} is not a power of two", alignment)))
+    }
+    let file = try!(fs::OpenOptions::new()
+      .read(true).write(true)
+      .custom_flags(libc::O_DIRECT)
+      .open(path));
+    Ok(FileVolume { file: file, alignment: alignment })
+  }
+
+  pub fn into_file(self) -> fs::File {
+    self.file
+  }
+
+  fn read_aligned(&self, offset: u64, buffer: &mut [u8]) -> Result<()> {
+    let begin = offset / self.alignment * self.alignment;
+    let end = align_up(offset + buffer.len() as u64, self.alignment);
+    let mut bounce = AlignedBuffer::new((end - begin) as usize, self.alignment as usize);
+    try!(read_exact_at(&self.file, bounce.as_mut(), begin));
+    let skip = (offset - begin) as usize;
+    buffer.copy_from_slice(&bounce.as_mut()[skip..skip + buffer.len()]);
     Ok(())
   }
 
-  #[cfg(not(feature = "read_exact"))]
-  fn read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<()> {
-    try!(self.0.seek(io::SeekFrom::Start(offset)));
-    let mut total_read = 0;
-    while total_read < buffer.len() {
-      total_read += try!(self.0.read(&mut buffer[total_read..]));
+  fn write_aligned(&mut self, offset: u64, buffer: &[u8]) -> Result<()> {
+    let begin = offset / self.alignment * self.alignment;
+    let end = align_up(offset + buffer.len() as u64, self.alignment);
+    let mut bounce = AlignedBuffer::new((end - begin) as usize, self.alignment as usize);
+    let skip = (offset - begin) as usize;
+    if skip != 0 || end != offset + buffer.len() as u64 {
+      try!(read_exact_at(&self.file, bounce.as_mut(), begin));
     }
+    bounce.as_mut()[skip..skip + buffer.len()].copy_from_slice(buffer);
+    try!(self.file.write_all_at(bounce.as_mut(), begin));
     Ok(())
   }
+}
+
+impl Volume for FileVolume {
+  fn read(&self, offset: u64, buffer: &mut [u8]) -> Result<()> {
+    if self.alignment > 1 {
+      return self.read_aligned(offset, buffer)
+    }
+    read_exact_at(&self.file, buffer, offset)
+  }
 
   fn write(&mut self, offset: u64, buffer: &[u8]) -> Result<()> {
-    try!(self.0.seek(io::SeekFrom::Start(offset)));
-    try!(self.0.write_all(buffer));
+    if self.alignment > 1 {
+      return self.write_aligned(offset, buffer)
+    }
+    try!(self.file.write_all_at(buffer, offset));
     Ok(())
   }
+
+  fn read_vectored_at(&self, offset: u64, buffers: &mut [&mut [u8]]) -> Result<()> {
+    if self.alignment > 1 {
+      let mut offset = offset;
+      for buffer in buffers.iter_mut() {
+        try!(self.read_aligned(offset, buffer));
+        offset += buffer.len() as u64;
+      }
+      return Ok(())
+    }
+
+    let mut offset = offset;
+    let mut first = 0;
+    let mut skip = 0;
+    while first < buffers.len() {
+      let iovecs: Vec<libc::iovec> = buffers[first..].iter_mut().enumerate()
+        .map(|(i, buffer)| {
+          let buffer = if i == 0 { &mut buffer[skip..] } else { &mut buffer[..] };
+          libc::iovec { iov_base: buffer.as_mut_ptr() as *mut libc::c_void, iov_len: buffer.len() }
+        }).collect();
+      let iovcnt = cmp::min(iovecs.len(), 1024);
+      let res = unsafe {
+        libc::preadv(self.file.as_raw_fd(), iovecs.as_ptr(), iovcnt as libc::c_int,
+          offset as libc::off_t)
+      };
+      if res < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted {
+          continue
+        }
+        return Err(Error::from(err))
+      } else if res == 0 && iovecs.iter().any(|iovec| iovec.iov_len > 0) {
+        return Err(Error::from(io::Error::new(io::ErrorKind::UnexpectedEof,
+          format!("Volume ended at offset {}", offset))))
+      }
+      offset += res as u64;
+      advance_buffers(buffers.iter().map(|buffer| buffer.len()),
+        res as usize, &mut first, &mut skip);
+    }
+    Ok(())
+  }
+
+  fn write_vectored_at(&mut self, offset: u64, buffers: &[&[u8]]) -> Result<()> {
+    if self.alignment > 1 {
+      let mut offset = offset;
+      for buffer in buffers.iter() {
+        try!(self.write_aligned(offset, buffer));
+        offset += buffer.len() as u64;
+      }
+      return Ok(())
+    }
+
+    let mut offset = offset;
+    let mut first = 0;
+    let mut skip = 0;
+    while first < buffers.len() {
+      let iovecs: Vec<libc::iovec> = buffers[first..].iter().enumerate()
+        .map(|(i, buffer)| {
+          let buffer = if i == 0 { &buffer[skip..] } else { &buffer[..] };
+          libc::iovec { iov_base: buffer.as_ptr() as *mut libc::c_void, iov_len: buffer.len() }
+        }).collect();
+      let iovcnt = cmp::min(iovecs.len(), 1024);
+      let res = unsafe {
+        libc::pwritev(self.file.as_raw_fd(), iovecs.as_ptr(), iovcnt as libc::c_int,
+          offset as libc::off_t)
+      };
+      if res < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted {
+          continue
+        }
+        return Err(Error::from(err))
+      } else if res == 0 && iovecs.iter().any(|iovec| iovec.iov_len > 0) {
+        return Err(Error::from(io::Error::new(io::ErrorKind::WriteZero,
+          format!("Volume refused to write at offset {}", offset))))
+      }
+      offset += res as u64;
+      advance_buffers(buffers.iter().map(|buffer| buffer.len()),
+        res as usize, &mut first, &mut skip);
+    }
+    Ok(())
+  }
+
+  fn sync(&mut self) -> Result<()> {
+    try!(self.file.sync_data());
+    Ok(())
+  }
+
+  // aligned writes read and rewrite whole sectors, which could race with a
+  // concurrent write to a neighbouring block
+  fn can_write_shared(&self) -> bool {
+    self.alignment == 1
+  }
+
+  fn write_shared(&self, offset: u64, buffer: &[u8]) -> Result<()> {
+    if !self.can_write_shared() {
+      return Err(Error::new(format!(
+        "Volume with alignment {} cannot write while shared", self.alignment)))
+    }
+    try!(self.file.write_all_at(buffer, offset));
+    Ok(())
+  }
+
+  fn size(&self) -> Result<u64> {
+    Ok(try!((&self.file).seek(SeekFrom::End(0))))
+  }
+
+  fn alignment(&self) -> u64 {
+    self.alignment
+  }
+}
+
+fn read_exact_at(file: &fs::File, buffer: &mut [u8], offset: u64) -> Result<()> {
+  match file.read_exact_at(buffer, offset) {
+    Ok(()) => Ok(()),
+    Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof =>
+      Err(Error::of_kind(ErrorKind::Io, format!(
+        "Volume ended before {} bytes at offset {} could be read",
+        buffer.len(), offset))),
+    Err(err) => Err(Error::from(err)),
+  }
+}
+
+fn advance_buffers<I: Iterator<Item = usize>>(lengths: I, mut done: usize,
+  first: &mut usize, skip: &mut usize)
+{
+  for length in lengths.skip(*first) {
+    let remaining = length - *skip;
+    if done < remaining {
+      *skip += done;
+      return
+    }
+    done -= remaining;
+    *first += 1;
+    *skip = 0;
+  }
+}
+
+fn align_up(value: u64, alignment: u64) -> u64 {
+  (value + alignment - 1) / alignment * alignment
+}
+
+struct AlignedBuffer {
+  data: Vec<u8>,
+  shift: usize,
+  length: usize,
+}
+
+impl AlignedBuffer {
+  fn new(length: usize, alignment: usize) -> AlignedBuffer {
+    let data = vec![0; length + alignment];
+    let shift = (alignment - data.as_ptr() as usize % alignment) % alignment;
+    AlignedBuffer { data: data, shift: shift, length: length }
+  }
+
+  fn as_mut(&mut self) -> &mut [u8] {
+    &mut self.data[self.shift..self.shift + self.length]
+  }
 }